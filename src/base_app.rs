@@ -68,8 +68,8 @@ impl BaseData {
         }
     }
 
-    fn controls(&mut self) {
-        // Compute directional vectors from euler angles
+    /// Get the camera right, up and forward vectors from the euler angles
+    pub fn camera_axes(&self) -> (vec3, vec3, vec3) {
         let cos_x = self.wx.cos();
         let sin_x = self.wx.sin();
         let cos_y = self.wy.cos();
@@ -78,6 +78,11 @@ impl BaseData {
         let dx = vec3(cos_y, 0.0, sin_y);
         let dy = vec3(-sin_x * sin_y, cos_x, sin_x * cos_y);
         let dz = vec3(-cos_x * sin_y, -sin_x, cos_x * cos_y);
+        (dx, dy, dz)
+    }
+
+    fn controls(&mut self) {
+        let (dx, dy, dz) = self.camera_axes();
 
         let mut dir = vec3(0.0, 0.0, 0.0);
         if self.key_left {
@@ -0,0 +1,26 @@
+// Timing helper for the benchmarks. Benchmarks are ignored tests named bench_*, so they only run when asked for:
+//   cargo test --release -- --ignored --nocapture bench_
+// Add --features scalar_math to time the scalar vec4 and mat4 math instead of SIMD.
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+/// Time a function and print the mean time per call of the fastest run, returns that time
+/// * `name` - The name printed with the time
+/// * `iterations` - The number of calls per run
+/// * `f` - The function to time, use `std::hint::black_box` to keep its results from being optimized away
+pub fn bench<F: FnMut()>(name: &str, iterations: u32, mut f: F) -> Duration {
+    f(); // Warm up caches and allocations
+
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        best = best.min(start.elapsed() / iterations);
+    }
+    println!("{name:<48} {:>12.3} us", best.as_secs_f64() * 1e6);
+    best
+}
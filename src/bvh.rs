@@ -0,0 +1,300 @@
+use crate::geometry::{Aabb, Ray};
+use crate::model::{AttributeType, Batch, Model};
+use crate::vector::*;
+
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+/// The closest intersection of a ray with a model
+#[derive(Copy, Clone)]
+pub struct RayHit {
+    /// Distance along the ray direction, in units of the direction length
+    pub distance: f32,
+    /// Index of the batch in the model
+    pub batch: usize,
+    /// Index of the triangle in the batch. Quads and strips are counted as the triangles they are split into.
+    pub triangle: u32,
+    /// Weights of the three triangle vertices at the hit point
+    pub barycentric: vec3,
+}
+
+struct Triangle {
+    v: [vec3; 3],
+    batch: u32,
+    index: u32,
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    // Leaf nodes: first triangle and count. Inner nodes: index of the second child (the first follows this node) and 0.
+    start: u32,
+    count: u32,
+}
+
+/// Bounding volume hierarchy over the triangles of a model, for ray queries
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    triangles: Vec<Triangle>,
+}
+
+impl Bvh {
+    /// Build a hierarchy from the triangle, quad and triangle strip batches of a model.
+    /// Line batches and batches without positions are ignored.
+    pub fn new(model: &Model) -> Bvh {
+        let mut triangles = Vec::new();
+        for (batch_index, batch) in model.batches.iter().enumerate() {
+            add_batch_triangles(batch, batch_index as u32, &mut triangles);
+        }
+
+        let mut ret = Bvh {
+            nodes: Vec::with_capacity(2 * triangles.len() / MAX_LEAF_TRIANGLES + 1),
+            triangles,
+        };
+        if !ret.triangles.is_empty() {
+            let count = ret.triangles.len();
+            ret.build_node(0, count);
+        }
+        ret
+    }
+
+    fn build_node(&mut self, start: usize, count: usize) {
+        let tris = &mut self.triangles[start..start + count];
+
+        let mut bounds = Aabb::EMPTY;
+        let mut centroid_bounds = Aabb::EMPTY;
+        for tri in tris.iter() {
+            for v in &tri.v {
+                bounds.extend(v);
+            }
+            centroid_bounds.extend(&centroid(tri));
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            start: start as u32,
+            count: count as u32,
+        });
+        if count <= MAX_LEAF_TRIANGLES {
+            return;
+        }
+
+        // Split at the median centroid of the longest axis
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let mut axis = 0;
+        if extent.y > extent[axis] {
+            axis = 1;
+        }
+        if extent.z > extent[axis] {
+            axis = 2;
+        }
+        let half = count / 2;
+        tris.select_nth_unstable_by(half, |a, b| centroid(a)[axis].total_cmp(&centroid(b)[axis]));
+
+        self.build_node(start, half);
+        let second = self.nodes.len() as u32;
+        self.build_node(start + half, count - half);
+
+        let node = &mut self.nodes[node_index];
+        node.start = second;
+        node.count = 0;
+    }
+
+    /// Find the closest intersection of a ray with the model
+    /// * `ray` - The ray to test
+    /// * `max_distance` - Hits further than this (in units of the ray direction length) are ignored
+    pub fn ray_cast(&self, ray: &Ray, max_distance: f32) -> Option<RayHit> {
+        let mut ret: Option<RayHit> = None;
+        let mut closest = max_distance;
+        self.traverse(ray, &mut closest, |tri, distance, barycentric| {
+            ret = Some(RayHit {
+                distance,
+                batch: tri.batch as usize,
+                triangle: tri.index,
+                barycentric,
+            });
+            false
+        });
+        ret
+    }
+
+    /// Test if a ray hits anything in the model before `max_distance`.
+    /// Cheaper than `ray_cast` as it stops at the first hit found.
+    pub fn ray_cast_any(&self, ray: &Ray, max_distance: f32) -> bool {
+        let mut hit = false;
+        let mut closest = max_distance;
+        self.traverse(ray, &mut closest, |_, _, _| {
+            hit = true;
+            true
+        });
+        hit
+    }
+
+    // Walk the nodes hit by the ray, calling on_hit for each triangle hit closer than max_distance.
+    // max_distance is reduced to each hit distance. Stops when on_hit returns true.
+    fn traverse<F>(&self, ray: &Ray, max_distance: &mut f32, mut on_hit: F)
+    where
+        F: FnMut(&Triangle, f32, vec3) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = [0u32; 64];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let index = stack[stack_size];
+            let node = &self.nodes[index as usize];
+            match ray.intersect_aabb(&node.bounds) {
+                Some((t_min, _)) if t_min <= *max_distance => (),
+                _ => continue,
+            }
+
+            if node.count == 0 {
+                debug_assert!(stack_size + 2 <= stack.len());
+                stack[stack_size] = node.start;
+                stack[stack_size + 1] = index + 1;
+                stack_size += 2;
+                continue;
+            }
+
+            let start = node.start as usize;
+            for tri in &self.triangles[start..start + node.count as usize] {
+                let [v0, v1, v2] = &tri.v;
+                if let Some((distance, barycentric)) = ray.intersect_triangle(v0, v1, v2) {
+                    if distance < *max_distance {
+                        *max_distance = distance;
+                        if on_hit(tri, distance, barycentric) {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn add_batch_triangles(batch: &Batch, batch_index: u32, triangles: &mut Vec<Triangle>) {
+    let Some(format) = batch.find_format(AttributeType::Vertex, 0) else {
+        return;
+    };
+    let position = |v: u32| -> vec3 {
+        let [x, y, z, _] = batch.read_attribute(format, v);
+        vec3(x, y, z)
+    };
+
+    let mut index = 0;
+    batch.for_each_triangle(|a, b, c| {
+        triangles.push(Triangle {
+            v: [position(a), position(b), position(c)],
+            batch: batch_index,
+            index,
+        });
+        index += 1;
+    });
+}
+
+fn centroid(tri: &Triangle) -> vec3 {
+    (tri.v[0] + tri.v[1] + tri.v[2]) / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_rand::GameRand;
+
+    fn load_room() -> Model {
+        Model::new(concat!(env!("CARGO_MANIFEST_DIR"), "/data/room0.hmdl")).unwrap()
+    }
+
+    // All hits of a ray, by testing every triangle of every batch
+    fn brute_force_hits(model: &Model, ray: &Ray, max_distance: f32) -> Vec<RayHit> {
+        let mut hits = Vec::new();
+        for (batch_index, batch) in model.batches.iter().enumerate() {
+            let mut triangles = Vec::new();
+            add_batch_triangles(batch, batch_index as u32, &mut triangles);
+            for tri in &triangles {
+                let [v0, v1, v2] = &tri.v;
+                if let Some((distance, barycentric)) = ray.intersect_triangle(v0, v1, v2) {
+                    if distance < max_distance {
+                        hits.push(RayHit {
+                            distance,
+                            batch: batch_index,
+                            triangle: tri.index,
+                            barycentric,
+                        });
+                    }
+                }
+            }
+        }
+        hits
+    }
+
+    fn random_rays(model: &Model, count: usize) -> Vec<Ray> {
+        let mut bounds = Aabb::EMPTY;
+        for batch in &model.batches {
+            let format = batch.find_format(AttributeType::Vertex, 0).unwrap();
+            for v in 0..batch.num_vertices {
+                let [x, y, z, _] = batch.read_attribute(format, v);
+                bounds.extend(&vec3(x, y, z));
+            }
+        }
+
+        // Rays from inside and around the room, in all directions
+        let mut rand = GameRand::new(28);
+        let center = bounds.center();
+        let half_extents = bounds.half_extents() * 1.5;
+        (0..count)
+            .map(|_| {
+                let offset = vec3(
+                    rand.rand_range_f32(-1.0, 1.0),
+                    rand.rand_range_f32(-1.0, 1.0),
+                    rand.rand_range_f32(-1.0, 1.0),
+                );
+                Ray::new(center + offset * half_extents, rand.point_on_sphere())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ray_cast_matches_brute_force() {
+        let model = load_room();
+        let bvh = Bvh::new(&model);
+        let mut hit_count = 0;
+        for ray in random_rays(&model, 2000) {
+            for max_distance in [f32::MAX, 100.0] {
+                let hits = brute_force_hits(&model, &ray, max_distance);
+                let closest = hits.iter().map(|h| h.distance).min_by(f32::total_cmp);
+                let hit = bvh.ray_cast(&ray, max_distance);
+                assert_eq!(hit.map(|h| h.distance), closest);
+                assert_eq!(bvh.ray_cast_any(&ray, max_distance), closest.is_some());
+
+                // Triangles sharing an edge can be hit at the same distance, any of them is correct
+                if let Some(hit) = hit {
+                    hit_count += 1;
+                    assert!(hits.iter().any(|h| h.distance == hit.distance
+                        && h.batch == hit.batch
+                        && h.triangle == hit.triangle
+                        && h.barycentric == hit.barycentric));
+                    let weights = hit.barycentric;
+                    assert!((weights.x + weights.y + weights.z - 1.0).abs() < 1e-5);
+                }
+            }
+        }
+        // Make sure the comparison covered a good number of hits
+        assert!(hit_count > 500, "{hit_count}");
+    }
+
+    #[test]
+    fn empty_model() {
+        let model = Model {
+            batches: Vec::new(),
+            skeleton: None,
+            animations: Vec::new(),
+        };
+        let bvh = Bvh::new(&model);
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0));
+        assert!(bvh.ray_cast(&ray, f32::MAX).is_none());
+        assert!(!bvh.ray_cast_any(&ray, f32::MAX));
+    }
+}
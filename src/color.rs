@@ -0,0 +1,374 @@
+use crate::curve::{sample_keys, Interpolation, Keyframe};
+use crate::vector::*;
+
+// Colours are vec4 with r, g, b, a in x, y, z, w, nominally in the 0-1 range
+
+pub const TRANSPARENT: vec4 = vec4(0.0, 0.0, 0.0, 0.0);
+pub const BLACK: vec4 = vec4(0.0, 0.0, 0.0, 1.0);
+pub const WHITE: vec4 = vec4(1.0, 1.0, 1.0, 1.0);
+pub const RED: vec4 = vec4(1.0, 0.0, 0.0, 1.0);
+pub const GREEN: vec4 = vec4(0.0, 1.0, 0.0, 1.0);
+pub const BLUE: vec4 = vec4(0.0, 0.0, 1.0, 1.0);
+pub const YELLOW: vec4 = vec4(1.0, 1.0, 0.0, 1.0);
+pub const CYAN: vec4 = vec4(0.0, 1.0, 1.0, 1.0);
+pub const MAGENTA: vec4 = vec4(1.0, 0.0, 1.0, 1.0);
+
+fn srgb_to_linear_component(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb_component(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert an sRGB encoded colour to linear, alpha is unchanged
+pub fn srgb_to_linear(c: &vec4) -> vec4 {
+    vec4(
+        srgb_to_linear_component(c.x),
+        srgb_to_linear_component(c.y),
+        srgb_to_linear_component(c.z),
+        c.w,
+    )
+}
+
+/// Convert a linear colour to sRGB encoding, alpha is unchanged
+pub fn linear_to_srgb(c: &vec4) -> vec4 {
+    vec4(
+        linear_to_srgb_component(c.x),
+        linear_to_srgb_component(c.y),
+        linear_to_srgb_component(c.z),
+        c.w,
+    )
+}
+
+/// Convert hue, saturation, value and alpha to RGBA
+/// * `hsv` - Hue in turns (0-1 covers the full circle), saturation, value and alpha
+pub fn hsv_to_rgb(hsv: &vec4) -> vec4 {
+    let h = hsv.x.rem_euclid(1.0) * 6.0;
+    let channel = |n: f32| {
+        let k = (n + h) % 6.0;
+        hsv.z - hsv.z * hsv.y * k.min(4.0 - k).clamp(0.0, 1.0)
+    };
+    vec4(channel(5.0), channel(3.0), channel(1.0), hsv.w)
+}
+
+/// Convert RGBA to hue, saturation, value and alpha, with the hue in turns
+pub fn rgb_to_hsv(rgb: &vec4) -> vec4 {
+    let max = rgb.x.max(rgb.y).max(rgb.z);
+    let min = rgb.x.min(rgb.y).min(rgb.z);
+    let delta = max - min;
+    let s = if max > 0.0 { delta / max } else { 0.0 };
+    vec4(hue(rgb, max, delta), s, max, rgb.w)
+}
+
+/// Convert hue, saturation, lightness and alpha to RGBA
+/// * `hsl` - Hue in turns (0-1 covers the full circle), saturation, lightness and alpha
+pub fn hsl_to_rgb(hsl: &vec4) -> vec4 {
+    let h = hsl.x.rem_euclid(1.0) * 12.0;
+    let a = hsl.y * hsl.z.min(1.0 - hsl.z);
+    let channel = |n: f32| {
+        let k = (n + h) % 12.0;
+        hsl.z - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0)
+    };
+    vec4(channel(0.0), channel(8.0), channel(4.0), hsl.w)
+}
+
+/// Convert RGBA to hue, saturation, lightness and alpha, with the hue in turns
+pub fn rgb_to_hsl(rgb: &vec4) -> vec4 {
+    let max = rgb.x.max(rgb.y).max(rgb.z);
+    let min = rgb.x.min(rgb.y).min(rgb.z);
+    let delta = max - min;
+    let l = (max + min) * 0.5;
+    let s = if delta > 0.0 {
+        delta / (1.0 - (2.0 * l - 1.0).abs())
+    } else {
+        0.0
+    };
+    vec4(hue(rgb, max, delta), s, l, rgb.w)
+}
+
+// Hue in turns shared by the HSV and HSL conversions
+fn hue(rgb: &vec4, max: f32, delta: f32) -> f32 {
+    if delta <= 0.0 {
+        return 0.0;
+    }
+    let h = if max == rgb.x {
+        ((rgb.y - rgb.z) / delta).rem_euclid(6.0)
+    } else if max == rgb.y {
+        (rgb.z - rgb.x) / delta + 2.0
+    } else {
+        (rgb.x - rgb.y) / delta + 4.0
+    };
+    h / 6.0
+}
+
+/// Pack a colour into 8 bits per channel, clamped to 0-1 and rounded.
+/// Red is in the lowest byte, so the in memory order on little endian matches the UBYTE4N vertex format.
+pub fn pack_rgba8(c: &vec4) -> u32 {
+    let byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    byte(c.x) | (byte(c.y) << 8) | (byte(c.z) << 16) | (byte(c.w) << 24)
+}
+
+/// Unpack a colour packed by `pack_rgba8`
+pub fn unpack_rgba8(packed: u32) -> vec4 {
+    let channel = |shift: u32| ((packed >> shift) & 0xff) as f32 / 255.0;
+    vec4(channel(0), channel(8), channel(16), channel(24))
+}
+
+/// Multiply the colour channels by alpha, for blending with ONE, ONE_MINUS_SRC_ALPHA
+pub fn premultiply_alpha(c: &vec4) -> vec4 {
+    vec4(c.x * c.w, c.y * c.w, c.z * c.w, c.w)
+}
+
+/// Undo `premultiply_alpha`, fully transparent colours become transparent black
+pub fn unpremultiply_alpha(c: &vec4) -> vec4 {
+    if c.w <= 0.0 {
+        return TRANSPARENT;
+    }
+    let inv_alpha = 1.0 / c.w;
+    vec4(c.x * inv_alpha, c.y * inv_alpha, c.z * inv_alpha, c.w)
+}
+
+// -------------------------------------------------------------------------------------------
+
+/// Keyframed colour gradient
+#[derive(Clone, Debug)]
+pub struct Gradient {
+    /// Keys sorted by position, normally from 0 to 1
+    pub keys: Vec<Keyframe<vec4>>,
+    pub interpolation: Interpolation,
+}
+
+impl Gradient {
+    /// Gradient linearly interpolated between keys
+    /// * `keys` - Position and colour of each key, sorted by position
+    pub fn new(keys: &[(f32, vec4)]) -> Gradient {
+        debug_assert!(keys.windows(2).all(|k| k[0].0 <= k[1].0));
+        Gradient {
+            keys: keys
+                .iter()
+                .map(|&(time, value)| Keyframe { time, value })
+                .collect(),
+            interpolation: Interpolation::Linear,
+        }
+    }
+
+    /// A gradient with the same colour everywhere
+    pub fn constant(color: vec4) -> Gradient {
+        Gradient::new(&[(0.0, color)])
+    }
+
+    /// Blue through white, yellow and red to black
+    pub fn fire() -> Gradient {
+        Gradient::new(&[
+            (0.0, vec4(0.0, 0.0, 1.0, 0.0)),
+            (3.0 / 11.0, vec4(1.0, 1.0, 1.0, 0.0)),
+            (4.0 / 11.0, vec4(1.0, 0.75, 0.0, 0.0)),
+            (7.0 / 11.0, vec4(1.0, 0.0, 0.0, 0.0)),
+            (1.0, vec4(0.0, 0.0, 0.0, 0.0)),
+        ])
+    }
+
+    /// White through cyan and dark blue to black
+    pub fn ice() -> Gradient {
+        Gradient::new(&[
+            (0.0, vec4(1.0, 1.0, 1.0, 0.0)),
+            (5.0 / 11.0, vec4(0.0, 1.0, 1.0, 0.0)),
+            (6.0 / 11.0, vec4(0.0, 0.0, 5.0 / 6.0, 0.0)),
+            (1.0, vec4(0.0, 0.0, 0.0, 0.0)),
+        ])
+    }
+
+    /// A faint grey fading to transparent black
+    pub fn smoke() -> Gradient {
+        Gradient::new(&[(0.0, vec4::splat(0.25)), (1.0, TRANSPARENT)])
+    }
+
+    /// Red through yellow, green, cyan and blue to black
+    pub fn rainbow() -> Gradient {
+        Gradient::new(&[
+            (0.0, vec4(1.0, 0.0, 0.0, 0.0)),
+            (2.0 / 11.0, vec4(1.0, 1.0, 0.0, 0.0)),
+            (4.0 / 11.0, vec4(0.0, 1.0, 0.0, 0.0)),
+            (6.0 / 11.0, vec4(0.0, 1.0, 1.0, 0.0)),
+            (8.0 / 11.0, vec4(0.0, 0.0, 1.0, 0.0)),
+            (9.0 / 11.0, vec4(0.0, 0.0, 0.5, 0.0)),
+            (1.0, vec4(0.0, 0.0, 0.0, 0.0)),
+        ])
+    }
+
+    /// Get the colour at a position, positions outside the keys get the first or last colour
+    pub fn sample(&self, pos: f32) -> vec4 {
+        sample_keys(&self.keys, pos, self.interpolation, lerp).unwrap_or(TRANSPARENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_rand::GameRand;
+
+    fn assert_near(a: vec4, b: vec4, tolerance: f32) {
+        assert!(length(&(a - b)) < tolerance, "{a:?} != {b:?}");
+    }
+
+    fn grey(v: f32) -> vec4 {
+        vec4(v, v, v, 1.0)
+    }
+
+    #[test]
+    fn srgb_known_values() {
+        assert_eq!(srgb_to_linear(&BLACK), BLACK);
+        assert_eq!(srgb_to_linear(&WHITE), WHITE);
+        assert_near(linear_to_srgb(&WHITE), WHITE, 1e-6);
+        assert_near(srgb_to_linear(&grey(0.5)), grey(0.214041), 1e-5);
+        assert_near(linear_to_srgb(&grey(0.214041)), grey(0.5), 1e-5);
+
+        // Alpha is not encoded
+        assert_eq!(srgb_to_linear(&vec4(1.0, 1.0, 1.0, 0.5)).w, 0.5);
+
+        // The linear segment and the curve meet at the knee
+        let knee = srgb_to_linear_component(0.04045);
+        assert!((knee - 0.0031308).abs() < 1e-7);
+        assert!((((0.04045f32 + 0.055) / 1.055).powf(2.4) - knee).abs() < 1e-6);
+        assert!((linear_to_srgb_component(0.0031308) - 0.04045).abs() < 1e-6);
+        assert!((1.055 * 0.0031308f32.powf(1.0 / 2.4) - 0.055 - 0.04045).abs() < 1e-5);
+        assert_eq!(srgb_to_linear_component(0.02), 0.02 / 12.92);
+        assert_eq!(linear_to_srgb_component(0.001), 0.001 * 12.92);
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for i in 0..=255 {
+            let v = i as f32 / 255.0;
+            let c = vec4(v, 1.0 - v, v * 0.5, 1.0);
+            assert_near(linear_to_srgb(&srgb_to_linear(&c)), c, 1e-5);
+            assert_near(srgb_to_linear(&linear_to_srgb(&c)), c, 1e-5);
+        }
+    }
+
+    #[test]
+    fn hsv_known_values() {
+        let cases = [
+            (RED, vec4(0.0, 1.0, 1.0, 1.0)),
+            (GREEN, vec4(1.0 / 3.0, 1.0, 1.0, 1.0)),
+            (BLUE, vec4(2.0 / 3.0, 1.0, 1.0, 1.0)),
+            (MAGENTA, vec4(5.0 / 6.0, 1.0, 1.0, 1.0)),
+            (vec4(1.0, 0.5, 0.0, 1.0), vec4(1.0 / 12.0, 1.0, 1.0, 1.0)),
+            (vec4(0.5, 0.25, 0.25, 0.5), vec4(0.0, 0.5, 0.5, 0.5)),
+            (BLACK, vec4(0.0, 0.0, 0.0, 1.0)),
+        ];
+        for (rgb, hsv) in cases {
+            assert_near(rgb_to_hsv(&rgb), hsv, 1e-6);
+            assert_near(hsv_to_rgb(&hsv), rgb, 1e-6);
+        }
+
+        // Hue wraps around
+        assert_near(hsv_to_rgb(&vec4(1.0, 1.0, 1.0, 1.0)), RED, 1e-6);
+        assert_near(hsv_to_rgb(&vec4(-1.0 / 3.0, 1.0, 1.0, 1.0)), BLUE, 1e-6);
+
+        // Grey has no hue, it is returned as 0 and ignored on the way back
+        assert_eq!(rgb_to_hsv(&grey(0.5)), vec4(0.0, 0.0, 0.5, 1.0));
+        for h in [0.0, 0.3, 0.75] {
+            assert_near(hsv_to_rgb(&vec4(h, 0.0, 0.5, 1.0)), grey(0.5), 1e-6);
+        }
+    }
+
+    #[test]
+    fn hsl_known_values() {
+        let cases = [
+            (RED, vec4(0.0, 1.0, 0.5, 1.0)),
+            (GREEN, vec4(1.0 / 3.0, 1.0, 0.5, 1.0)),
+            (BLUE, vec4(2.0 / 3.0, 1.0, 0.5, 1.0)),
+            (vec4(0.5, 0.25, 0.25, 0.5), vec4(0.0, 1.0 / 3.0, 0.375, 0.5)),
+            (
+                vec4(0.25, 0.5, 1.0, 1.0),
+                vec4(11.0 / 18.0, 1.0, 0.625, 1.0),
+            ),
+            (WHITE, vec4(0.0, 0.0, 1.0, 1.0)),
+            (BLACK, vec4(0.0, 0.0, 0.0, 1.0)),
+        ];
+        for (rgb, hsl) in cases {
+            assert_near(rgb_to_hsl(&rgb), hsl, 1e-6);
+            assert_near(hsl_to_rgb(&hsl), rgb, 1e-6);
+        }
+
+        assert_eq!(rgb_to_hsl(&grey(0.5)), vec4(0.0, 0.0, 0.5, 1.0));
+        for h in [0.0, 0.3, 0.75] {
+            assert_near(hsl_to_rgb(&vec4(h, 0.0, 0.5, 1.0)), grey(0.5), 1e-6);
+        }
+    }
+
+    #[test]
+    fn hsv_hsl_round_trip() {
+        let mut rand = GameRand::new(12345);
+        for _ in 0..1000 {
+            let c = vec4(
+                rand.next_random01(),
+                rand.next_random01(),
+                rand.next_random01(),
+                rand.next_random01(),
+            );
+            assert_near(hsv_to_rgb(&rgb_to_hsv(&c)), c, 1e-5);
+            assert_near(hsl_to_rgb(&rgb_to_hsl(&c)), c, 1e-5);
+        }
+    }
+
+    #[test]
+    fn rgba8_packing() {
+        assert_eq!(pack_rgba8(&RED), 0xff0000ff);
+        assert_eq!(pack_rgba8(&vec4(0.0, 0.5, 1.0, 0.25)), 0x40ff8000);
+        // Red first in memory, like the UBYTE4N vertex format
+        assert_eq!(
+            pack_rgba8(&vec4(0.0, 0.5, 1.0, 0.25)).to_le_bytes(),
+            [0, 128, 255, 64]
+        );
+
+        // Rounded to the nearest byte and clamped
+        assert_eq!(
+            pack_rgba8(&vec4(0.5 / 255.0, 0.49 / 255.0, 254.6 / 255.0, 0.0)),
+            0x00ff0001
+        );
+        assert_eq!(pack_rgba8(&vec4(-1.0, 2.0, 0.0, 1.0)), 0xff00ff00);
+
+        assert_eq!(
+            unpack_rgba8(0x40ff8000),
+            vec4(0.0, 128.0 / 255.0, 1.0, 64.0 / 255.0)
+        );
+        for packed in [0, 0xffffffff, 0x12345678, 0x80ff007f] {
+            assert_eq!(pack_rgba8(&unpack_rgba8(packed)), packed);
+        }
+        let c = vec4(0.1, 0.2, 0.3, 0.4);
+        assert_near(unpack_rgba8(pack_rgba8(&c)), c, 1e-2);
+    }
+
+    #[test]
+    fn premultiplied_alpha() {
+        let c = vec4(1.0, 0.5, 0.25, 0.5);
+        let p = premultiply_alpha(&c);
+        assert_eq!(p, vec4(0.5, 0.25, 0.125, 0.5));
+        assert_eq!(unpremultiply_alpha(&p), c);
+        assert_eq!(premultiply_alpha(&WHITE), WHITE);
+        assert_eq!(premultiply_alpha(&vec4(1.0, 0.5, 0.25, 0.0)), TRANSPARENT);
+        assert_eq!(unpremultiply_alpha(&vec4(0.5, 0.5, 0.5, 0.0)), TRANSPARENT);
+    }
+
+    #[test]
+    fn gradient_sample() {
+        let g = Gradient::new(&[(0.0, RED), (0.5, GREEN), (1.0, BLUE)]);
+        assert_eq!(g.sample(-1.0), RED);
+        assert_eq!(g.sample(0.25), vec4(0.5, 0.5, 0.0, 1.0));
+        assert_eq!(g.sample(0.75), vec4(0.0, 0.5, 0.5, 1.0));
+        assert_eq!(g.sample(2.0), BLUE);
+        assert_eq!(Gradient::constant(CYAN).sample(0.3), CYAN);
+        assert_eq!(Gradient::new(&[]).sample(0.3), TRANSPARENT);
+    }
+}
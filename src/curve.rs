@@ -0,0 +1,197 @@
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interpolation {
+    /// Hold the value of the previous key
+    Step,
+    Linear,
+    /// Ease in and out of each key (smoothstep)
+    Smooth,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+}
+
+/// Sample a keyframe track, times before the first or after the last key get the first or last value.
+/// Returns None if there are no keys.
+/// * `keys` - The keys, sorted by time
+/// * `time` - The time to sample at
+/// * `interpolation` - How to interpolate between keys
+/// * `interpolate` - Interpolates between two values by a 0-1 weight, e.g. `lerp` or `slerp`
+pub fn sample_keys<T: Copy>(
+    keys: &[Keyframe<T>],
+    time: f32,
+    interpolation: Interpolation,
+    interpolate: fn(&T, &T, f32) -> T,
+) -> Option<T> {
+    let first = keys.first()?;
+    let last = keys.last()?;
+    if time <= first.time {
+        return Some(first.value);
+    }
+    if time >= last.time {
+        return Some(last.value);
+    }
+
+    // Index of the first key after the time, at least 1 due to the checks above
+    let next = keys.partition_point(|k| k.time <= time);
+    let k0 = &keys[next - 1];
+    let k1 = &keys[next];
+    let a = (time - k0.time) / (k1.time - k0.time);
+    match interpolation {
+        Interpolation::Step => Some(k0.value),
+        Interpolation::Linear => Some(interpolate(&k0.value, &k1.value, a)),
+        Interpolation::Smooth => Some(interpolate(&k0.value, &k1.value, a * a * (3.0 - 2.0 * a))),
+    }
+}
+
+/// Keyframed scalar value, e.g. a particle size multiplier over its life
+#[derive(Clone, Debug)]
+pub struct Curve {
+    /// Keys sorted by time
+    pub keys: Vec<Keyframe<f32>>,
+    pub interpolation: Interpolation,
+}
+
+impl Curve {
+    /// * `keys` - Time and value of each key, sorted by time
+    pub fn new(keys: &[(f32, f32)], interpolation: Interpolation) -> Curve {
+        debug_assert!(keys.windows(2).all(|k| k[0].0 <= k[1].0));
+        Curve {
+            keys: keys
+                .iter()
+                .map(|&(time, value)| Keyframe { time, value })
+                .collect(),
+            interpolation,
+        }
+    }
+
+    /// A curve with the same value at all times
+    pub fn constant(value: f32) -> Curve {
+        Curve::new(&[(0.0, value)], Interpolation::Step)
+    }
+
+    /// Get the value at a time, a curve without keys is 0
+    pub fn sample(&self, time: f32) -> f32 {
+        sample_keys(&self.keys, time, self.interpolation, |a, b, t| {
+            a + (b - a) * t
+        })
+        .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(interpolation: Interpolation) -> Curve {
+        Curve::new(&[(0.0, 1.0), (1.0, 3.0), (3.0, -1.0)], interpolation)
+    }
+
+    #[test]
+    fn step() {
+        let c = curve(Interpolation::Step);
+        assert_eq!(c.sample(0.0), 1.0);
+        assert_eq!(c.sample(0.99), 1.0);
+        assert_eq!(c.sample(1.0), 3.0);
+        assert_eq!(c.sample(2.5), 3.0);
+        assert_eq!(c.sample(3.0), -1.0);
+    }
+
+    #[test]
+    fn linear() {
+        let c = curve(Interpolation::Linear);
+        assert_eq!(c.sample(0.25), 1.5);
+        assert_eq!(c.sample(0.5), 2.0);
+        assert_eq!(c.sample(1.0), 3.0);
+        assert_eq!(c.sample(2.0), 1.0);
+        assert_eq!(c.sample(2.5), 0.0);
+    }
+
+    #[test]
+    fn smooth() {
+        let c = curve(Interpolation::Smooth);
+        // Smoothstep of 0.25 is 0.15625 and of 0.75 is 0.84375
+        assert_eq!(c.sample(0.25), 1.3125);
+        assert_eq!(c.sample(0.5), 2.0);
+        assert_eq!(c.sample(0.75), 2.6875);
+        assert_eq!(c.sample(2.0), 1.0);
+
+        // Flat at the keys
+        let eps = 1e-3;
+        assert!((c.sample(1.0 - eps) - 3.0).abs() < 1e-4);
+        assert!((c.sample(1.0 + eps) - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn clamps_outside_the_keys() {
+        for interpolation in [
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::Smooth,
+        ] {
+            let c = curve(interpolation);
+            assert_eq!(c.sample(-1.0), 1.0);
+            assert_eq!(c.sample(f32::NEG_INFINITY), 1.0);
+            assert_eq!(c.sample(10.0), -1.0);
+            assert_eq!(c.sample(f32::INFINITY), -1.0);
+        }
+    }
+
+    #[test]
+    fn single_key_and_empty() {
+        let c = Curve::new(&[(0.5, 2.0)], Interpolation::Linear);
+        assert_eq!(c.sample(0.0), 2.0);
+        assert_eq!(c.sample(0.5), 2.0);
+        assert_eq!(c.sample(7.0), 2.0);
+        assert_eq!(Curve::constant(4.0).sample(100.0), 4.0);
+
+        assert_eq!(Curve::new(&[], Interpolation::Linear).sample(0.5), 0.0);
+        let keys: [Keyframe<f32>; 0] = [];
+        assert_eq!(
+            sample_keys(&keys, 0.5, Interpolation::Linear, |a, _, _| *a),
+            None
+        );
+    }
+
+    // Two keys at the same time make a jump, taking the second value from that time on
+    #[test]
+    fn equal_times() {
+        let c = Curve::new(
+            &[(0.0, 0.0), (1.0, 0.0), (1.0, 5.0), (2.0, 5.0)],
+            Interpolation::Linear,
+        );
+        assert_eq!(c.sample(0.999), 0.0);
+        assert_eq!(c.sample(1.0), 5.0);
+        assert_eq!(c.sample(1.5), 5.0);
+    }
+
+    #[test]
+    fn interpolates_other_types() {
+        let keys = [
+            Keyframe {
+                time: 0.0,
+                value: [0.0, 10.0],
+            },
+            Keyframe {
+                time: 2.0,
+                value: [4.0, 0.0],
+            },
+        ];
+        let lerp2 = |a: &[f32; 2], b: &[f32; 2], t: f32| {
+            [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]
+        };
+        assert_eq!(
+            sample_keys(&keys, 0.5, Interpolation::Linear, lerp2),
+            Some([1.0, 7.5])
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn unsorted_keys() {
+        Curve::new(&[(1.0, 0.0), (0.0, 1.0)], Interpolation::Linear);
+    }
+}
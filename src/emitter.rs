@@ -0,0 +1,443 @@
+use crate::game_rand::GameRand;
+use crate::model::{AttributeType, Batch};
+use crate::vector::*;
+
+/// Where new particles are spawned, relative to the particle system position.
+/// Oriented shapes are oriented by the emission direction.
+#[derive(Clone)]
+pub enum EmitterShape {
+    Point,
+    /// Inside the sphere, or on its surface if `surface` is set
+    Sphere {
+        radius: f32,
+        surface: bool,
+    },
+    /// Inside the half of the sphere on the emission direction side, or on its surface if `surface` is set
+    Hemisphere {
+        radius: f32,
+        surface: bool,
+    },
+    Box {
+        half_extents: vec3,
+    },
+    /// On a disk facing the emission direction
+    Disk {
+        radius: f32,
+    },
+    /// On a disk facing the emission direction, with the normal tilting outward up to `angle` radians at the edge
+    Cone {
+        radius: f32,
+        angle: f32,
+    },
+    /// On the line segment between two points
+    Line {
+        start: vec3,
+        end: vec3,
+    },
+    /// On the surface of a mesh
+    Mesh(MeshEmitter),
+}
+
+/// How the direction of new particles is chosen, before the spread is applied
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EmitDirection {
+    /// Along the emission direction
+    Fixed,
+    /// Along the emitter shape normal at the spawn point: outward for spheres, the tilted normal for cones
+    /// and the surface normal for meshes. Other shapes use the emission direction.
+    Normal,
+}
+
+/// How new particle directions are spread around the emit direction
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpreadShape {
+    /// Uniform within a cone, the spread is the cone angle in radians
+    Cone,
+    /// The original particle direction: offsets along two axes perpendicular to the emit direction, each up to
+    /// the spread and weighted toward 0 like `GameRand::spread`. Directions fill a square pyramid and are
+    /// denser near its centre. A spread of 0.3 reaches about 17 degrees along the axes and 23 at the corners.
+    Square,
+}
+
+impl SpreadShape {
+    /// Pick a random direction around an axis
+    /// * `rand` - The random number generator to use
+    /// * `axis` - The normalized direction to spread around
+    /// * `spread` - The cone angle in radians for cones, the largest offset along each axis for squares
+    pub fn sample(&self, rand: &mut GameRand, axis: &vec3, spread: f32) -> vec3 {
+        match self {
+            SpreadShape::Cone => rand.dir_in_cone(axis, spread),
+            SpreadShape::Square => {
+                // cross(t, axis) so +Y spreads along +X and +Z like the original,
+                // the second basis vector would be -Z
+                let (t, _) = orthonormal_basis(axis);
+                let b = cross(&t, axis);
+                let x = rand.spread(0.0, spread);
+                let y = rand.spread(0.0, spread);
+                normalize(&(*axis + t * x + b * y))
+            }
+        }
+    }
+}
+
+impl EmitterShape {
+    /// Pick a random spawn point, returns its offset from the emitter position and the shape normal there
+    /// * `rand` - The random number generator to use
+    /// * `direction` - The normalized emission direction, orienting the disk, cone and hemisphere shapes
+    pub fn sample(&self, rand: &mut GameRand, direction: &vec3) -> (vec3, vec3) {
+        match self {
+            EmitterShape::Point => (vec3(0.0, 0.0, 0.0), *direction),
+            EmitterShape::Sphere { radius, surface } => {
+                let (p, normal) = sphere_point(rand, *surface, direction);
+                (p * *radius, normal)
+            }
+            EmitterShape::Hemisphere { radius, surface } => {
+                let (mut p, mut normal) = sphere_point(rand, *surface, direction);
+                if dot(&p, direction) < 0.0 {
+                    p = -p;
+                    normal = -normal;
+                }
+                (p * *radius, normal)
+            }
+            EmitterShape::Box { half_extents } => {
+                let p = vec3(
+                    rand.rand_range_f32(-1.0, 1.0),
+                    rand.rand_range_f32(-1.0, 1.0),
+                    rand.rand_range_f32(-1.0, 1.0),
+                );
+                (p * *half_extents, *direction)
+            }
+            EmitterShape::Disk { radius } => {
+                let (t, b) = orthonormal_basis(direction);
+                let d = rand.point_in_disk() * *radius;
+                (t * d.x + b * d.y, *direction)
+            }
+            EmitterShape::Cone { radius, angle } => {
+                let (t, b) = orthonormal_basis(direction);
+                let d = rand.point_in_disk();
+                let dist = length(&d);
+                let radial = t * d.x + b * d.y;
+                let normal = if dist > 0.0 {
+                    let tilt = *angle * dist;
+                    *direction * tilt.cos() + radial * (tilt.sin() / dist)
+                } else {
+                    *direction
+                };
+                (radial * *radius, normal)
+            }
+            EmitterShape::Line { start, end } => {
+                let a = rand.next_random01();
+                (lerp(start, end, a), *direction)
+            }
+            EmitterShape::Mesh(mesh) => mesh.sample(rand),
+        }
+    }
+}
+
+// A point in or on the unit sphere, with the direction from the center as the normal
+fn sphere_point(rand: &mut GameRand, surface: bool, direction: &vec3) -> (vec3, vec3) {
+    if surface {
+        let p = rand.point_on_sphere();
+        return (p, p);
+    }
+    let p = rand.point_in_sphere();
+    let len = length(&p);
+    let normal = if len > 0.0 { p / len } else { *direction };
+    (p, normal)
+}
+
+/// The triangles of a mesh, for spawning particles evenly over its surface
+#[derive(Clone)]
+pub struct MeshEmitter {
+    triangles: Vec<[vec3; 3]>,
+    // Running total of the triangle areas, for picking triangles in proportion to their area
+    cumulative_area: Vec<f32>,
+}
+
+impl MeshEmitter {
+    /// Collect the triangles of a batch, using the first position attribute.
+    /// Returns None if the batch has no positions or no triangles with an area.
+    pub fn new(batch: &Batch) -> Option<MeshEmitter> {
+        let format = batch.find_format(AttributeType::Vertex, 0)?;
+        let position = |v: u32| -> vec3 {
+            let [x, y, z, _] = batch.read_attribute(format, v);
+            vec3(x, y, z)
+        };
+
+        let mut ret = MeshEmitter {
+            triangles: Vec::new(),
+            cumulative_area: Vec::new(),
+        };
+        let mut total_area = 0.0;
+        batch.for_each_triangle(|a, b, c| {
+            let v = [position(a), position(b), position(c)];
+            let area = 0.5 * length(&cross(&(v[1] - v[0]), &(v[2] - v[0])));
+            if area > 0.0 {
+                total_area += area;
+                ret.triangles.push(v);
+                ret.cumulative_area.push(total_area);
+            }
+        });
+
+        if ret.triangles.is_empty() {
+            return None;
+        }
+        Some(ret)
+    }
+
+    /// Pick a uniformly distributed point on the surface.
+    /// Returns the point and the normal of the triangle it is on, facing the counter clockwise side.
+    pub fn sample(&self, rand: &mut GameRand) -> (vec3, vec3) {
+        let total_area = *self.cumulative_area.last().unwrap();
+        let target = rand.next_random01() * total_area;
+        let index = self
+            .cumulative_area
+            .partition_point(|&area| area <= target)
+            .min(self.triangles.len() - 1);
+        let [v0, v1, v2] = &self.triangles[index];
+
+        // Fold points outside the triangle back inside
+        let mut u = rand.next_random01();
+        let mut v = rand.next_random01();
+        if u + v > 1.0 {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        let edge1 = *v1 - *v0;
+        let edge2 = *v2 - *v0;
+        let point = *v0 + edge1 * u + edge2 * v;
+        (point, normalize(&cross(&edge1, &edge2)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{AttributeFormat, Format, PrimitiveType};
+    use crate::rand_quality::{chi_square_test, Z_LIMIT};
+
+    const SAMPLES: usize = 10_000;
+
+    fn direction() -> vec3 {
+        normalize(&vec3(1.0, -2.0, 0.5))
+    }
+
+    fn assert_unit(v: &vec3) {
+        assert!((length(v) - 1.0).abs() < 1e-5, "{v:?}");
+    }
+
+    // Call a check with samples of a shape emitting along direction()
+    fn for_each_sample(shape: &EmitterShape, mut check: impl FnMut(vec3, vec3)) {
+        let mut rand = GameRand::new(42);
+        for _ in 0..SAMPLES {
+            let (p, normal) = shape.sample(&mut rand, &direction());
+            check(p, normal);
+        }
+    }
+
+    #[test]
+    fn point() {
+        for_each_sample(&EmitterShape::Point, |p, normal| {
+            assert_eq!(p, vec3(0.0, 0.0, 0.0));
+            assert_eq!(normal, direction());
+        });
+    }
+
+    #[test]
+    fn sphere() {
+        let volume = EmitterShape::Sphere {
+            radius: 2.0,
+            surface: false,
+        };
+        let mut max_len = 0.0f32;
+        for_each_sample(&volume, |p, normal| {
+            assert!(length(&p) <= 2.0 + 1e-5);
+            assert_unit(&normal);
+            assert!(length(&(normal * length(&p) - p)) < 1e-4);
+            max_len = max_len.max(length(&p));
+        });
+        assert!(max_len > 1.9);
+
+        let surface = EmitterShape::Sphere {
+            radius: 2.0,
+            surface: true,
+        };
+        for_each_sample(&surface, |p, normal| {
+            assert!((length(&p) - 2.0).abs() < 1e-4);
+            assert!(length(&(normal * 2.0 - p)) < 1e-4);
+        });
+    }
+
+    #[test]
+    fn hemisphere() {
+        for surface in [false, true] {
+            let shape = EmitterShape::Hemisphere {
+                radius: 2.0,
+                surface,
+            };
+            let mut max_len = 0.0f32;
+            for_each_sample(&shape, |p, normal| {
+                let len = length(&p);
+                if surface {
+                    assert!((len - 2.0).abs() < 1e-4);
+                } else {
+                    assert!(len <= 2.0 + 1e-5);
+                }
+                assert!(dot(&p, &direction()) >= 0.0, "{p:?} behind the emitter");
+                assert!(dot(&normal, &direction()) >= 0.0);
+                assert!(length(&(normal * len - p)) < 1e-4);
+                max_len = max_len.max(len);
+            });
+            assert!(max_len > 1.9);
+        }
+    }
+
+    #[test]
+    fn box_shape() {
+        let half_extents = vec3(1.0, 2.0, 0.5);
+        for_each_sample(&EmitterShape::Box { half_extents }, |p, normal| {
+            assert_eq!(clamp(&p, &-half_extents, &half_extents), p);
+            assert_eq!(normal, direction());
+        });
+    }
+
+    #[test]
+    fn disk() {
+        let mut max_len = 0.0f32;
+        for_each_sample(&EmitterShape::Disk { radius: 3.0 }, |p, normal| {
+            assert!(
+                dot(&p, &direction()).abs() < 1e-5,
+                "{p:?} not in the disk plane"
+            );
+            assert!(length(&p) <= 3.0 + 1e-5);
+            assert_eq!(normal, direction());
+            max_len = max_len.max(length(&p));
+        });
+        assert!(max_len > 2.9);
+    }
+
+    #[test]
+    fn cone() {
+        let angle = 0.4;
+        let shape = EmitterShape::Cone { radius: 3.0, angle };
+        let mut max_angle = 0.0f32;
+        for_each_sample(&shape, |p, normal| {
+            assert!(dot(&p, &direction()).abs() < 1e-5);
+            assert!(length(&p) <= 3.0 + 1e-5);
+            assert_unit(&normal);
+
+            // Tilted outward, by an angle growing with the distance from the centre
+            let tilt = dot(&normal, &direction()).clamp(-1.0, 1.0).acos();
+            assert!(tilt <= angle + 1e-3);
+            assert!((tilt - angle * length(&p) / 3.0).abs() < 1e-3);
+            if length(&p) > 1e-3 {
+                assert!(dot(&normal, &p) >= 0.0);
+            }
+            max_angle = max_angle.max(tilt);
+        });
+        assert!(max_angle > angle * 0.95);
+    }
+
+    #[test]
+    fn line() {
+        let start = vec3(1.0, 2.0, 3.0);
+        let end = vec3(-3.0, 2.0, 5.0);
+        let shape = EmitterShape::Line { start, end };
+        let segment = end - start;
+        let (mut lo, mut hi) = (1.0f32, 0.0f32);
+        for_each_sample(&shape, |p, normal| {
+            let a = dot(&(p - start), &segment) / length_squared(&segment);
+            assert!((0.0..=1.0).contains(&a));
+            assert!(
+                length(&(start + segment * a - p)) < 1e-5,
+                "{p:?} off the line"
+            );
+            assert_eq!(normal, direction());
+            lo = lo.min(a);
+            hi = hi.max(a);
+        });
+        assert!(lo < 0.01 && hi > 0.99);
+    }
+
+    // Two triangles in separate planes, the second with three times the area of the first
+    fn two_triangle_batch() -> Batch {
+        let positions: [f32; 18] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0, //
+            0.0, 0.0, 5.0, 3.0, 0.0, 5.0, 0.0, 2.0, 5.0,
+        ];
+        let vertices = positions.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let formats = vec![Format::new(
+            AttributeType::Vertex,
+            AttributeFormat::Float,
+            3,
+            0,
+            0,
+        )];
+        Batch::new(
+            PrimitiveType::Triangles,
+            formats,
+            12,
+            vertices,
+            0,
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn mesh_area_weighting() {
+        let mesh = MeshEmitter::new(&two_triangle_batch()).unwrap();
+        let mut rand = GameRand::new(42);
+        let mut counts = [0u64; 2];
+        for _ in 0..SAMPLES {
+            let (p, normal) = mesh.sample(&mut rand);
+            let (index, width) = if p.z == 0.0 { (0, 1.0) } else { (1, 3.0) };
+            assert!(p.z == 0.0 || p.z == 5.0);
+            // Inside the triangle with corners (0, 0), (width, 0) and (0, 2)
+            assert!(p.x >= 0.0 && p.y >= 0.0);
+            assert!(p.x / width + p.y / 2.0 <= 1.0 + 1e-5, "{p:?}");
+            assert_eq!(normal, vec3(0.0, 0.0, 1.0));
+            counts[index] += 1;
+        }
+        let z = chi_square_test(&counts, &[0.25, 0.75]);
+        assert!(z.abs() < Z_LIMIT, "z = {z}, counts {counts:?}");
+    }
+
+    #[test]
+    fn mesh_without_triangles() {
+        let formats = vec![Format::new(
+            AttributeType::Vertex,
+            AttributeFormat::Float,
+            3,
+            0,
+            0,
+        )];
+        // Three points on a line
+        let positions: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0];
+        let vertices = positions.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let batch = Batch::new(
+            PrimitiveType::Triangles,
+            formats,
+            12,
+            vertices,
+            0,
+            Vec::new(),
+        );
+        assert!(MeshEmitter::new(&batch).is_none());
+    }
+
+    #[test]
+    fn square_spread() {
+        let mut rand = GameRand::new(42);
+        let axis = direction();
+        let (t, _) = orthonormal_basis(&axis);
+        let b = cross(&t, &axis);
+        for _ in 0..SAMPLES {
+            let dir = SpreadShape::Square.sample(&mut rand, &axis, 0.3);
+            assert_unit(&dir);
+            // Offsets along both axes are at most the spread, relative to the axis component
+            let along = dot(&dir, &axis);
+            assert!(dot(&dir, &t).abs() <= 0.3 * along + 1e-5);
+            assert!(dot(&dir, &b).abs() <= 0.3 * along + 1e-5);
+        }
+    }
+}
@@ -22,29 +22,95 @@
 // random number generator.  _Statistics and Probability Letters
 // 8_ (1990), 35-39.
 
-// const Uint64 a = 18782; // for SEED_COUNT=4096, period approx 2^131104 (from Marsaglia usenet post 2003-05-13)
-// const Uint64 a = 123471786; // for SEED_COUNT=1024, period approx 2^32794
-// const Uint64 a = 123554632; // for SEED_COUNT=512, period approx 2^16410
-// const Uint64 a = 8001634; // for SEED_COUNT=256, period approx 2^8182
-// const Uint64 a = 8007626; // for SEED_COUNT=128, period approx 2^4118
-// const Uint64 a = 647535442; // for SEED_COUNT=64, period approx 2^2077
-// const Uint64 a = 547416522; // for SEED_COUNT=32, period approx 2^1053
-// const Uint64 a = 487198574; // for SEED_COUNT=16, period approx  2^540
-const SEED_COUNT: u32 = 8;
+use crate::vector::*;
+use std::f32::consts::TAU;
+
+/// Get the CMWC multiplier for a seed table size, these are the sizes with a multiplier from the article
+/// or Marsaglia's later usenet posts
+pub const fn seed_multiplier(seed_count: usize) -> u64 {
+    match seed_count {
+        8 => 716514398,    // period approx 2^285
+        16 => 487198574,   // period approx 2^540
+        32 => 547416522,   // period approx 2^1053
+        64 => 647535442,   // period approx 2^2077
+        128 => 8007626,    // period approx 2^4118
+        256 => 8001634,    // period approx 2^8182
+        512 => 123554632,  // period approx 2^16410
+        1024 => 123471786, // period approx 2^32794
+        4096 => 18782,     // period approx 2^131104 (from Marsaglia usenet post 2003-05-13)
+        _ => panic!("Unsupported GameRand seed table size"),
+    }
+}
+
+/// The default generator with an 8 entry seed table, period approx 2^285
+pub type GameRand = GameRandN<8>;
+pub type GameRandState = GameRandStateN<8>;
+
+/// The complete state of a GameRandN, for saving and restoring a sequence position
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GameRandStateN<const SEED_COUNT: usize> {
+    pub param_q: [u32; SEED_COUNT],
+    pub param_c: u32,
+    pub param_i: u32,
+}
+
+impl<const SEED_COUNT: usize> GameRandStateN<SEED_COUNT> {
+    /// Size of the state in bytes when written with `write_bytes`
+    pub const BYTE_SIZE: usize = (SEED_COUNT + 2) * 4;
+
+    /// Append the state to a buffer as little endian u32 values
+    pub fn write_bytes(&self, out: &mut Vec<u8>) {
+        for q in &self.param_q {
+            out.extend_from_slice(&q.to_le_bytes());
+        }
+        out.extend_from_slice(&self.param_c.to_le_bytes());
+        out.extend_from_slice(&self.param_i.to_le_bytes());
+    }
+
+    /// Read a state written by `write_bytes`.
+    /// Returns None if the buffer is too short or does not contain a valid state.
+    pub fn read_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::BYTE_SIZE {
+            return None;
+        }
+        let mut values = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+        let mut ret = GameRandStateN {
+            param_q: [0; SEED_COUNT],
+            param_c: 0,
+            param_i: 0,
+        };
+        for q in &mut ret.param_q {
+            *q = values.next()?;
+        }
+        ret.param_c = values.next()?;
+        ret.param_i = values.next()?;
+        if ret.param_i >= SEED_COUNT as u32 {
+            return None;
+        }
+        Some(ret)
+    }
+}
 
 /// A random number generator that is deterministic and suitable for games.
-pub struct GameRand {
-    param_q: [u32; SEED_COUNT as usize],
+/// * `SEED_COUNT` - The seed table size, larger tables give longer periods. Must be supported by `seed_multiplier`.
+#[derive(Clone)]
+pub struct GameRandN<const SEED_COUNT: usize> {
+    param_q: [u32; SEED_COUNT],
     param_c: u32,
     param_i: u32,
 }
 
-impl GameRand {
+impl<const SEED_COUNT: usize> GameRandN<SEED_COUNT> {
+    const MULTIPLIER: u64 = seed_multiplier(SEED_COUNT);
+
     /// Constructor that sets a random seed value on the number generator
     /// * `seed` - The seed value to use
-    pub fn new(seed: u32) -> GameRand {
-        let mut ret = GameRand {
-            param_q: [0; SEED_COUNT as usize],
+    pub fn new(seed: u32) -> Self {
+        let mut ret = GameRandN {
+            param_q: [0; SEED_COUNT],
             param_c: 0,
             param_i: 0,
         };
@@ -55,6 +121,9 @@ impl GameRand {
     /// Reset the random seed value on the number generator (not necessary to call)
     /// * `seed` - The seed value to use
     pub fn seed_random(&mut self, seed: u32) {
+        // Evaluated at compile time, so unsupported sizes fail to build
+        const { assert!(Self::MULTIPLIER > 0) };
+
         let mut j = seed;
         if j == 0 {
             j = 12345; // 0 is a terrible seed (probably the only bad choice), substitute something else:
@@ -67,15 +136,77 @@ impl GameRand {
         }
 
         self.param_c = 362436;
-        self.param_i = SEED_COUNT - 1;
+        self.param_i = (SEED_COUNT - 1) as u32;
+    }
+
+    /// Get the current state, restoring it with `set_state` continues the sequence from this point
+    pub fn get_state(&self) -> GameRandStateN<SEED_COUNT> {
+        GameRandStateN {
+            param_q: self.param_q,
+            param_c: self.param_c,
+            param_i: self.param_i,
+        }
+    }
+
+    /// Restore a state returned by `get_state`
+    pub fn set_state(&mut self, state: &GameRandStateN<SEED_COUNT>) {
+        debug_assert!(state.param_i < SEED_COUNT as u32);
+        self.param_q = state.param_q;
+        self.param_c = state.param_c;
+        self.param_i = state.param_i & (SEED_COUNT - 1) as u32;
+    }
+
+    /// Create a number generator continuing from a state returned by `get_state`
+    pub fn from_state(state: &GameRandStateN<SEED_COUNT>) -> Self {
+        let mut ret = Self::new(0);
+        ret.set_state(state);
+        ret
+    }
+
+    /// Create an independent child number generator from the current state.
+    /// Advances this generator, so repeated calls give different children.
+    pub fn fork(&mut self) -> Self {
+        self.next_random();
+        Self::from_hash(self.hash_state(FORK_KEY))
+    }
+
+    /// Create an independent child number generator for a stream id, e.g. one per particle system.
+    /// Does not advance this generator, the same state and stream id always give the same child.
+    /// * `stream_id` - The id of the child stream
+    pub fn split(&self, stream_id: u32) -> Self {
+        Self::from_hash(self.hash_state((stream_id as u64).wrapping_mul(GOLDEN_GAMMA)))
+    }
+
+    // Hash the state and a key. The 64 bit mixing keeps children of nearby keys and states uncorrelated.
+    fn hash_state(&self, key: u64) -> u64 {
+        let mut hash = mix64(key);
+        for value in self.param_q.iter().chain([&self.param_c, &self.param_i]) {
+            hash = mix64(hash ^ *value as u64);
+        }
+        hash
+    }
+
+    // Fill the seed table, carry and index from a hash
+    fn from_hash(mut hash: u64) -> Self {
+        let mut next = || {
+            hash = hash.wrapping_add(GOLDEN_GAMMA);
+            mix64(hash)
+        };
+        let mut ret = Self::new(0);
+        for q in &mut ret.param_q {
+            *q = (next() >> 32) as u32;
+        }
+        ret.param_c = (next() % Self::MULTIPLIER) as u32;
+        ret.param_i = (next() % SEED_COUNT as u64) as u32;
+        ret
     }
 
     /// Return the next pseudo-random number in the sequence.
     pub fn next_random(&mut self) -> u32 {
         let r: u32 = 0xFFFFFFFE;
-        let a: u64 = 716514398; // for SEED_COUNT=8, period approx 2^285
+        let a: u64 = Self::MULTIPLIER;
 
-        self.param_i = (self.param_i + 1) & (SEED_COUNT - 1);
+        self.param_i = (self.param_i + 1) & (SEED_COUNT - 1) as u32;
 
         let q = &mut self.param_q[self.param_i as usize];
         let t: u64 = a * (*q as u64) + (self.param_c as u64);
@@ -92,30 +223,762 @@ impl GameRand {
         return val;
     }
 
-    /// Return the next pseudo-random number in the 0..1 range.
-    /// DT_TODO: Test this - unsure if the distribution in the high values is equal with the float 24bit precision
+    /// Return the next pseudo-random number in the [0, 1) range.
+    /// Uses 24 bits of the random value, so every value is an even multiple of 2^-24 and 1.0 is never returned.
     pub fn next_random01(&mut self) -> f32 {
-        const DIV: f32 = 1.0 / (u32::MAX as f32);
-        let val = self.next_random();
-        let val_f = (val as f32) * DIV;
+        const SCALE: f32 = 1.0 / (1u32 << 24) as f32;
+        (self.next_random() >> 8) as f32 * SCALE
+    }
 
-        return val_f;
+    /// Return the next pseudo-random number in the [0, 1) range, with 53 bits of precision from two random values.
+    pub fn next_random01_f64(&mut self) -> f64 {
+        const SCALE: f64 = 1.0 / (1u64 << 53) as f64;
+        let hi = (self.next_random() >> 5) as u64;
+        let lo = (self.next_random() >> 6) as u64;
+        ((hi << 26) | lo) as f64 * SCALE
     }
 
-    /// Generate a pseudo-random number within a given bounds. Does not guarantee an exact even distribution
-    /// of values in the range, but if the range is small (<10000's) it is close to even.
+    /// Generate a pseudo-random number within a given bounds, with every value in the range equally likely.
     /// * `min` - The minimum bound of the random number, inclusive.
-    /// * `max` - The maximum bound of the random number, inclusive.
-    /// \precondition max >= min.
+    /// * `max` - The maximum bound of the random number, inclusive, must be >= min.
     pub fn rand_range(&mut self, min: u32, max: u32) -> u32 {
-        let mut val = self.next_random();
+        debug_assert!(max >= min);
+        let range_diff = max - min;
+        if range_diff == u32::MAX {
+            return self.next_random();
+        }
 
-        let range_diff: u32 = max - min;
-        if range_diff != u32::MAX {
-            val %= range_diff + 1;
-            val += min;
+        // Lemire's multiply and reject method, the top 32 bits of the product are the result.
+        // Rejecting low parts below 2^32 % range removes the bias of the values that occur once more.
+        let range = range_diff + 1;
+        let mut m = self.next_random() as u64 * range as u64;
+        if (m as u32) < range {
+            let threshold = range.wrapping_neg() % range;
+            while (m as u32) < threshold {
+                m = self.next_random() as u64 * range as u64;
+            }
         }
+        min + (m >> 32) as u32
+    }
 
-        return val;
+    /// Generate a signed pseudo-random number within a given bounds, with every value in the range equally likely.
+    /// * `min` - The minimum bound of the random number, inclusive.
+    /// * `max` - The maximum bound of the random number, inclusive, must be >= min.
+    pub fn rand_range_i32(&mut self, min: i32, max: i32) -> i32 {
+        debug_assert!(max >= min);
+        // Offset to unsigned, the difference always fits in a u32
+        let range_diff = max.wrapping_sub(min) as u32;
+        min.wrapping_add(self.rand_range(0, range_diff) as i32)
+    }
+
+    /// Generate a pseudo-random number in the half open range [min, max).
+    /// Panics if the bounds are not finite or max < min.
+    /// * `min` - The minimum bound of the random number, inclusive.
+    /// * `max` - The maximum bound of the random number, exclusive, must be >= min. Returns min if they are equal.
+    pub fn rand_range_f32(&mut self, min: f32, max: f32) -> f32 {
+        // NaN or infinite bounds would never give a value below max
+        assert!(
+            min.is_finite() && max.is_finite() && max >= min,
+            "Invalid random range [{min}, {max})"
+        );
+        loop {
+            let val = min + (max - min) * self.next_random01();
+            // Rounding can give max when the range is large compared to min
+            if val < max || max <= min {
+                return val;
+            }
+        }
+    }
+
+    /// Generate a pseudo-random number in the half open range [min, max).
+    /// Panics if the bounds are not finite or max < min.
+    /// * `min` - The minimum bound of the random number, inclusive.
+    /// * `max` - The maximum bound of the random number, exclusive, must be >= min. Returns min if they are equal.
+    pub fn rand_range_f64(&mut self, min: f64, max: f64) -> f64 {
+        // NaN or infinite bounds would never give a value below max
+        assert!(
+            min.is_finite() && max.is_finite() && max >= min,
+            "Invalid random range [{min}, {max})"
+        );
+        loop {
+            let val = min + (max - min) * self.next_random01_f64();
+            if val < max || max <= min {
+                return val;
+            }
+        }
+    }
+
+    /// Generate a pseudo-random number in [mean - spread, mean + spread), with values near the mean more likely.
+    /// The offset from the mean is r * |r| * spread for a uniform r in [-1, 1), so half the values are
+    /// within a quarter of the spread. Always uses one random value, even when the spread is 0.
+    /// * `mean` - The center of the range
+    /// * `spread` - The largest distance from the mean
+    pub fn spread(&mut self, mean: f32, spread: f32) -> f32 {
+        let r = self.rand_range_f32(-1.0, 1.0);
+        mean + r * r.abs() * spread
+    }
+
+    /// Generate a normally distributed pseudo-random number (Box-Muller transform)
+    /// * `mean` - The mean of the distribution
+    /// * `std_dev` - The standard deviation of the distribution
+    pub fn next_normal(&mut self, mean: f32, std_dev: f32) -> f32 {
+        // 1 - x is in (0, 1], keeping the log finite
+        let u = 1.0 - self.next_random01();
+        let theta = TAU * self.next_random01();
+        mean + std_dev * (-2.0 * u.ln()).sqrt() * theta.cos()
+    }
+
+    /// Generate an exponentially distributed pseudo-random number, e.g. the time between random events
+    /// * `rate` - The rate parameter (lambda), the mean of the result is 1 / rate
+    pub fn next_exponential(&mut self, rate: f32) -> f32 {
+        -(1.0 - self.next_random01()).ln() / rate
+    }
+
+    /// Generate a uniformly distributed point inside the unit sphere
+    pub fn point_in_sphere(&mut self) -> vec3 {
+        loop {
+            let p = vec3(
+                self.rand_range_f32(-1.0, 1.0),
+                self.rand_range_f32(-1.0, 1.0),
+                self.rand_range_f32(-1.0, 1.0),
+            );
+            if length_squared(&p) <= 1.0 {
+                return p;
+            }
+        }
+    }
+
+    /// Generate a uniformly distributed point on the surface of the unit sphere
+    pub fn point_on_sphere(&mut self) -> vec3 {
+        let z = self.rand_range_f32(-1.0, 1.0);
+        let phi = TAU * self.next_random01();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        vec3(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Generate a uniformly distributed point inside the unit disk
+    pub fn point_in_disk(&mut self) -> vec2 {
+        let r = self.next_random01().sqrt();
+        let phi = TAU * self.next_random01();
+        vec2(r * phi.cos(), r * phi.sin())
+    }
+
+    /// Generate a uniformly distributed unit vector within a cone
+    /// * `axis` - The normalized cone axis
+    /// * `angle` - The angle between the axis and the cone side in radians, PI gives the whole sphere
+    pub fn dir_in_cone(&mut self, axis: &vec3, angle: f32) -> vec3 {
+        let cos_theta = 1.0 - self.next_random01() * (1.0 - angle.cos());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = TAU * self.next_random01();
+        let (t, b) = orthonormal_basis(axis);
+        t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + *axis * cos_theta
+    }
+
+    /// Generate a uniformly distributed unit vector on the hemisphere around a normal
+    /// * `normal` - The normalized hemisphere normal
+    pub fn dir_in_hemisphere(&mut self, normal: &vec3) -> vec3 {
+        let p = self.point_on_sphere();
+        if dot(&p, normal) < 0.0 {
+            -p
+        } else {
+            p
+        }
+    }
+
+    /// Pick a random index with the probability of each index proportional to its weight.
+    /// Returns None if there are no positive weights.
+    /// * `weights` - The weight of each index, negative weights count as zero
+    pub fn weighted_choice(&mut self, weights: &[f32]) -> Option<usize> {
+        let total: f32 = weights.iter().map(|w| w.max(0.0)).sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut target = self.next_random01() * total;
+        let mut last = 0;
+        for (i, w) in weights.iter().enumerate() {
+            if *w <= 0.0 {
+                continue;
+            }
+            if target < *w {
+                return Some(i);
+            }
+            target -= w;
+            last = i;
+        }
+        // Rounding in the sum can leave a little over
+        Some(last)
+    }
+
+    /// Randomly reorder a slice, with all orders equally likely (Fisher-Yates shuffle)
+    pub fn shuffle<T>(&mut self, values: &mut [T]) {
+        for i in (1..values.len()).rev() {
+            let j = self.rand_range(0, i as u32) as usize;
+            values.swap(i, j);
+        }
+    }
+}
+
+const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+// Hash key for fork, so forks do not match any split stream
+const FORK_KEY: u64 = 0x6A09E667F3BCC909;
+
+// SplitMix64 finalizer
+fn mix64(z: u64) -> u64 {
+    let z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_quality::{chi_square_test, Z_LIMIT};
+
+    // Assert that bucket counts are consistent with the expected probabilities
+    fn assert_distribution(counts: &[u64], probabilities: &[f64]) {
+        let z = chi_square_test(counts, probabilities);
+        assert!(z.abs() < Z_LIMIT, "z = {z}, counts {counts:?}");
+    }
+
+    fn assert_uniform(counts: &[u64]) {
+        assert_distribution(counts, &vec![1.0 / counts.len() as f64; counts.len()]);
+    }
+
+    #[test]
+    fn float_ranges_are_half_open() {
+        let mut rand = GameRand::new(36);
+        // The last range is large compared to min, so rounding would often give max
+        for (min, max) in [
+            (0.0, 1.0),
+            (-1.0, 1.0),
+            (-5.0, -4.5),
+            (16777216.0, 16777220.0),
+        ] {
+            for _ in 0..100_000 {
+                let val = rand.rand_range_f32(min, max);
+                assert!(val >= min && val < max, "{val} in [{min}, {max})");
+                let val = rand.rand_range_f64(min as f64, max as f64);
+                assert!(
+                    val >= min as f64 && val < max as f64,
+                    "{val} in [{min}, {max})"
+                );
+            }
+        }
+        let val = rand.rand_range_f64(9007199254740992.0, 9007199254740996.0);
+        assert!(val < 9007199254740996.0);
+
+        assert_eq!(rand.rand_range_f32(2.5, 2.5), 2.5);
+        assert_eq!(rand.rand_range_f64(-2.5, -2.5), -2.5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn nan_float_range() {
+        GameRand::new(36).rand_range_f32(0.0, f32::NAN);
+    }
+
+    #[test]
+    #[should_panic]
+    fn infinite_float_range() {
+        GameRand::new(36).rand_range_f64(f64::NEG_INFINITY, 0.0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reversed_float_range() {
+        GameRand::new(36).rand_range_f32(1.0, 0.0);
+    }
+
+    #[test]
+    fn float_range_uniformity() {
+        let mut rand = GameRand::new(36);
+        let mut counts = [0u64; 50];
+        for _ in 0..200_000 {
+            counts[((rand.rand_range_f32(-3.0, 7.0) + 3.0) * 5.0) as usize] += 1;
+        }
+        assert_uniform(&counts);
+
+        let mut counts = [0u64; 50];
+        for _ in 0..200_000 {
+            counts[((rand.rand_range_f64(-3.0, 7.0) + 3.0) * 5.0) as usize] += 1;
+        }
+        assert_uniform(&counts);
+    }
+
+    // Uniformity, including the 3 * 2^30 range a plain modulo gets wrong, is in the quality battery
+    #[test]
+    fn integer_range_bounds() {
+        let mut rand = GameRand::new(36);
+        for (min, max) in [(0, 1), (5, 11), (1000, 1099)] {
+            let mut seen = vec![false; (max - min + 1) as usize];
+            for _ in 0..10_000 {
+                let val = rand.rand_range(min, max);
+                assert!(val >= min && val <= max);
+                seen[(val - min) as usize] = true;
+            }
+            assert!(seen.iter().all(|&s| s));
+        }
+
+        assert_eq!(rand.rand_range(7, 7), 7);
+        assert_eq!(rand.rand_range(u32::MAX, u32::MAX), u32::MAX);
+        let mut high_bit = 0;
+        for _ in 0..1000 {
+            high_bit += rand.rand_range(0, u32::MAX) >> 31;
+        }
+        assert!((400..600).contains(&high_bit));
+    }
+
+    #[test]
+    fn signed_ranges() {
+        let mut rand = GameRand::new(36);
+        for (min, max) in [(-5, 5), (-12, -3), (-1, 0)] {
+            let mut counts = vec![0u64; (max - min + 1) as usize];
+            for _ in 0..100_000 {
+                let val = rand.rand_range_i32(min, max);
+                assert!(val >= min && val <= max);
+                counts[(val - min) as usize] += 1;
+            }
+            assert_uniform(&counts);
+        }
+
+        // The full range, where max - min does not fit in an i32
+        let mut negative = 0;
+        for _ in 0..1000 {
+            negative += (rand.rand_range_i32(i32::MIN, i32::MAX) < 0) as u32;
+        }
+        assert!((400..600).contains(&negative));
+        assert_eq!(rand.rand_range_i32(i32::MIN, i32::MIN), i32::MIN);
+        assert_eq!(rand.rand_range_i32(-8, -8), -8);
+    }
+
+    // Count samples into buckets split at the given bounds, the last bucket is everything above the last bound
+    fn bucket_counts<F: FnMut() -> f64>(bounds: &[f64], samples: usize, mut sample: F) -> Vec<u64> {
+        let mut counts = vec![0u64; bounds.len() + 1];
+        for _ in 0..samples {
+            let val = sample();
+            counts[bounds.partition_point(|&b| b <= val)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn spread_distribution() {
+        let mut rand = GameRand::new(37);
+        let mut below = 0;
+        // |offset| < k * spread has probability sqrt(k)
+        let counts = bucket_counts(&[0.25, 0.5, 0.75], 100_000, || {
+            let val = rand.spread(10.0, 4.0);
+            assert!((6.0..14.0).contains(&val));
+            below += (val < 10.0) as u32;
+            ((val - 10.0) / 4.0).abs() as f64
+        });
+        let sqrt = f64::sqrt;
+        assert_distribution(
+            &counts,
+            &[
+                sqrt(0.25),
+                sqrt(0.5) - sqrt(0.25),
+                sqrt(0.75) - sqrt(0.5),
+                1.0 - sqrt(0.75),
+            ],
+        );
+        assert!((49_000..51_000).contains(&below));
+
+        assert_eq!(rand.spread(3.0, 0.0), 3.0);
+    }
+
+    #[test]
+    fn normal_distribution() {
+        let mut rand = GameRand::new(37);
+        // Standard normal CDF at -2, -1 and -0.5 standard deviations
+        let (p2, p1, p05) = (0.02275013, 0.15865525, 0.30853754);
+        let counts = bucket_counts(&[-2.0, -1.0, -0.5, 0.0, 0.5, 1.0, 2.0], 100_000, || {
+            ((rand.next_normal(5.0, 2.0) - 5.0) / 2.0) as f64
+        });
+        let probabilities = [p2, p1 - p2, p05 - p1, 0.5 - p05];
+        let mirrored: Vec<f64> = probabilities
+            .iter()
+            .chain(probabilities.iter().rev())
+            .copied()
+            .collect();
+        assert_distribution(&counts, &mirrored);
+    }
+
+    #[test]
+    fn exponential_distribution() {
+        let mut rand = GameRand::new(37);
+        let bounds = [0.1, 0.25, 0.5, 1.0, 2.0];
+        let counts = bucket_counts(&bounds, 100_000, || {
+            let val = rand.next_exponential(2.0);
+            assert!(val >= 0.0 && val.is_finite());
+            val as f64
+        });
+        let cdf = |x: f64| 1.0 - (-2.0 * x).exp();
+        let mut probabilities = vec![cdf(bounds[0])];
+        for b in bounds.windows(2) {
+            probabilities.push(cdf(b[1]) - cdf(b[0]));
+        }
+        probabilities.push(1.0 - cdf(bounds[bounds.len() - 1]));
+        assert_distribution(&counts, &probabilities);
+    }
+
+    // The octant of a point, to check directions are not biased
+    fn octant(p: vec3) -> usize {
+        (p.x >= 0.0) as usize + 2 * (p.y >= 0.0) as usize + 4 * (p.z >= 0.0) as usize
+    }
+
+    #[test]
+    fn sphere_distributions() {
+        let mut rand = GameRand::new(37);
+        let mut octants = [0u64; 8];
+        // Uniform in volume, so the cube of the radius is uniform
+        let counts = bucket_counts(&[0.2, 0.4, 0.6, 0.8], 100_000, || {
+            let p = rand.point_in_sphere();
+            octants[octant(p)] += 1;
+            let r = length(&p);
+            assert!(r <= 1.0);
+            (r * r * r) as f64
+        });
+        assert_uniform(&counts);
+        assert_uniform(&octants);
+
+        // Uniform on the surface, so the height is uniform (Archimedes)
+        let mut octants = [0u64; 8];
+        let counts = bucket_counts(&[-0.6, -0.2, 0.2, 0.6], 100_000, || {
+            let p = rand.point_on_sphere();
+            octants[octant(p)] += 1;
+            assert!((length(&p) - 1.0).abs() < 1e-5);
+            p.z as f64
+        });
+        assert_uniform(&counts);
+        assert_uniform(&octants);
+
+        let normal = normalize(&vec3(1.0, 2.0, -2.0));
+        let counts = bucket_counts(&[0.2, 0.4, 0.6, 0.8], 100_000, || {
+            let d = rand.dir_in_hemisphere(&normal);
+            assert!((length(&d) - 1.0).abs() < 1e-5);
+            dot(&d, &normal) as f64
+        });
+        assert_uniform(&counts);
+    }
+
+    #[test]
+    fn disk_distribution() {
+        let mut rand = GameRand::new(37);
+        let mut quadrants = [0u64; 4];
+        // Uniform in area, so the squared radius is uniform
+        let counts = bucket_counts(&[0.2, 0.4, 0.6, 0.8], 100_000, || {
+            let p = rand.point_in_disk();
+            quadrants[(p.x >= 0.0) as usize + 2 * (p.y >= 0.0) as usize] += 1;
+            let r_sq = length_squared(&p);
+            assert!(r_sq <= 1.0 + 1e-6);
+            r_sq as f64
+        });
+        assert_uniform(&counts);
+        assert_uniform(&quadrants);
+    }
+
+    #[test]
+    fn cone_distribution() {
+        let mut rand = GameRand::new(37);
+        let axis = normalize(&vec3(-1.0, 0.5, 3.0));
+        let angle = 0.5f32;
+        let min_cos = angle.cos() as f64;
+        // Uniform over the spherical cap, so the cosine to the axis is uniform
+        let bounds: Vec<f64> = (1..5)
+            .map(|i| min_cos + (1.0 - min_cos) * i as f64 / 5.0)
+            .collect();
+        let counts = bucket_counts(&bounds, 100_000, || {
+            let d = rand.dir_in_cone(&axis, angle);
+            assert!((length(&d) - 1.0).abs() < 1e-5);
+            let cos = dot(&d, &axis) as f64;
+            assert!(cos >= min_cos - 1e-5);
+            cos
+        });
+        assert_uniform(&counts);
+
+        // PI covers the whole sphere
+        let counts = bucket_counts(&[-0.6, -0.2, 0.2, 0.6], 100_000, || {
+            dot(&rand.dir_in_cone(&axis, std::f32::consts::PI), &axis) as f64
+        });
+        assert_uniform(&counts);
+    }
+
+    #[test]
+    fn weighted_choice_distribution() {
+        let mut rand = GameRand::new(37);
+        let weights = [1.0, 0.0, 3.0, -1.0, 4.0];
+        let mut counts = [0u64; 5];
+        for _ in 0..100_000 {
+            counts[rand.weighted_choice(&weights).unwrap()] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        assert_eq!(counts[3], 0);
+        assert_distribution(&[counts[0], counts[2], counts[4]], &[0.125, 0.375, 0.5]);
+
+        assert_eq!(rand.weighted_choice(&[]), None);
+        assert_eq!(rand.weighted_choice(&[0.0, -2.0]), None);
+        assert_eq!(rand.weighted_choice(&[0.0, 2.0, 0.0]), Some(1));
+    }
+
+    #[test]
+    fn shuffle_distribution() {
+        let mut rand = GameRand::new(37);
+        // Each of the 6 orders of 3 values equally likely
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        let mut counts = [0u64; 6];
+        for _ in 0..60_000 {
+            let mut values = [0, 1, 2];
+            rand.shuffle(&mut values);
+            counts[orders.iter().position(|o| *o == values).unwrap()] += 1;
+        }
+        assert_uniform(&counts);
+
+        // Each value equally likely to end up in each position
+        let mut counts = [0u64; 10];
+        for _ in 0..50_000 {
+            let mut values: Vec<u32> = (0..10).collect();
+            rand.shuffle(&mut values);
+            counts[values.iter().position(|&v| v == 0).unwrap()] += 1;
+            values.sort_unstable();
+            assert!(values.iter().copied().eq(0..10));
+        }
+        assert_uniform(&counts);
+
+        let mut empty: [u32; 0] = [];
+        rand.shuffle(&mut empty);
+    }
+
+    fn draw<const N: usize>(rand: &mut GameRandN<N>, count: usize) -> Vec<u32> {
+        (0..count).map(|_| rand.next_random()).collect()
+    }
+
+    #[test]
+    fn state_resumes_sequence() {
+        let mut rand = GameRand::new(38);
+        draw(&mut rand, 13); // Start part way through the seed table
+        let state = rand.get_state();
+        let first = draw(&mut rand, 100);
+
+        rand.set_state(&state);
+        assert_eq!(draw(&mut rand, 100), first);
+        assert_eq!(draw(&mut GameRand::from_state(&state), 100), first);
+
+        let mut other = GameRand::new(1);
+        other.set_state(&state);
+        assert_eq!(draw(&mut other, 100), first);
+
+        // Larger seed tables save their whole table
+        let mut rand = GameRandN::<64>::new(38);
+        draw(&mut rand, 100);
+        let state = rand.get_state();
+        let first = draw(&mut rand, 200);
+        assert_eq!(draw(&mut GameRandN::<64>::from_state(&state), 200), first);
+    }
+
+    #[test]
+    fn state_bytes_round_trip() {
+        let mut rand = GameRand::new(38);
+        draw(&mut rand, 5);
+        let state = rand.get_state();
+
+        let mut bytes = Vec::new();
+        state.write_bytes(&mut bytes);
+        assert_eq!(bytes.len(), GameRandState::BYTE_SIZE);
+        assert_eq!(GameRandState::read_bytes(&bytes), Some(state));
+
+        assert_eq!(GameRandState::read_bytes(&bytes[..bytes.len() - 1]), None);
+        let last = bytes.len() - 4;
+        bytes[last..].copy_from_slice(&8u32.to_le_bytes());
+        assert_eq!(GameRandState::read_bytes(&bytes), None);
+    }
+
+    // The number of equal values and the fraction of agreeing bits between two streams
+    fn similarity(a: &[u32], b: &[u32]) -> (usize, f64) {
+        let same = a.iter().zip(b).filter(|(a, b)| a == b).count();
+        let agree: u32 = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| 32 - (a ^ b).count_ones())
+            .sum();
+        (same, agree as f64 / (a.len() * 32) as f64)
+    }
+
+    // Unrelated streams share no values and agree on about half the bits
+    fn assert_unrelated(a: &[u32], b: &[u32]) {
+        let (same, agree) = similarity(a, b);
+        assert_eq!(same, 0);
+        assert!((agree - 0.5).abs() < 0.05, "{agree}");
+    }
+
+    #[test]
+    fn fork_is_reproducible() {
+        let mut parent = GameRand::new(38);
+        let state = parent.get_state();
+        let mut child = parent.fork();
+        let child_values = draw(&mut child, 1000);
+        let parent_values = draw(&mut parent, 1000);
+
+        // The same parent state gives the same child, and leaves the parent in the same place
+        let mut parent2 = GameRand::from_state(&state);
+        assert_eq!(draw(&mut parent2.fork(), 1000), child_values);
+        assert_eq!(draw(&mut parent2, 1000), parent_values);
+
+        // The child is unrelated to the parent, to a second fork and to splits of the same state
+        assert_unrelated(&child_values, &parent_values);
+        let mut parent3 = GameRand::from_state(&state);
+        parent3.fork();
+        assert_unrelated(&draw(&mut parent3.fork(), 1000), &child_values);
+        let parent4 = GameRand::from_state(&state);
+        for id in 0..4 {
+            assert_unrelated(&draw(&mut parent4.split(id), 1000), &child_values);
+        }
+    }
+
+    #[test]
+    fn split_is_deterministic() {
+        let mut parent = GameRand::new(38);
+        let state = parent.get_state();
+        let children: Vec<Vec<u32>> = (0..16).map(|id| draw(&mut parent.split(id), 100)).collect();
+
+        // Splitting does not advance the parent
+        assert_eq!(parent.get_state(), state);
+        let parent2 = GameRand::from_state(&state);
+        for (id, values) in children.iter().enumerate() {
+            assert_eq!(&draw(&mut parent2.split(id as u32), 100), values);
+        }
+
+        // Neighbouring ids and parent states give unrelated streams
+        for pair in children.windows(2) {
+            assert_unrelated(&pair[0], &pair[1]);
+        }
+        parent.next_random();
+        assert_unrelated(&draw(&mut parent.split(0), 100), &children[0]);
+
+        // Over all neighbouring streams, bits agree half the time to within 2%
+        let agree: f64 = children
+            .windows(2)
+            .map(|pair| similarity(&pair[0], &pair[1]).1)
+            .sum();
+        assert!((agree / 15.0 - 0.5).abs() < 0.02, "{agree}");
+    }
+
+    // The first four values after skipping 1000, for seed 12345
+    fn known_answers<const N: usize>() -> [u32; 4] {
+        let mut rand = GameRandN::<N>::new(12345);
+        draw(&mut rand, 1000);
+        [0; 4].map(|_| rand.next_random())
+    }
+
+    macro_rules! known_answer_test {
+        ($test:ident, $size:literal, $expected:expr) => {
+            #[test]
+            fn $test() {
+                assert_eq!(known_answers::<$size>(), $expected);
+            }
+        };
+    }
+
+    known_answer_test!(
+        known_answers_8,
+        8,
+        [1552575051, 1103655656, 2086584381, 1136331779]
+    );
+    known_answer_test!(
+        known_answers_16,
+        16,
+        [2212361494, 2886069441, 3616660595, 2424000187]
+    );
+    known_answer_test!(
+        known_answers_32,
+        32,
+        [1554239989, 2843316206, 3898463537, 1616005101]
+    );
+    known_answer_test!(
+        known_answers_64,
+        64,
+        [642068883, 672437513, 3567614878, 358202901]
+    );
+    known_answer_test!(
+        known_answers_128,
+        128,
+        [1161188402, 3477558019, 1358318266, 531285803]
+    );
+    known_answer_test!(
+        known_answers_256,
+        256,
+        [3775572701, 4041653020, 2458196927, 2165970884]
+    );
+    known_answer_test!(
+        known_answers_512,
+        512,
+        [955435613, 2092414279, 1505102660, 2416221122]
+    );
+    known_answer_test!(
+        known_answers_1024,
+        1024,
+        [3118686241, 2618624379, 1354126953, 3300789768]
+    );
+    known_answer_test!(
+        known_answers_4096,
+        4096,
+        [2788838288, 4020606975, 1408133571, 4276181387]
+    );
+
+    // Values from GameRand before the seed table size became a parameter, the default must not change
+    #[test]
+    fn default_matches_original() {
+        let expected: [(u32, [u32; 10]); 3] = [
+            (
+                12345,
+                [
+                    870853078, 812251510, 3461785441, 77165296, 29969263, 79489770, 3800733196,
+                    3720437869, 1833809203, 2228705421,
+                ],
+            ),
+            (
+                1,
+                [
+                    1218205676, 4208184373, 2262166687, 3267713255, 1031917732, 3705621293,
+                    2789995931, 3859105376, 1743536264, 3558166111,
+                ],
+            ),
+            (
+                0xDEADBEEF,
+                [
+                    4095646730, 4251638743, 1919017269, 754916049, 1956354141, 3760979218,
+                    4243967993, 22522753, 3928975384, 3822416001,
+                ],
+            ),
+        ];
+        for (seed, values) in expected {
+            assert_eq!(draw(&mut GameRand::new(seed), 10), values);
+        }
+        // A seed of 0 is replaced by 12345
+        assert_eq!(draw(&mut GameRand::new(0), 10), expected[0].1);
+    }
+
+    // Values from the start of the default generator with seed 12345, each group following the previous
+    #[test]
+    fn default_known_answers() {
+        let mut rand = GameRand::new(12345);
+        assert_eq!(
+            draw(&mut rand, 4),
+            [870853078, 812251510, 3461785441, 77165296]
+        );
+        let random01 = [0; 4].map(|_| rand.next_random01());
+        assert_eq!(random01, [0.006977737, 0.0185076, 0.88492715, 0.8662319]);
+        let range = [0; 4].map(|_| rand.rand_range(5, 1067));
+        assert_eq!(range, [458, 556, 671, 1020]);
+        let range_i32 = [0; 4].map(|_| rand.rand_range_i32(-1000, 1000));
+        assert_eq!(range_i32, [-24, -586, -759, -941]);
     }
 }
@@ -0,0 +1,524 @@
+use crate::vector::*;
+
+/// Axis aligned bounding box
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: vec3,
+    pub max: vec3,
+}
+
+impl Aabb {
+    /// A box containing nothing, extending it by a point gives a box around that point
+    pub const EMPTY: Aabb = Aabb {
+        min: vec3::splat(f32::MAX),
+        max: vec3::splat(-f32::MAX),
+    };
+
+    pub const fn new(min: vec3, max: vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn from_points(points: &[vec3]) -> Aabb {
+        let mut ret = Aabb::EMPTY;
+        for p in points {
+            ret.extend(p);
+        }
+        ret
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn extend(&mut self, p: &vec3) {
+        self.min = min(&self.min, p);
+        self.max = max(&self.max, p);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(min(&self.min, &other.min), max(&self.max, &other.max))
+    }
+
+    pub fn center(&self) -> vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn contains_point(&self, p: &vec3) -> bool {
+        p.x >= self.min.x
+            && p.x <= self.max.x
+            && p.y >= self.min.y
+            && p.y <= self.max.y
+            && p.z >= self.min.z
+            && p.z <= self.max.z
+    }
+
+    /// Like `contains_point`, but points on the surface of the box are outside
+    pub fn contains_point_exclusive(&self, p: &vec3) -> bool {
+        p.x > self.min.x
+            && p.x < self.max.x
+            && p.y > self.min.y
+            && p.y < self.max.y
+            && p.z > self.min.z
+            && p.z < self.max.z
+    }
+
+    pub fn closest_point(&self, p: &vec3) -> vec3 {
+        clamp(p, &self.min, &self.max)
+    }
+
+    /// Squared distance from a point to the box, 0 if the point is inside
+    pub fn distance_squared(&self, p: &vec3) -> f32 {
+        length_squared(&(self.closest_point(p) - *p))
+    }
+
+    pub fn intersects_aabb(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.distance_squared(&sphere.center) <= sphere.radius * sphere.radius
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Sphere {
+    pub center: vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub const fn new(center: vec3, radius: f32) -> Sphere {
+        Sphere { center, radius }
+    }
+
+    pub fn contains_point(&self, p: &vec3) -> bool {
+        length_squared(&(*p - self.center)) <= self.radius * self.radius
+    }
+
+    pub fn intersects_sphere(&self, other: &Sphere) -> bool {
+        let r = self.radius + other.radius;
+        length_squared(&(other.center - self.center)) <= r * r
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+/// Which side of a plane a shape is on
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum PlaneSide {
+    Front,
+    Back,
+    Intersecting,
+}
+
+/// Plane of points p where dot(normal, p) + d == 0. The normal points to the front side.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Plane {
+    pub normal: vec3,
+    pub d: f32,
+}
+
+impl Plane {
+    pub const fn new(normal: vec3, d: f32) -> Plane {
+        Plane { normal, d }
+    }
+
+    /// * `normal` - Normalized plane normal
+    pub fn from_point_normal(p: &vec3, normal: &vec3) -> Plane {
+        Plane::new(*normal, -dot(normal, p))
+    }
+
+    /// Plane through three points, the front side is where they appear counter clockwise
+    pub fn from_points(a: &vec3, b: &vec3, c: &vec3) -> Plane {
+        let normal = normalize(&cross(&(*b - *a), &(*c - *a)));
+        Plane::from_point_normal(a, &normal)
+    }
+
+    /// Scale the plane so the normal is unit length
+    pub fn normalize(&self) -> Plane {
+        let inv_len = 1.0 / length(&self.normal);
+        Plane::new(self.normal * inv_len, self.d * inv_len)
+    }
+
+    /// Signed distance to a point, positive on the front side
+    pub fn distance(&self, p: &vec3) -> f32 {
+        dot(&self.normal, p) + self.d
+    }
+
+    pub fn classify_aabb(&self, aabb: &Aabb) -> PlaneSide {
+        let center_dist = self.distance(&aabb.center());
+        let radius = dot(&abs(&self.normal), &aabb.half_extents());
+        if center_dist > radius {
+            PlaneSide::Front
+        } else if center_dist < -radius {
+            PlaneSide::Back
+        } else {
+            PlaneSide::Intersecting
+        }
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.classify_aabb(aabb) == PlaneSide::Intersecting
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: vec3,
+    pub dir: vec3,
+    inv_dir: vec3,
+}
+
+impl Ray {
+    /// * `dir` - The ray direction, does not need to be normalized. Distances along the ray are in units of its length.
+    pub fn new(origin: vec3, dir: vec3) -> Ray {
+        Ray {
+            origin,
+            dir,
+            inv_dir: vec3(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z),
+        }
+    }
+
+    pub fn at(&self, t: f32) -> vec3 {
+        self.origin + self.dir * t
+    }
+
+    /// Returns the range of the ray inside the box, clipped to start at 0
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<(f32, f32)> {
+        let mut t_min = 0.0f32;
+        let mut t_max = f32::MAX;
+        for i in 0..3 {
+            // Parallel to the slab, the infinite inverse would give NaN for a ray on its boundary
+            if self.dir[i] == 0.0 {
+                if self.origin[i] < aabb.min[i] || self.origin[i] > aabb.max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (aabb.min[i] - self.origin[i]) * self.inv_dir[i];
+            let t1 = (aabb.max[i] - self.origin[i]) * self.inv_dir[i];
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+        if t_min <= t_max {
+            Some((t_min, t_max))
+        } else {
+            None
+        }
+    }
+
+    /// Moller-Trumbore intersection, both faces are hit.
+    /// Returns the distance and the weights of the three vertices at the hit point.
+    pub fn intersect_triangle(&self, v0: &vec3, v1: &vec3, v2: &vec3) -> Option<(f32, vec3)> {
+        const EPSILON: f32 = 1e-8;
+
+        let edge1 = *v1 - *v0;
+        let edge2 = *v2 - *v0;
+        let p = cross(&self.dir, &edge2);
+        let det = dot(&edge1, &p);
+        if det.abs() < EPSILON {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let t_vec = self.origin - *v0;
+        let u = dot(&t_vec, &p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = cross(&t_vec, &edge1);
+        let v = dot(&self.dir, &q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = dot(&edge2, &q) * inv_det;
+        if t < 0.0 {
+            return None;
+        }
+        Some((t, vec3(1.0 - u - v, u, v)))
+    }
+
+    pub fn intersect_plane(&self, plane: &Plane) -> Option<f32> {
+        let denom = dot(&plane.normal, &self.dir);
+        if denom == 0.0 {
+            return None;
+        }
+        let t = -plane.distance(&self.origin) / denom;
+        if t < 0.0 {
+            return None;
+        }
+        Some(t)
+    }
+
+    /// Returns the first distance the ray is inside the sphere, 0 if it starts inside
+    pub fn intersect_sphere(&self, sphere: &Sphere) -> Option<f32> {
+        let m = self.origin - sphere.center;
+        let a = dot(&self.dir, &self.dir);
+        let b = dot(&m, &self.dir);
+        let c = dot(&m, &m) - sphere.radius * sphere.radius;
+        if c <= 0.0 {
+            return Some(0.0);
+        }
+        if b > 0.0 {
+            return None;
+        }
+        let discr = b * b - a * c;
+        if discr < 0.0 {
+            return None;
+        }
+        Some((-b - discr.sqrt()) / a)
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+/// View frustum, with plane normals pointing inside
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Extract the frustum planes from a view projection matrix to OpenGL clip space
+    pub fn from_matrix(m: &mat4) -> Frustum {
+        let c = &m.cols;
+        let row = |i: usize| vec4(c[0][i], c[1][i], c[2][i], c[3][i]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let plane = |v: vec4| Plane::new(v.xyz(), v.w).normalize();
+        Frustum {
+            planes: [
+                plane(r3 + r0), // Left
+                plane(r3 - r0), // Right
+                plane(r3 + r1), // Bottom
+                plane(r3 - r1), // Top
+                plane(r3 + r2), // Near
+                plane(r3 - r2), // Far
+            ],
+        }
+    }
+
+    pub fn contains_point(&self, p: &vec3) -> bool {
+        self.planes.iter().all(|plane| plane.distance(p) >= 0.0)
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.distance(&sphere.center) >= -sphere.radius)
+    }
+
+    /// Conservative test, may return true for boxes just outside a frustum corner
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.classify_aabb(aabb) != PlaneSide::Back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0))
+    }
+
+    // 90 degree view down -Z from the origin, near 1 and far 100
+    fn test_frustum() -> Frustum {
+        let projection = mat4::perspective(std::f32::consts::FRAC_PI_2, 1.0, 1.0, 100.0);
+        Frustum::from_matrix(&projection)
+    }
+
+    #[test]
+    fn aabb_points() {
+        let aabb = unit_box();
+        assert!(aabb.contains_point(&vec3(0.5, -0.5, 0.0)));
+        assert!(aabb.contains_point(&vec3(1.0, 0.0, 0.0)));
+        assert!(!aabb.contains_point(&vec3(1.5, 0.0, 0.0)));
+        assert!(aabb.contains_point_exclusive(&vec3(0.5, -0.5, 0.0)));
+        assert!(!aabb.contains_point_exclusive(&vec3(1.0, 0.0, 0.0)));
+
+        assert_eq!(
+            aabb.closest_point(&vec3(3.0, 0.5, -2.0)),
+            vec3(1.0, 0.5, -1.0)
+        );
+        assert_eq!(aabb.distance_squared(&vec3(3.0, 0.5, -2.0)), 5.0);
+        assert_eq!(aabb.distance_squared(&vec3(0.5, 0.5, 0.5)), 0.0);
+
+        assert!(Aabb::EMPTY.is_empty());
+        let points = [vec3(1.0, 2.0, 3.0), vec3(-1.0, 5.0, 0.0)];
+        let aabb = Aabb::from_points(&points);
+        assert_eq!(aabb, Aabb::new(vec3(-1.0, 2.0, 0.0), vec3(1.0, 5.0, 3.0)));
+        assert_eq!(aabb.center(), vec3(0.0, 3.5, 1.5));
+        assert_eq!(aabb.half_extents(), vec3(1.0, 1.5, 1.5));
+    }
+
+    #[test]
+    fn aabb_overlaps() {
+        let aabb = unit_box();
+        assert!(aabb.intersects_aabb(&Aabb::new(vec3(0.5, 0.5, 0.5), vec3(2.0, 2.0, 2.0))));
+        assert!(aabb.intersects_aabb(&Aabb::new(vec3(1.0, 0.0, 0.0), vec3(2.0, 1.0, 1.0))));
+        assert!(!aabb.intersects_aabb(&Aabb::new(vec3(1.5, 0.0, 0.0), vec3(2.0, 1.0, 1.0))));
+
+        assert!(aabb.intersects_sphere(&Sphere::new(vec3(2.0, 0.0, 0.0), 1.5)));
+        assert!(!aabb.intersects_sphere(&Sphere::new(vec3(2.0, 2.0, 0.0), 1.0)));
+        assert!(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0)
+            .intersects_sphere(&Sphere::new(vec3(1.5, 0.0, 0.0), 0.6)));
+        assert!(!Sphere::new(vec3(0.0, 0.0, 0.0), 1.0)
+            .intersects_sphere(&Sphere::new(vec3(1.5, 0.0, 0.0), 0.4)));
+    }
+
+    #[test]
+    fn plane_aabb() {
+        let plane = Plane::from_point_normal(&vec3(0.0, 2.0, 0.0), &vec3(0.0, 1.0, 0.0));
+        assert_eq!(plane.distance(&vec3(5.0, 3.0, 1.0)), 1.0);
+        assert_eq!(plane.classify_aabb(&unit_box()), PlaneSide::Back);
+        let above = Aabb::new(vec3(0.0, 2.5, 0.0), vec3(1.0, 3.0, 1.0));
+        assert_eq!(plane.classify_aabb(&above), PlaneSide::Front);
+        let across = Aabb::new(vec3(0.0, 1.5, 0.0), vec3(1.0, 3.0, 1.0));
+        assert_eq!(plane.classify_aabb(&across), PlaneSide::Intersecting);
+        assert!(plane.intersects_aabb(&across));
+        assert!(!plane.intersects_aabb(&above));
+
+        // A diagonal plane only reaching the box corner
+        let diagonal =
+            Plane::from_point_normal(&vec3(1.0, 1.0, 1.0), &normalize(&vec3(1.0, 1.0, 1.0)));
+        assert!(diagonal.intersects_aabb(&unit_box()));
+        let moved = Plane::new(diagonal.normal, diagonal.d - 0.01);
+        assert_eq!(moved.classify_aabb(&unit_box()), PlaneSide::Back);
+
+        // Counter clockwise points seen from +Z face +Z
+        let plane = Plane::from_points(
+            &vec3(0.0, 0.0, 1.0),
+            &vec3(1.0, 0.0, 1.0),
+            &vec3(0.0, 1.0, 1.0),
+        );
+        assert_eq!(plane, Plane::new(vec3(0.0, 0.0, 1.0), -1.0));
+        assert_eq!(Plane::new(vec3(0.0, 0.0, 2.0), -2.0).normalize(), plane);
+    }
+
+    #[test]
+    fn ray_aabb() {
+        let aabb = unit_box();
+        let ray = Ray::new(vec3(-3.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), Some((2.0, 4.0)));
+        // Distances are in units of the direction length
+        let ray = Ray::new(vec3(-3.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), Some((1.0, 2.0)));
+        // Starting inside clips to 0
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), Some((0.0, 1.0)));
+        // Pointing away and passing beside
+        let ray = Ray::new(vec3(-3.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), None);
+        let ray = Ray::new(vec3(-3.0, 2.0, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), None);
+        // Diagonal ray through the box
+        let ray = Ray::new(vec3(-2.0, -2.0, -2.0), vec3(1.0, 1.0, 1.0));
+        assert_eq!(ray.intersect_aabb(&aabb), Some((1.0, 3.0)));
+        // In the slab plane of a face, with a zero direction component
+        let ray = Ray::new(vec3(-3.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_aabb(&aabb), Some((2.0, 4.0)));
+    }
+
+    #[test]
+    fn ray_triangle() {
+        let v0 = vec3(0.0, 0.0, 0.0);
+        let v1 = vec3(2.0, 0.0, 0.0);
+        let v2 = vec3(0.0, 2.0, 0.0);
+
+        let ray = Ray::new(vec3(0.5, 0.5, 3.0), vec3(0.0, 0.0, -1.0));
+        let (t, weights) = ray.intersect_triangle(&v0, &v1, &v2).unwrap();
+        assert_eq!(t, 3.0);
+        assert_eq!(weights, vec3(0.5, 0.25, 0.25));
+        assert_eq!(v0 * weights.x + v1 * weights.y + v2 * weights.z, ray.at(t));
+
+        // Both faces are hit
+        let ray = Ray::new(vec3(0.5, 0.5, -3.0), vec3(0.0, 0.0, 2.0));
+        assert_eq!(ray.intersect_triangle(&v0, &v1, &v2).unwrap().0, 1.5);
+        // Outside the edges, behind the origin and parallel
+        let ray = Ray::new(vec3(1.5, 1.5, 3.0), vec3(0.0, 0.0, -1.0));
+        assert!(ray.intersect_triangle(&v0, &v1, &v2).is_none());
+        let ray = Ray::new(vec3(0.5, 0.5, 3.0), vec3(0.0, 0.0, 1.0));
+        assert!(ray.intersect_triangle(&v0, &v1, &v2).is_none());
+        let ray = Ray::new(vec3(0.5, 0.5, 3.0), vec3(1.0, 0.0, 0.0));
+        assert!(ray.intersect_triangle(&v0, &v1, &v2).is_none());
+    }
+
+    #[test]
+    fn ray_plane_sphere() {
+        let plane = Plane::new(vec3(0.0, 1.0, 0.0), 0.0);
+        let ray = Ray::new(vec3(0.0, 4.0, 0.0), vec3(0.0, -2.0, 0.0));
+        assert_eq!(ray.intersect_plane(&plane), Some(2.0));
+        let ray = Ray::new(vec3(0.0, 4.0, 0.0), vec3(0.0, 1.0, 0.0));
+        assert_eq!(ray.intersect_plane(&plane), None);
+
+        let sphere = Sphere::new(vec3(5.0, 0.0, 0.0), 1.0);
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_sphere(&sphere), Some(4.0));
+        let ray = Ray::new(vec3(5.0, 0.5, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_sphere(&sphere), Some(0.0));
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_sphere(&sphere), None);
+        let ray = Ray::new(vec3(0.0, 2.0, 0.0), vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray.intersect_sphere(&sphere), None);
+    }
+
+    #[test]
+    fn frustum_points_and_spheres() {
+        let frustum = test_frustum();
+        assert!(frustum.contains_point(&vec3(0.0, 0.0, -10.0)));
+        assert!(frustum.contains_point(&vec3(9.0, -9.0, -10.0)));
+        assert!(!frustum.contains_point(&vec3(11.0, 0.0, -10.0)));
+        assert!(!frustum.contains_point(&vec3(0.0, 0.0, 10.0)));
+        assert!(!frustum.contains_point(&vec3(0.0, 0.0, -0.5)));
+        assert!(!frustum.contains_point(&vec3(0.0, 0.0, -101.0)));
+
+        // 1 / sqrt(2) outside the right plane
+        assert!(frustum.intersects_sphere(&Sphere::new(vec3(11.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects_sphere(&Sphere::new(vec3(11.0, 0.0, -10.0), 0.5)));
+        assert!(frustum.intersects_sphere(&Sphere::new(vec3(0.0, 0.0, -101.0), 2.0)));
+        assert!(!frustum.intersects_sphere(&Sphere::new(vec3(0.0, 0.0, 5.0), 2.0)));
+    }
+
+    #[test]
+    fn frustum_aabbs() {
+        let frustum = test_frustum();
+        let around = |center: vec3| Aabb::new(center - vec3::splat(1.0), center + vec3::splat(1.0));
+        assert!(frustum.intersects_aabb(&around(vec3(0.0, 0.0, -10.0))));
+        assert!(frustum.intersects_aabb(&around(vec3(10.5, 0.0, -10.0))));
+        // Across the near plane, and just in front of it
+        assert!(frustum.intersects_aabb(&around(vec3(0.0, 0.0, -1.0))));
+        assert!(!frustum.intersects_aabb(&around(vec3(0.0, 0.0, 0.5))));
+        assert!(!frustum.intersects_aabb(&around(vec3(0.0, 0.0, 5.0))));
+        assert!(!frustum.intersects_aabb(&around(vec3(20.0, 0.0, -10.0))));
+        assert!(!frustum.intersects_aabb(&around(vec3(0.0, 0.0, -110.0))));
+
+        // A camera moved back and looking at the origin
+        let view = mat4::look_at(
+            &vec3(0.0, 0.0, 10.0),
+            &vec3(0.0, 0.0, 0.0),
+            &vec3(0.0, 1.0, 0.0),
+        );
+        let projection = mat4::perspective(std::f32::consts::FRAC_PI_2, 1.0, 1.0, 100.0);
+        let frustum = Frustum::from_matrix(&(projection * view));
+        assert!(frustum.intersects_aabb(&unit_box()));
+        assert!(!frustum.intersects_aabb(&around(vec3(0.0, 0.0, 15.0))));
+        assert!(frustum.contains_point(&vec3(0.0, 0.0, 0.0)));
+    }
+}
@@ -1,11 +1,26 @@
 //#![windows_subsystem = "windows"]
 
 mod base_app;
+#[cfg(test)]
+mod bench;
+mod bvh;
+mod color;
+mod curve;
+mod emitter;
 mod game_rand;
+mod geometry;
 mod model;
+mod noise;
+mod particle_effect;
+mod particle_manager;
 mod particle_system;
+#[cfg(test)]
+mod rand_quality;
 mod sapp;
 mod sgfx;
+#[cfg(target_arch = "x86_64")]
+mod simd;
+mod skin;
 mod timer;
 mod vector;
 
@@ -13,7 +28,10 @@ use std::ffi::c_void;
 
 use base_app::*;
 use game_rand::GameRand;
+use geometry::*;
 use model::*;
+use particle_effect::{EffectWatcher, ParticleEffect};
+use particle_manager::ParticleManager;
 use particle_system::*;
 use sapp::*;
 use sgfx::*;
@@ -24,8 +42,17 @@ const MAX_PFX_PARTICLES : u32 = 1200;
 const MAX_TOTAL_PARTICLES : u32 = MAX_PFX_PARTICLES * 5;
 const PFX_VERTEX_SIZE : u32 = (4 * 3) + (4 * 2) + (4 * 4);
 
+// Room shader attributes, in shader desc order
+const ROOM_ATTRIBUTES : [AttributeBinding; 5] = [
+    AttributeBinding { name: "position", attrib_type: AttributeType::Vertex, index: 0, size: 3 },
+    AttributeBinding { name: "uv", attrib_type: AttributeType::Texcoord, index: 0, size: 2 },
+    AttributeBinding { name: "mat0", attrib_type: AttributeType::Texcoord, index: 1, size: 3 },
+    AttributeBinding { name: "mat1", attrib_type: AttributeType::Texcoord, index: 2, size: 3 },
+    AttributeBinding { name: "mat2", attrib_type: AttributeType::Texcoord, index: 3, size: 3 },
+];
+
 struct Light {
-    particles : ParticleSystem,
+    particles : usize, // Id of the system in the particle manager
     position : vec3,
     radius : f32,
     xs :f32,
@@ -35,35 +62,16 @@ struct Light {
 
 impl Light {
 
-    fn new(position :vec3, radius : f32, xs : f32, ys : f32, zs: f32) -> Light
+    fn new(particles : &mut ParticleManager, effect : &ParticleEffect, position :vec3, radius : f32, xs : f32, ys : f32, zs: f32) -> Light
     {
-        let mut new_light = Light {
-            particles: ParticleSystem::new(),
+        Light {
+            particles: particles.add(effect.create()),
             position,
             radius,
             xs,
             ys,
             zs,
-        };
-
-        new_light.particles.spawn_rate = 400.0;
-        new_light.particles.speed = 70.0;
-        new_light.particles.speed_spread = 20.0;
-        new_light.particles.life = 3.0;
-        new_light.particles.life_spread = 0.0;
-        new_light.particles.directional_force = vec3(0.0, -10.0, 0.0);
-        new_light.particles.friction_factor = 0.95;
-        //particles.setPosition(pos);
-        new_light.particles.size = 15.0;
-        new_light.particles.size_spread = 5.0;
-    
-        for i in 0..6 {
-          let i_f = i as f32;  
-          new_light.particles.colors[i] = vec4(0.05 * i_f, 0.01 * i_f, 0.0, 0.0);
-          new_light.particles.colors[i+6] = vec4(0.05 * 6.0, 0.05 * i_f + 0.06, 0.02 * i_f, 0.0);
         }
-
-        new_light
     }
 
     fn calc_light_offset(&self, t : f32, j : f32) -> vec3 
@@ -90,28 +98,43 @@ struct Sector {
     room : Model,
     portals : Vec<Portal>,
     lights : Vec<Light>,
-    min : vec3,
-    max : vec3,
+    bounds : Aabb,
     has_been_drawn: bool,
 }
 
 impl Sector {
     fn new() -> Sector {
         Sector {
-            room: Model { batches: Vec::with_capacity(0) },
+            room: Model { batches: Vec::with_capacity(0), skeleton: None, animations: Vec::new() },
             portals: Vec::with_capacity(1),
             lights: Vec::with_capacity(1),
-            min: vec3(0.0,0.0,0.0),
-            max: vec3(0.0,0.0,0.0),
+            bounds: Aabb::new(vec3(0.0,0.0,0.0), vec3(0.0,0.0,0.0)),
             has_been_drawn: false
         }
     }
 
+    fn room_collider(&self) -> Collider {
+        Collider::new(ColliderShape::Aabb { aabb: self.bounds, inside: true })
+    }
+
+    // Add a light, keeping its particles inside the room
+    fn add_light(&mut self, light: Light, particles: &mut ParticleManager) {
+        particles.get_mut(light.particles).colliders.push(self.room_collider());
+        self.lights.push(light);
+    }
+
+    // Apply a reloaded light effect to the lights, keeping their particles inside the room
+    fn apply_light_effect(&self, effect: &ParticleEffect, particles: &mut ParticleManager) {
+        for light in &self.lights {
+            let system = particles.get_mut(light.particles);
+            effect.apply(system);
+            system.colliders.push(self.room_collider());
+        }
+    }
+
+    // Strict tests, so a point on a wall shared by two sectors is in neither
     fn is_in_bounding_box(&self, pos: &vec3) -> bool {
-        return 
-        pos.x > self.min.x && pos.x < self.max.x &&
-        pos.y > self.min.y && pos.y < self.max.y &&
-        pos.z > self.min.z && pos.z < self.max.z;
+        self.bounds.contains_point_exclusive(pos)
       }
     
       fn is_sphere_in_sector(&self, pos : &vec3, radius : f32) -> bool {
@@ -119,18 +142,7 @@ impl Sector {
       }
     
       fn get_distance_sqr(&self, pos : &vec3) -> f32 {
-        let mut d = 0.0f32;
-        for i in 0..3 {
-          if pos[i] < self.min[i] {
-            let s = pos[i] - self.min[i];
-            d += s * s;
-          }
-          else if pos[i] > self.max[i] {
-            let s = pos[i] - self.max[i];
-            d += s * s;
-          }
-        }
-        d
+        self.bounds.distance_squared(pos)
       }
 }
 
@@ -138,10 +150,13 @@ struct App {
     timer: Timer,
 
     sectors : [Sector; 5],
+    particles : ParticleManager,
+    light_effect : EffectWatcher,
   
     shader : sg_shader, 
     base : [sg_image; 3],
     bump : [sg_image; 3],
+    room_layout : sg_layout_desc,
     room_pipline : sg_pipeline,
     room_pipline_blend : sg_pipeline,
   
@@ -175,6 +190,8 @@ impl AppI for App {
     }
 
     fn load(&mut self, app: &mut BaseData, _sapp: &mut SAppData) -> bool {
+        // Keep all the particles within the size of the particle buffers
+        self.particles.max_particles = Some(MAX_TOTAL_PARTICLES as usize);
         {
             let mut indices = vec![0u16; MAX_TOTAL_PARTICLES as usize * 6];
             for i in 0..MAX_TOTAL_PARTICLES {
@@ -198,6 +215,70 @@ impl AppI for App {
               usage : sg_usage::STREAM,
               ..sg_buffer_desc::default()
             });
+
+        let rooms = [
+            ("data/room0.hmdl", vec3(0.0, 256.0, 0.0)),
+            ("data/room1.hmdl", vec3(-384.0, 256.0, 3072.0)),
+            ("data/room2.hmdl", vec3(1536.0, 256.0, 2688.0)),
+            ("data/room3.hmdl", vec3(-1024.0, -768.0, 2688.0)),
+            ("data/room4.hmdl", vec3(-2304.0, 256.0, 2688.0)),
+        ];
+        for (sector, (filename, offset)) in self.sectors.iter_mut().zip(rooms) {
+            sector.room = match Model::new(filename) {
+                Ok(model) => model,
+                Err(err) => {
+                    // Leave the sector empty and without lights
+                    println!("Failed to load {filename}: {err}");
+                    continue;
+                }
+            };
+            sector.room.translate(&offset);
+            sector.bounds = sector.room.bounds();
+
+            // All the rooms are drawn with one pipeline, so every batch has to fit the room shader
+            for batch in &sector.room.batches {
+                match batch.get_layout_desc(&ROOM_ATTRIBUTES) {
+                    Ok(layout) => self.room_layout = layout,
+                    Err(err) => {
+                        println!("Failed to bind {filename} to the room shader: {err}");
+                        return false;
+                    }
+                }
+            }
+        }
+
+        // The first poll loads the effect, draw_frame polls again to reload it when the file changes
+        let light_effect = match self.light_effect.poll() {
+            Some(Ok(effect)) => effect,
+            Some(Err(err)) => {
+                println!("Failed to load {}: {err}", self.light_effect.filename());
+                return false;
+            }
+            None => {
+                println!("Failed to load {}: file not found", self.light_effect.filename());
+                return false;
+            }
+        };
+
+        // Sector, position, radius and the size of the movement along each axis
+        let lights = [
+            (0, vec3(0.0, 128.0, 0.0), 800.0, 100.0, 100.0, 100.0),
+            (1, vec3(-256.0, 224.0, 1800.0), 650.0, 100.0, 80.0, 100.0),
+            (1, vec3(-512.0, 128.0, 3100.0), 900.0, 100.0, 100.0, 300.0),
+            (2, vec3(1300.0, 128.0, 2700.0), 800.0, 100.0, 100.0, 200.0),
+            (3, vec3(-100.0, -700.0, 2432.0), 600.0, 50.0, 50.0, 50.0),
+            (3, vec3(-1450.0, -700.0, 2900.0), 1200.0, 250.0, 80.0, 250.0),
+            (4, vec3(-2200.0, 256.0, 2300.0), 800.0, 100.0, 100.0, 100.0),
+            (4, vec3(-2000.0, 0.0, 4000.0), 800.0, 100.0, 100.0, 100.0),
+        ];
+        for (sector, position, radius, xs, ys, zs) in lights {
+            // Without its room a light has no bounds to keep its sparks in
+            if self.sectors[sector].room.batches.is_empty() {
+                continue;
+            }
+            let light = Light::new(&mut self.particles, &light_effect, position, radius, xs, ys, zs);
+            self.sectors[sector].add_light(light, &mut self.particles);
+        }
 /*        
           // create an image 
           sg_image_desc imageDesc = {
@@ -279,29 +360,9 @@ impl AppI for App {
           };
           pfx_particle = create_texture("data/Particle.png", pfx_imageDesc);
         
-          auto load_model = [](const char* filename, Sector& sector, vec3 offset) {
-            load_model_from_file(filename, sector.room);
-        
-            //mat4 mat(1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1);
-            //mat.translate(offset);
-            mat4 mat(
-              vec4(1.0, 0.0, 0.0, 0.0),
-              vec4(0.0, 1.0, 0.0, 0.0),
-              vec4(0.0, 0.0, 1.0, 0.0),
-              vec4(offset, 1.0));
-        
-            transform_model(sector.room, mat);
-        
-            // Calculate min/max bounds
-            get_bounding_box(sector.room, sector.min, sector.max);
+          for (Sector& sector : sectors) {
             make_model_renderable(sector.room);
-          };
-        
-          load_model("data/room0.hmdl", sectors[0], vec3(0, 256, 0));
-          load_model("data/room1.hmdl", sectors[1], vec3(-384, 256, 3072));
-          load_model("data/room2.hmdl", sectors[2], vec3(1536, 256, 2688));
-          load_model("data/room3.hmdl", sectors[3], vec3(-1024, -768, 2688));
-          load_model("data/room4.hmdl", sectors[4], vec3(-2304, 256, 2688));
+          }
         
           // Setup portals
           sectors[0].portals.push_back(Portal(1, vec3(-384, 384, 1024), vec3(-128, 384, 1024), vec3(-384, 0, 1024)));
@@ -319,27 +380,9 @@ impl AppI for App {
           sectors[1].portals.push_back(Portal(4, vec3(-1280, 192, 3840), vec3(-1280, 192, 4096), vec3(-1280, -256, 3840)));
           sectors[4].portals.push_back(Portal(1, vec3(-1280, 192, 3840), vec3(-1280, 192, 4096), vec3(-1280, -256, 3840)));
         
-          // Setup lights
-          sectors[0].lights.push_back(Light(vec3(0, 128, 0), 800, 100, 100, 100));
-        
-          sectors[1].lights.push_back(Light(vec3(-256, 224, 1800), 650, 100, 80, 100));
-          sectors[1].lights.push_back(Light(vec3(-512, 128, 3100), 900, 100, 100, 300));
-        
-          sectors[2].lights.push_back(Light(vec3(1300, 128, 2700), 800, 100, 100, 200));
-        
-          sectors[3].lights.push_back(Light(vec3(-100, -700, 2432), 600, 50, 50, 50));
-          sectors[3].lights.push_back(Light(vec3(-1450, -700, 2900), 1200, 250, 80, 250));
-        
-          sectors[4].lights.push_back(Light(vec3(-2200, 256, 2300), 800, 100, 100, 100));
-          sectors[4].lights.push_back(Light(vec3(-2000, 0, 4000), 800, 100, 100, 100));
-        
           {
             sg_pipeline_desc roomPipDesc = {};
-            roomPipDesc.layout.attrs[0] = { .offset = 0, .format = SG_VERTEXFORMAT_FLOAT3 }; // position
-            roomPipDesc.layout.attrs[1] = { .offset = 12, .format = SG_VERTEXFORMAT_FLOAT2 }; // uv
-            roomPipDesc.layout.attrs[2] = { .offset = 20, .format = SG_VERTEXFORMAT_FLOAT3 }; // mat0
-            roomPipDesc.layout.attrs[3] = { .offset = 32, .format = SG_VERTEXFORMAT_FLOAT3 }; // mat1
-            roomPipDesc.layout.attrs[4] = { .offset = 44, .format = SG_VERTEXFORMAT_FLOAT3 }; // mat2
+            roomPipDesc.layout = room_layout;
             roomPipDesc.shader = shader;
             roomPipDesc.index_type = SG_INDEXTYPE_UINT16;
             roomPipDesc.depth = {
@@ -401,7 +444,28 @@ impl AppI for App {
         false // DT_TODO: Use enum here
     }
 
-    fn draw_frame(&mut self, _app: &mut BaseData, _sapp: &mut SAppData) {}
+    fn draw_frame(&mut self, app: &mut BaseData, _sapp: &mut SAppData) {
+        match self.light_effect.poll() {
+            Some(Ok(effect)) => {
+                for sector in &self.sectors {
+                    sector.apply_light_effect(&effect, &mut self.particles);
+                }
+            }
+            Some(Err(err)) => println!("Failed to load {}: {err}", self.light_effect.filename()),
+            None => {}
+        }
+
+        // Move the sparks with the lights, then update and build all the particles for drawing
+        for sector in &self.sectors {
+            for (j, light) in sector.lights.iter().enumerate() {
+                let pos = light.position + light.calc_light_offset(app.app_time, j as f32);
+                self.particles.get_mut(light.particles).pos = pos;
+            }
+        }
+        self.particles.update(app.app_time);
+        let (dx, dy, _) = app.camera_axes();
+        self.particles.build_vertex_arrays(dx, dy, true, false);
+    }
 
 }
 
@@ -421,10 +485,13 @@ fn main() {
     let App = App {
         timer: Timer::new(),
         sectors : core::array::from_fn(|_| Sector::new()),
+        particles : ParticleManager::new(1235),
+        light_effect : EffectWatcher::new("data/light.effect"),
   
         shader : sg_shader::default(), 
         base : [sg_image::default(); 3],
         bump : [sg_image::default(); 3],
+        room_layout : sg_layout_desc::default(),
         room_pipline : sg_pipeline::default(),
         room_pipline_blend : sg_pipeline::default(),
       
@@ -482,18 +549,6 @@ fn main() {
     v *= 3.0;
     v /= 3.0;
 
-    /*/
-        let mut rand = GameRand::new(12345);
-
-        for _ in 0..1_000_000 {
-            println!("{}", rand.next_random());
-            println!("{}", rand.next_random01());
-            println!("{}", rand.rand_range(5, 1067));
-            break;
-        }
-    */
-    //let val = rand.rand_range(&(0u32..=3));
-
     //println!("MyEnum: {:?} {test3}", test2);
 }
 
@@ -1,6 +1,11 @@
 use std::fs::File;
 use std::io::{BufReader, Read};
 
+use crate::geometry::Aabb;
+use crate::sgfx::*;
+use crate::skin::{AnimationClip, Skeleton};
+use crate::vector::*;
+
 pub enum EnumLoadError {
     InvalidData,
 }
@@ -85,27 +90,49 @@ enum_load! {
 }
 
 enum_load! {
-    enum AttributeType {
-        Vertex   = 0,
-        Normal   = 1,
-        Texcoord = 2,
-        Color    = 3,
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AttributeType {
+        Vertex     = 0,
+        Normal     = 1,
+        Texcoord   = 2,
+        Color      = 3,
+        BoneIndex  = 4,
+        BoneWeight = 5,
     }
 }
 
 enum_load! {
-    enum AttributeFormat {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AttributeFormat {
         Float        = 0,
         UnsignedByte = 1,
     }
 }
 
 pub struct Format {
-    attrib_type: AttributeType,
-    attrib_format: AttributeFormat,
-    size: u32,
-    offset: u32,
-    index: u32,
+    pub attrib_type: AttributeType,
+    pub attrib_format: AttributeFormat,
+    pub size: u32,
+    pub offset: u32,
+    pub index: u32,
+}
+
+impl Format {
+    pub fn new(
+        attrib_type: AttributeType,
+        attrib_format: AttributeFormat,
+        size: u32,
+        offset: u32,
+        index: u32,
+    ) -> Format {
+        Format {
+            attrib_type,
+            attrib_format,
+            size,
+            offset,
+            index,
+        }
+    }
 }
 
 pub struct Batch {
@@ -126,12 +153,245 @@ pub struct Batch {
 
 pub struct Model {
     pub batches: Vec<Batch>,
+
+    /// Version 1 .hmdl files have no skeleton or animation data, so loaded models have none.
+    /// Skinned models set these from code until the file format gets a version that stores them.
+    pub skeleton: Option<Skeleton>,
+    pub animations: Vec<AnimationClip>,
+}
+
+/// Binds a shader vertex attribute to the model attribute that supplies it
+pub struct AttributeBinding<'a> {
+    pub name: &'a str,
+    pub attrib_type: AttributeType,
+    pub index: u32,
+    pub size: u32,
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    TooManyAttributes(usize),
+    MissingAttribute(String),
+    SizeMismatch(String, u32, u32),
+    UnsupportedFormat(String),
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutError::TooManyAttributes(count) => write!(
+                f,
+                "{count} shader attributes requested, max is {SG_MAX_VERTEX_ATTRIBUTES}"
+            ),
+            LayoutError::MissingAttribute(name) => {
+                write!(f, "shader attribute '{name}' is not in the model")
+            }
+            LayoutError::SizeMismatch(name, shader_size, model_size) => write!(
+                f,
+                "shader attribute '{name}' has {shader_size} components, model has {model_size}"
+            ),
+            LayoutError::UnsupportedFormat(name) => {
+                write!(f, "shader attribute '{name}' has no matching vertex format")
+            }
+        }
+    }
+}
+
+impl Batch {
+    /// Create a batch from vertex and index data already in memory
+    /// * `vertices` - Packed vertex data, `vertex_size` bytes per vertex
+    /// * `indices` - Packed index data, `index_size` (2 or 4) bytes per index. May be empty.
+    pub fn new(
+        primitive_type: PrimitiveType,
+        formats: Vec<Format>,
+        vertex_size: u32,
+        vertices: Vec<u8>,
+        index_size: u32,
+        indices: Vec<u8>,
+    ) -> Batch {
+        debug_assert!(vertices.len().is_multiple_of(vertex_size as usize));
+        debug_assert!(indices.is_empty() || index_size == 2 || index_size == 4);
+        Batch {
+            num_vertices: (vertices.len() / vertex_size as usize) as u32,
+            num_indices: if indices.is_empty() {
+                0
+            } else {
+                (indices.len() / index_size as usize) as u32
+            },
+            vertex_size,
+            index_size,
+            primitive_type,
+            formats,
+            vertices,
+            indices,
+        }
+    }
+
+    pub fn find_format(&self, attrib_type: AttributeType, index: u32) -> Option<&Format> {
+        self.formats
+            .iter()
+            .find(|f| f.attrib_type == attrib_type && f.index == index)
+    }
+
+    /// Read an attribute of a vertex. Components past the format size are returned as 0.
+    /// Unsigned byte components are returned unnormalized (0..255).
+    pub fn read_attribute(&self, format: &Format, vertex: u32) -> [f32; 4] {
+        let mut ret = [0.0; 4];
+        let start = (vertex * self.vertex_size + format.offset) as usize;
+        let count = format.size.min(4) as usize;
+        match format.attrib_format {
+            AttributeFormat::Float => {
+                for (i, val) in ret.iter_mut().take(count).enumerate() {
+                    let offset = start + i * 4;
+                    let bytes = self.vertices[offset..offset + 4].try_into().unwrap();
+                    *val = f32::from_le_bytes(bytes);
+                }
+            }
+            AttributeFormat::UnsignedByte => {
+                for (i, val) in ret.iter_mut().take(count).enumerate() {
+                    *val = self.vertices[start + i] as f32;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Get the vertex index at the given position in the index list.
+    /// For non-indexed batches this is the position itself.
+    pub fn get_index(&self, i: u32) -> u32 {
+        if self.num_indices == 0 {
+            return i;
+        }
+        let offset = (i * self.index_size) as usize;
+        if self.index_size == 2 {
+            u16::from_le_bytes(self.indices[offset..offset + 2].try_into().unwrap()) as u32
+        } else {
+            u32::from_le_bytes(self.indices[offset..offset + 4].try_into().unwrap())
+        }
+    }
+
+    /// Call a function with the vertex indices of each triangle, in draw order.
+    /// Quads and triangle strips are split into triangles, line batches have none.
+    pub fn for_each_triangle<F: FnMut(u32, u32, u32)>(&self, mut f: F) {
+        let count = if self.num_indices > 0 {
+            self.num_indices
+        } else {
+            self.num_vertices
+        };
+        let mut add = |a: u32, b: u32, c: u32| {
+            f(self.get_index(a), self.get_index(b), self.get_index(c));
+        };
+
+        match self.primitive_type {
+            PrimitiveType::Triangles => {
+                for i in (0..count / 3).map(|t| t * 3) {
+                    add(i, i + 1, i + 2);
+                }
+            }
+            PrimitiveType::Quads => {
+                for i in (0..count / 4).map(|q| q * 4) {
+                    add(i, i + 1, i + 2);
+                    add(i, i + 2, i + 3);
+                }
+            }
+            PrimitiveType::TriangleStrip => {
+                for i in 2..count {
+                    // Flip every second triangle to keep a consistent winding
+                    if i % 2 == 0 {
+                        add(i - 2, i - 1, i);
+                    } else {
+                        add(i - 1, i - 2, i);
+                    }
+                }
+            }
+            PrimitiveType::Lines => {}
+        }
+    }
+
+    /// Build the vertex layout for this batch, with one layout attribute per shader attribute
+    /// in shader attribute order. All attributes are read from vertex buffer 0.
+    /// * `bindings` - The shader attributes, in the order they are declared in the shader desc
+    pub fn get_layout_desc(
+        &self,
+        bindings: &[AttributeBinding],
+    ) -> Result<sg_layout_desc, LayoutError> {
+        if bindings.len() > SG_MAX_VERTEX_ATTRIBUTES as usize {
+            return Err(LayoutError::TooManyAttributes(bindings.len()));
+        }
+
+        let mut layout = sg_layout_desc::default();
+        layout.buffers[0].stride = self.vertex_size as i32;
+
+        for (binding, attr) in bindings.iter().zip(layout.attrs.iter_mut()) {
+            let format = self
+                .find_format(binding.attrib_type, binding.index)
+                .ok_or_else(|| LayoutError::MissingAttribute(binding.name.to_string()))?;
+
+            if format.size != binding.size {
+                return Err(LayoutError::SizeMismatch(
+                    binding.name.to_string(),
+                    binding.size,
+                    format.size,
+                ));
+            }
+
+            attr.buffer_index = 0;
+            attr.offset = format.offset as i32;
+            attr.format = match (format.attrib_format, format.size) {
+                (AttributeFormat::Float, 1) => sg_vertex_format::FLOAT,
+                (AttributeFormat::Float, 2) => sg_vertex_format::FLOAT2,
+                (AttributeFormat::Float, 3) => sg_vertex_format::FLOAT3,
+                (AttributeFormat::Float, 4) => sg_vertex_format::FLOAT4,
+                (AttributeFormat::UnsignedByte, 4) => sg_vertex_format::UBYTE4N,
+                _ => return Err(LayoutError::UnsupportedFormat(binding.name.to_string())),
+            };
+        }
+
+        Ok(layout)
+    }
 }
 
 impl Model {
     pub fn new(filename: &str) -> std::io::Result<Model> {
         load_model_from_file(filename)
     }
+
+    /// Move the vertex positions of all batches. Batches without float positions are unchanged.
+    pub fn translate(&mut self, offset: &vec3) {
+        for batch in &mut self.batches {
+            let Some(format) = batch.find_format(AttributeType::Vertex, 0) else {
+                continue;
+            };
+            if format.attrib_format != AttributeFormat::Float {
+                continue;
+            }
+            let count = format.size.min(3) as usize;
+            let start = format.offset as usize;
+            let add = [offset.x, offset.y, offset.z];
+            for vertex in batch.vertices.chunks_exact_mut(batch.vertex_size as usize) {
+                for (i, add) in add.iter().take(count).enumerate() {
+                    let bytes = &mut vertex[start + i * 4..start + i * 4 + 4];
+                    let val = f32::from_le_bytes((&*bytes).try_into().unwrap()) + add;
+                    bytes.copy_from_slice(&val.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// Get the box around the vertex positions of all batches
+    pub fn bounds(&self) -> Aabb {
+        let mut ret = Aabb::EMPTY;
+        for batch in &self.batches {
+            let Some(format) = batch.find_format(AttributeType::Vertex, 0) else {
+                continue;
+            };
+            for v in 0..batch.num_vertices {
+                let [x, y, z, _] = batch.read_attribute(format, v);
+                ret.extend(&vec3(x, y, z));
+            }
+        }
+        ret
+    }
 }
 
 fn load_model_from_file(filename: &str) -> std::io::Result<Model> {
@@ -155,6 +415,8 @@ fn load_model_from_file(filename: &str) -> std::io::Result<Model> {
 
     let mut out_model = Model {
         batches: Vec::with_capacity(num_batches as usize),
+        skeleton: None,
+        animations: Vec::new(),
     };
     for _ in 0..num_batches {
         let num_vertices = read_u32(&mut buf_reader)?;
@@ -204,3 +466,156 @@ fn load_model_from_file(filename: &str) -> std::io::Result<Model> {
 
     Ok(out_model)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM0: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data/room0.hmdl");
+
+    fn binding(
+        name: &str,
+        attrib_type: AttributeType,
+        index: u32,
+        size: u32,
+    ) -> AttributeBinding<'_> {
+        AttributeBinding {
+            name,
+            attrib_type,
+            index,
+            size,
+        }
+    }
+
+    // The attributes of the room shader, as bound in main.rs
+    fn room_attributes() -> [AttributeBinding<'static>; 5] {
+        [
+            binding("position", AttributeType::Vertex, 0, 3),
+            binding("uv", AttributeType::Texcoord, 0, 2),
+            binding("mat0", AttributeType::Texcoord, 1, 3),
+            binding("mat1", AttributeType::Texcoord, 2, 3),
+            binding("mat2", AttributeType::Texcoord, 3, 3),
+        ]
+    }
+
+    #[test]
+    fn room_layout() {
+        let model = Model::new(ROOM0).unwrap();
+        assert!(!model.batches.is_empty());
+
+        let expected = [
+            (0, sg_vertex_format::FLOAT3),
+            (12, sg_vertex_format::FLOAT2),
+            (20, sg_vertex_format::FLOAT3),
+            (32, sg_vertex_format::FLOAT3),
+            (44, sg_vertex_format::FLOAT3),
+        ];
+        for batch in &model.batches {
+            let layout = batch.get_layout_desc(&room_attributes()).unwrap();
+            assert_eq!(layout.buffers[0].stride, 56);
+            for (attr, (offset, format)) in layout.attrs.iter().zip(expected) {
+                assert_eq!(attr.buffer_index, 0);
+                assert_eq!(attr.offset, offset);
+                assert_eq!(attr.format, format);
+            }
+
+            // Attributes past the bindings are left unused
+            for attr in &layout.attrs[expected.len()..] {
+                assert_eq!(attr.format, sg_vertex_format::default());
+            }
+        }
+    }
+
+    #[test]
+    fn layout_follows_binding_order() {
+        let model = Model::new(ROOM0).unwrap();
+        let bindings = [
+            binding("uv", AttributeType::Texcoord, 0, 2),
+            binding("position", AttributeType::Vertex, 0, 3),
+        ];
+        let layout = model.batches[0].get_layout_desc(&bindings).unwrap();
+        assert_eq!(layout.attrs[0].offset, 12);
+        assert_eq!(layout.attrs[0].format, sg_vertex_format::FLOAT2);
+        assert_eq!(layout.attrs[1].offset, 0);
+        assert_eq!(layout.attrs[1].format, sg_vertex_format::FLOAT3);
+    }
+
+    #[test]
+    fn layout_errors() {
+        let model = Model::new(ROOM0).unwrap();
+        let batch = &model.batches[0];
+
+        let bindings = [
+            binding("position", AttributeType::Vertex, 0, 3),
+            binding("normal", AttributeType::Normal, 0, 3),
+        ];
+        match batch.get_layout_desc(&bindings) {
+            Err(LayoutError::MissingAttribute(name)) => assert_eq!(name, "normal"),
+            _ => panic!("expected a missing attribute"),
+        }
+
+        let bindings = [binding("uv", AttributeType::Texcoord, 0, 3)];
+        match batch.get_layout_desc(&bindings) {
+            Err(LayoutError::SizeMismatch(name, shader_size, model_size)) => {
+                assert_eq!((name.as_str(), shader_size, model_size), ("uv", 3, 2));
+            }
+            _ => panic!("expected a size mismatch"),
+        }
+
+        let count = SG_MAX_VERTEX_ATTRIBUTES as usize;
+        let bindings: Vec<_> = (0..count + 1)
+            .map(|_| binding("position", AttributeType::Vertex, 0, 3))
+            .collect();
+        match batch.get_layout_desc(&bindings) {
+            Err(LayoutError::TooManyAttributes(n)) => assert_eq!(n, count + 1),
+            _ => panic!("expected too many attributes"),
+        }
+        assert!(batch.get_layout_desc(&bindings[..count]).is_ok());
+    }
+
+    #[test]
+    fn layout_formats() {
+        let formats = vec![
+            Format::new(AttributeType::Vertex, AttributeFormat::Float, 1, 0, 0),
+            Format::new(AttributeType::Color, AttributeFormat::UnsignedByte, 4, 4, 0),
+            Format::new(AttributeType::Color, AttributeFormat::UnsignedByte, 3, 8, 1),
+        ];
+        let batch = Batch::new(
+            PrimitiveType::Triangles,
+            formats,
+            12,
+            vec![0; 36],
+            0,
+            Vec::new(),
+        );
+
+        let bindings = [
+            binding("x", AttributeType::Vertex, 0, 1),
+            binding("color", AttributeType::Color, 0, 4),
+        ];
+        let layout = batch.get_layout_desc(&bindings).unwrap();
+        assert_eq!(layout.buffers[0].stride, 12);
+        assert_eq!(layout.attrs[0].format, sg_vertex_format::FLOAT);
+        assert_eq!(layout.attrs[1].format, sg_vertex_format::UBYTE4N);
+        assert_eq!(layout.attrs[1].offset, 4);
+
+        let bindings = [binding("rgb", AttributeType::Color, 1, 3)];
+        match batch.get_layout_desc(&bindings) {
+            Err(LayoutError::UnsupportedFormat(name)) => assert_eq!(name, "rgb"),
+            _ => panic!("expected an unsupported format"),
+        }
+    }
+
+    #[test]
+    fn translate_moves_bounds() {
+        let mut model = Model::new(ROOM0).unwrap();
+        let bounds = model.bounds();
+        assert!(!bounds.is_empty());
+
+        let offset = vec3(-384.0, 256.0, 3072.0);
+        model.translate(&offset);
+        let moved = model.bounds();
+        assert_eq!(moved.min, bounds.min + offset);
+        assert_eq!(moved.max, bounds.max + offset);
+    }
+}
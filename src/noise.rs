@@ -0,0 +1,519 @@
+// Coherent noise: Perlin gradient noise and simplex noise in 2, 3 and 4 dimensions, fractal sums and curl noise.
+// Based on Ken Perlin's improved noise (2002) and Stefan Gustavson's "Simplex noise demystified" (2005).
+
+use crate::game_rand::GameRand;
+use crate::vector::*;
+
+// Gradients for 2D, the x and y of the 3D gradients
+const GRAD2: [vec2; 12] = [
+    vec2(1.0, 1.0),
+    vec2(-1.0, 1.0),
+    vec2(1.0, -1.0),
+    vec2(-1.0, -1.0),
+    vec2(1.0, 0.0),
+    vec2(-1.0, 0.0),
+    vec2(1.0, 0.0),
+    vec2(-1.0, 0.0),
+    vec2(0.0, 1.0),
+    vec2(0.0, -1.0),
+    vec2(0.0, 1.0),
+    vec2(0.0, -1.0),
+];
+
+// Scales bringing the noise functions to approximately the -1 to 1 range, measured by sampling.
+// 3D and 4D Perlin noise peak a few percent past 1 at rare points, so they are clamped to stay in range.
+// The simplex kernels use a radius of 0.5 in all dimensions, as larger radii are not continuous in 3D and 4D.
+const PERLIN4_SCALE: f32 = 0.85;
+const SIMPLEX2_SCALE: f32 = 70.0;
+const SIMPLEX3_SCALE: f32 = 76.0;
+const SIMPLEX4_SCALE: f32 = 62.0;
+
+// Step used for the derivatives of curl noise
+const CURL_EPSILON: f32 = 1e-3;
+
+/// Seeded noise generator, the same seed always gives the same noise
+pub struct Noise {
+    // The permutation of 0-255 repeated twice, to avoid wrapping indices
+    perm: [u8; 512],
+}
+
+impl Noise {
+    /// Create a noise generator with a permutation table shuffled from a seed
+    /// * `seed` - The seed value to use
+    pub fn new(seed: u32) -> Noise {
+        Noise::from_rand(&mut GameRand::new(seed))
+    }
+
+    /// Create a noise generator with a permutation table shuffled by a random number generator
+    pub fn from_rand(rand: &mut GameRand) -> Noise {
+        let mut values: [u8; 256] = core::array::from_fn(|i| i as u8);
+        rand.shuffle(&mut values);
+
+        let mut perm = [0; 512];
+        perm[..256].copy_from_slice(&values);
+        perm[256..].copy_from_slice(&values);
+        Noise { perm }
+    }
+
+    fn hash2(&self, x: i32, y: i32) -> usize {
+        let p = &self.perm;
+        p[p[(x & 255) as usize] as usize + (y & 255) as usize] as usize
+    }
+
+    fn hash3(&self, x: i32, y: i32, z: i32) -> usize {
+        self.perm[self.hash2(x, y) + (z & 255) as usize] as usize
+    }
+
+    fn hash4(&self, x: i32, y: i32, z: i32, w: i32) -> usize {
+        self.perm[self.hash3(x, y, z) + (w & 255) as usize] as usize
+    }
+
+    /// 2D Perlin gradient noise, approximately in the -1 to 1 range and 0 at integer coordinates
+    pub fn perlin2(&self, p: &vec2) -> f32 {
+        let (xf, yf) = (p.x.floor(), p.y.floor());
+        let (xi, yi) = (xf as i32, yf as i32);
+        let (x, y) = (p.x - xf, p.y - yf);
+
+        let grad = |i: i32, j: i32| {
+            let g = GRAD2[self.hash2(xi + i, yi + j) % 12];
+            g.x * (x - i as f32) + g.y * (y - j as f32)
+        };
+
+        let (u, v) = (fade(x), fade(y));
+        let n0 = lerp_f32(grad(0, 0), grad(1, 0), u);
+        let n1 = lerp_f32(grad(0, 1), grad(1, 1), u);
+        lerp_f32(n0, n1, v)
+    }
+
+    /// 3D Perlin gradient noise, in the -1 to 1 range and 0 at integer coordinates
+    pub fn perlin3(&self, p: &vec3) -> f32 {
+        let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (xi, yi, zi) = (xf as i32, yf as i32, zf as i32);
+        let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+
+        let grad = |i: i32, j: i32, k: i32| {
+            let h = self.hash3(xi + i, yi + j, zi + k);
+            grad3(h, x - i as f32, y - j as f32, z - k as f32)
+        };
+
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let lerp_x = |j: i32, k: i32| lerp_f32(grad(0, j, k), grad(1, j, k), u);
+        let n0 = lerp_f32(lerp_x(0, 0), lerp_x(1, 0), v);
+        let n1 = lerp_f32(lerp_x(0, 1), lerp_x(1, 1), v);
+        lerp_f32(n0, n1, w).clamp(-1.0, 1.0)
+    }
+
+    /// 4D Perlin gradient noise, in the -1 to 1 range and 0 at integer coordinates.
+    /// Animate 3D noise smoothly by using time as the fourth coordinate.
+    pub fn perlin4(&self, p: &vec4) -> f32 {
+        let (xf, yf, zf, wf) = (p.x.floor(), p.y.floor(), p.z.floor(), p.w.floor());
+        let (xi, yi, zi, wi) = (xf as i32, yf as i32, zf as i32, wf as i32);
+        let (x, y, z, w) = (p.x - xf, p.y - yf, p.z - zf, p.w - wf);
+
+        let grad = |i: i32, j: i32, k: i32, l: i32| {
+            let h = self.hash4(xi + i, yi + j, zi + k, wi + l);
+            grad4(h, x - i as f32, y - j as f32, z - k as f32, w - l as f32)
+        };
+
+        let (fx, fy, fz, fw) = (fade(x), fade(y), fade(z), fade(w));
+        let lerp_x = |j: i32, k: i32, l: i32| lerp_f32(grad(0, j, k, l), grad(1, j, k, l), fx);
+        let lerp_y = |k: i32, l: i32| lerp_f32(lerp_x(0, k, l), lerp_x(1, k, l), fy);
+        let lerp_z = |l: i32| lerp_f32(lerp_y(0, l), lerp_y(1, l), fz);
+        (PERLIN4_SCALE * lerp_f32(lerp_z(0), lerp_z(1), fw)).clamp(-1.0, 1.0)
+    }
+
+    /// 2D simplex noise, approximately in the -1 to 1 range. Cheaper than Perlin noise with fewer grid artifacts.
+    pub fn simplex2(&self, p: &vec2) -> f32 {
+        const F2: f32 = 0.36602542; // (sqrt(3) - 1) / 2
+        const G2: f32 = 0.21132487; // (3 - sqrt(3)) / 6
+
+        // Skew to find the simplex cell, then unskew the cell origin back
+        let s = (p.x + p.y) * F2;
+        let (i, j) = ((p.x + s).floor(), (p.y + s).floor());
+        let t = (i + j) * G2;
+        let p0 = vec2(p.x - (i - t), p.y - (j - t));
+        let (i, j) = (i as i32, j as i32);
+
+        // The middle corner of the triangle
+        let (i1, j1) = if p0.x > p0.y { (1, 0) } else { (0, 1) };
+
+        let p1 = p0 - vec2(i1 as f32, j1 as f32) + vec2::splat(G2);
+        let p2 = p0 - vec2::splat(1.0 - 2.0 * G2);
+
+        let corner = |d: &vec2, hi: i32, hj: i32| {
+            let t = 0.5 - dot(d, d);
+            if t < 0.0 {
+                return 0.0;
+            }
+            let g = GRAD2[self.hash2(i + hi, j + hj) % 12];
+            t * t * t * t * dot(&g, d)
+        };
+        SIMPLEX2_SCALE * (corner(&p0, 0, 0) + corner(&p1, i1, j1) + corner(&p2, 1, 1))
+    }
+
+    /// 3D simplex noise, approximately in the -1 to 1 range
+    pub fn simplex3(&self, p: &vec3) -> f32 {
+        const F3: f32 = 1.0 / 3.0;
+        const G3: f32 = 1.0 / 6.0;
+
+        let s = (p.x + p.y + p.z) * F3;
+        let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+        let t = (i + j + k) * G3;
+        let p0 = vec3(p.x - (i - t), p.y - (j - t), p.z - (k - t));
+        let (i, j, k) = (i as i32, j as i32, k as i32);
+
+        // The second and third corners of the tetrahedron, from the order of the coordinates
+        let (c1, c2) = if p0.x >= p0.y {
+            if p0.y >= p0.z {
+                ([1, 0, 0], [1, 1, 0])
+            } else if p0.x >= p0.z {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if p0.y < p0.z {
+            ([0, 0, 1], [0, 1, 1])
+        } else if p0.x < p0.z {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+
+        let offset = |c: [i32; 3]| vec3(c[0] as f32, c[1] as f32, c[2] as f32);
+        let p1 = p0 - offset(c1) + vec3::splat(G3);
+        let p2 = p0 - offset(c2) + vec3::splat(2.0 * G3);
+        let p3 = p0 - vec3::splat(1.0 - 3.0 * G3);
+
+        let corner = |d: &vec3, c: [i32; 3]| {
+            let t = 0.5 - dot(d, d);
+            if t < 0.0 {
+                return 0.0;
+            }
+            let h = self.hash3(i + c[0], j + c[1], k + c[2]);
+            t * t * t * t * grad3(h, d.x, d.y, d.z)
+        };
+        SIMPLEX3_SCALE
+            * (corner(&p0, [0, 0, 0]) + corner(&p1, c1) + corner(&p2, c2) + corner(&p3, [1, 1, 1]))
+    }
+
+    /// 4D simplex noise, approximately in the -1 to 1 range
+    pub fn simplex4(&self, p: &vec4) -> f32 {
+        const F4: f32 = 0.309017; // (sqrt(5) - 1) / 4
+        const G4: f32 = 0.1381966; // (5 - sqrt(5)) / 20
+
+        let s = (p.x + p.y + p.z + p.w) * F4;
+        let cell = vec4(
+            (p.x + s).floor(),
+            (p.y + s).floor(),
+            (p.z + s).floor(),
+            (p.w + s).floor(),
+        );
+        let t = (cell.x + cell.y + cell.z + cell.w) * G4;
+        let p0 = *p - (cell - vec4::splat(t));
+        let (i, j, k, l) = (cell.x as i32, cell.y as i32, cell.z as i32, cell.w as i32);
+
+        // Rank the coordinates by size, the corners step along the axes from the largest to the smallest
+        let mut rank = [0; 4];
+        for a in 0..4 {
+            for b in (a + 1)..4 {
+                if p0[a] > p0[b] {
+                    rank[a] += 1;
+                } else {
+                    rank[b] += 1;
+                }
+            }
+        }
+        let corner_offset = |n: i32| rank.map(|r| (r >= n) as i32);
+
+        let corner = |n: i32| {
+            let c = if n == 0 { [0; 4] } else { corner_offset(4 - n) };
+            let d = p0 - vec4(c[0] as f32, c[1] as f32, c[2] as f32, c[3] as f32)
+                + vec4::splat(n as f32 * G4);
+            let t = 0.5 - dot(&d, &d);
+            if t < 0.0 {
+                return 0.0;
+            }
+            let h = self.hash4(i + c[0], j + c[1], k + c[2], l + c[3]);
+            t * t * t * t * grad4(h, d.x, d.y, d.z, d.w)
+        };
+        SIMPLEX4_SCALE * (0..5).map(corner).sum::<f32>()
+    }
+
+    /// 2D curl noise, a divergence free flow field made from the rotated gradient of simplex noise
+    pub fn curl2(&self, p: &vec2) -> vec2 {
+        let dx = vec2(CURL_EPSILON, 0.0);
+        let dy = vec2(0.0, CURL_EPSILON);
+        let scale = 1.0 / (2.0 * CURL_EPSILON);
+        let ddx = (self.simplex2(&(*p + dx)) - self.simplex2(&(*p - dx))) * scale;
+        let ddy = (self.simplex2(&(*p + dy)) - self.simplex2(&(*p - dy))) * scale;
+        vec2(ddy, -ddx)
+    }
+
+    /// 3D curl noise, a divergence free flow field for turbulence that does not bunch particles together.
+    /// The curl of a vector potential made of three offset simplex noise fields.
+    pub fn curl3(&self, p: &vec3) -> vec3 {
+        // Offsets decorrelating the three potential components
+        const OFFSET1: vec3 = vec3(31.416, -47.853, 12.793);
+        const OFFSET2: vec3 = vec3(-71.137, 19.081, 53.419);
+
+        let potential = |q: vec3| {
+            vec3(
+                self.simplex3(&q),
+                self.simplex3(&(q + OFFSET1)),
+                self.simplex3(&(q + OFFSET2)),
+            )
+        };
+        let derivative = |axis: vec3| {
+            (potential(*p + axis) - potential(*p - axis)) * (1.0 / (2.0 * CURL_EPSILON))
+        };
+        let ddx = derivative(vec3(CURL_EPSILON, 0.0, 0.0));
+        let ddy = derivative(vec3(0.0, CURL_EPSILON, 0.0));
+        let ddz = derivative(vec3(0.0, 0.0, CURL_EPSILON));
+        vec3(ddy.z - ddz.y, ddz.x - ddx.z, ddx.y - ddy.x)
+    }
+}
+
+/// Fractal Brownian motion, a sum of octaves of noise at increasing frequency and decreasing amplitude.
+/// The result is divided by the total amplitude, keeping the range of the noise function.
+/// * `p` - The sample position
+/// * `octaves` - The number of noise layers
+/// * `lacunarity` - The frequency multiplier of each octave, usually 2
+/// * `gain` - The amplitude multiplier of each octave, usually 0.5
+/// * `noise` - The noise function, e.g. `|p| noise.simplex3(p)`
+pub fn fbm<T: Vector, F: Fn(&T) -> f32>(
+    p: &T,
+    octaves: u32,
+    lacunarity: f32,
+    gain: f32,
+    noise: F,
+) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * noise(&(*p * frequency));
+        total_amplitude += amplitude;
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+    if total_amplitude > 0.0 {
+        sum / total_amplitude
+    } else {
+        0.0
+    }
+}
+
+// Perlin's fade curve 6t^5 - 15t^4 + 10t^3, with zero first and second derivatives at 0 and 1
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp_f32(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// One of the 12 gradients to the edges of a cube, as in Perlin's improved noise
+fn grad3(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// One of the 32 gradients to the edges of a 4D hypercube
+fn grad4(hash: usize, x: f32, y: f32, z: f32, w: f32) -> f32 {
+    let h = hash & 31;
+    let a = if h < 24 { x } else { y };
+    let b = if h < 16 { y } else { z };
+    let c = if h < 8 { z } else { w };
+    (if h & 1 == 0 { a } else { -a })
+        + (if h & 2 == 0 { b } else { -b })
+        + (if h & 4 == 0 { c } else { -c })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample spacing, not a multiple of the lattice spacing, so samples fall between lattice points
+    const GRID_STEP: f32 = 0.137;
+    const GRID_SIZE: i32 = 24;
+    // Distance between neighbouring samples in the continuity tests
+    const DELTA: f32 = 1e-3;
+    // Largest allowed change between neighbouring samples, a bound on the slope of the noise
+    const MAX_SLOPE: f32 = 10.0;
+
+    fn grid2() -> impl Iterator<Item = vec2> {
+        (0..GRID_SIZE * GRID_SIZE).map(|i| {
+            let (x, y) = (i % GRID_SIZE, i / GRID_SIZE);
+            vec2(x as f32, y as f32) * GRID_STEP + vec2(-1.3, 0.7)
+        })
+    }
+
+    fn grid3() -> impl Iterator<Item = vec3> {
+        grid2()
+            .flat_map(|p| (0..GRID_SIZE).map(move |z| vec3(p.x, p.y, z as f32 * GRID_STEP - 2.1)))
+    }
+
+    fn grid4() -> impl Iterator<Item = vec4> {
+        grid3().flat_map(|p| (0..8).map(move |w| vec4(p.x, p.y, p.z, w as f32 * 0.41 + 5.3)))
+    }
+
+    // Checks the noise stays in the -1 to 1 range at random points and reaches at least `min_peak`.
+    // Values at the clamp limits have to be rare, or the scale is too large.
+    fn assert_in_range(min_peak: f32, noise: impl Fn(&vec4) -> f32) {
+        const SAMPLES: usize = 200_000;
+        let mut rand = GameRand::new(41);
+        let mut max_abs = 0.0f32;
+        let mut clamped = 0;
+        for _ in 0..SAMPLES {
+            let mut coord = || rand.rand_range_f32(-100.0, 100.0);
+            let p = vec4(coord(), coord(), coord(), coord());
+            let v = noise(&p);
+            assert!((-1.0..=1.0).contains(&v), "{v} out of range at {p:?}");
+            max_abs = max_abs.max(v.abs());
+            clamped += (v.abs() == 1.0) as usize;
+        }
+        assert!(max_abs > min_peak, "largest value {max_abs}");
+        assert!(clamped < SAMPLES / 10_000, "{clamped} values clamped");
+    }
+
+    // Checks the noise changes by a small amount between samples a small distance apart
+    fn assert_continuous<T: Vector>(
+        points: impl Iterator<Item = T>,
+        offset: T,
+        noise: impl Fn(&T) -> f32,
+    ) {
+        for p in points {
+            let delta = (noise(&(p + offset)) - noise(&p)).abs();
+            assert!(delta < MAX_SLOPE * DELTA, "delta {delta}");
+        }
+    }
+
+    #[test]
+    fn noise_2d() {
+        let noise = Noise::new(41);
+        assert_in_range(0.75, |p| noise.perlin2(&p.xy()));
+        assert_in_range(0.75, |p| noise.simplex2(&p.xy()));
+        for offset in [vec2(DELTA, 0.0), vec2(0.0, DELTA)] {
+            assert_continuous(grid2(), offset, |p| noise.perlin2(p));
+            assert_continuous(grid2(), offset, |p| noise.simplex2(p));
+        }
+    }
+
+    #[test]
+    fn noise_3d() {
+        let noise = Noise::new(41);
+        assert_in_range(0.75, |p| noise.perlin3(&p.xyz()));
+        assert_in_range(0.75, |p| noise.simplex3(&p.xyz()));
+        for offset in [
+            vec3(DELTA, 0.0, 0.0),
+            vec3(0.0, DELTA, 0.0),
+            vec3(0.0, 0.0, DELTA),
+        ] {
+            assert_continuous(grid3(), offset, |p| noise.perlin3(p));
+            assert_continuous(grid3(), offset, |p| noise.simplex3(p));
+        }
+    }
+
+    #[test]
+    fn noise_4d() {
+        let noise = Noise::new(41);
+        assert_in_range(0.75, |p| noise.perlin4(p));
+        assert_in_range(0.75, |p| noise.simplex4(p));
+        let offsets = [
+            vec4(DELTA, 0.0, 0.0, 0.0),
+            vec4(0.0, DELTA, 0.0, 0.0),
+            vec4(0.0, 0.0, DELTA, 0.0),
+            vec4(0.0, 0.0, 0.0, DELTA),
+        ];
+        for offset in offsets {
+            assert_continuous(grid4(), offset, |p| noise.perlin4(p));
+            assert_continuous(grid4(), offset, |p| noise.simplex4(p));
+        }
+    }
+
+    #[test]
+    fn perlin_is_zero_at_integers() {
+        let noise = Noise::new(41);
+        for i in -3..3 {
+            let f = i as f32;
+            assert_eq!(noise.perlin2(&vec2(f, 2.0)), 0.0);
+            assert_eq!(noise.perlin3(&vec3(1.0, f, -5.0)), 0.0);
+            assert_eq!(noise.perlin4(&vec4(f, 7.0, f, -1.0)), 0.0);
+        }
+    }
+
+    #[test]
+    fn seeds() {
+        let p = vec3(0.3, 1.7, -2.2);
+        assert_eq!(Noise::new(7).simplex3(&p), Noise::new(7).simplex3(&p));
+        assert_eq!(
+            Noise::new(7).simplex3(&p),
+            Noise::from_rand(&mut GameRand::new(7)).simplex3(&p)
+        );
+        assert_ne!(Noise::new(7).simplex3(&p), Noise::new(8).simplex3(&p));
+    }
+
+    #[test]
+    fn fbm_octaves() {
+        let noise = Noise::new(41);
+        let simplex = |p: &vec3| noise.simplex3(p);
+        let p = vec3(0.3, 1.7, -2.2);
+        assert_eq!(fbm(&p, 0, 2.0, 0.5, simplex), 0.0);
+        assert_eq!(fbm(&p, 1, 2.0, 0.5, simplex), noise.simplex3(&p));
+
+        // Two octaves weighted 1 and 0.5, divided by the total amplitude
+        let two = (noise.simplex3(&p) + 0.5 * noise.simplex3(&(p * 2.0))) / 1.5;
+        assert!((fbm(&p, 2, 2.0, 0.5, simplex) - two).abs() < 1e-6);
+
+        // Octaves partly cancel out, so fBm peaks lower than the noise it sums
+        assert_in_range(0.5, |p| fbm(&p.xyz(), 5, 2.0, 0.5, simplex));
+        assert_in_range(0.5, |p| {
+            fbm(&p.xy(), 5, 2.0, 0.5, |q: &vec2| noise.perlin2(q))
+        });
+    }
+
+    // The divergence of a curl field is zero, checked with central differences much larger than CURL_EPSILON
+    #[test]
+    fn curl_is_divergence_free() {
+        let noise = Noise::new(41);
+        let h = 0.01;
+        let scale = 1.0 / (2.0 * h);
+        let mut max_divergence = 0.0f32;
+        let mut max_derivative = 0.0f32;
+        for p in grid2() {
+            let ddx = (noise.curl2(&(p + vec2(h, 0.0))) - noise.curl2(&(p - vec2(h, 0.0)))) * scale;
+            let ddy = (noise.curl2(&(p + vec2(0.0, h))) - noise.curl2(&(p - vec2(0.0, h)))) * scale;
+            max_divergence = max_divergence.max((ddx.x + ddy.y).abs());
+            max_derivative = max_derivative.max(ddx.x.abs()).max(ddy.y.abs());
+        }
+        for p in grid3().step_by(7) {
+            let ddx = (noise.curl3(&(p + vec3(h, 0.0, 0.0)))
+                - noise.curl3(&(p - vec3(h, 0.0, 0.0))))
+                * scale;
+            let ddy = (noise.curl3(&(p + vec3(0.0, h, 0.0)))
+                - noise.curl3(&(p - vec3(0.0, h, 0.0))))
+                * scale;
+            let ddz = (noise.curl3(&(p + vec3(0.0, 0.0, h)))
+                - noise.curl3(&(p - vec3(0.0, 0.0, h))))
+                * scale;
+            max_divergence = max_divergence.max((ddx.x + ddy.y + ddz.z).abs());
+            max_derivative = max_derivative
+                .max(ddx.x.abs())
+                .max(ddy.y.abs())
+                .max(ddz.z.abs());
+        }
+        assert!(
+            max_divergence < 0.05 * max_derivative,
+            "divergence {max_divergence}, derivative {max_derivative}"
+        );
+    }
+}
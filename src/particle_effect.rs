@@ -0,0 +1,997 @@
+// Particle effect files describe the parameters of a ParticleSystem as text, one `name = value` per line.
+// Comments start with #. Numbers are separated by spaces, so a vec3 is `0 1 0`.
+// Numbers must be finite. Directions, axes and normals are normalized and must not be zero.
+// Parameters not in the file keep their ParticleSystem::new values. The position is set by code.
+//
+//   spawn_rate = 400
+//   directional_force = 0 -10 0
+//   emitter = sphere 10 surface
+//   color_over_life = fire
+//   size_over_life = smooth 0: 1, 1: 3
+//
+// Parameters with variants:
+//   emitter          point, sphere <radius> [surface], hemisphere <radius> [surface], box <half extents>,
+//                    disk <radius>, cone <radius> <angle>, line <start> <end>
+//   spread_shape     square, cone
+//   emit_direction   fixed, normal
+//   orientation      camera_facing, velocity_stretched <stretch>, axis_locked <axis>, world_aligned <normal>
+//   sort             none, back_to_front
+//   max_particles    none, <count>
+//   overflow_policy  stop_spawning, kill_oldest, reduce_spawn_rate
+//
+// Curves are an optional interpolation (step, linear or smooth, default linear) followed by comma separated
+// `time: value` keys. color_over_life takes vec4 values, or one of the presets fire, ice, smoke or rainbow.
+//
+// A [point_force] or [collider] line starts a new force or collider and the lines after it set its values,
+// so the system parameters go before the first one:
+//
+//   [point_force]
+//   pos = 0 100 0
+//   strength = 50
+//   linear_attenuation = 0.01
+//   quadratic_attenuation = 0
+//
+//   [collider]
+//   shape = box -100 0 -100 100 200 100 inside
+//   restitution = 0.5
+//   friction = 0.5
+//   kill_on_contact = true
+//
+// Collider shapes are plane <normal> <d>, box <min> <max> [inside] and sphere <center> <radius> [inside].
+
+use crate::color::Gradient;
+use crate::curve::{Curve, Interpolation, Keyframe};
+use crate::emitter::{EmitDirection, EmitterShape, SpreadShape};
+use crate::geometry::{Aabb, Plane, Sphere};
+use crate::particle_system::*;
+use crate::vector::*;
+
+use std::str::SplitWhitespace;
+use std::time::SystemTime;
+
+#[derive(Debug)]
+pub enum EffectError {
+    Io(std::io::Error),
+    /// The line number, starting at 1, and what is wrong with it
+    Parse(usize, String),
+}
+
+impl std::fmt::Display for EffectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EffectError::Io(err) => write!(f, "{err}"),
+            EffectError::Parse(line, message) => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for EffectError {
+    fn from(err: std::io::Error) -> EffectError {
+        EffectError::Io(err)
+    }
+}
+
+/// Particle system parameters loaded from an effect file
+pub struct ParticleEffect {
+    // Holds the parameters, never updated
+    params: ParticleSystem,
+}
+
+impl ParticleEffect {
+    pub fn load(filename: &str) -> Result<ParticleEffect, EffectError> {
+        ParticleEffect::parse(&std::fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(text: &str) -> Result<ParticleEffect, EffectError> {
+        let mut params = ParticleSystem::new();
+        let mut section = Section::Main;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let result = if line.starts_with('[') {
+                finish_section(&mut params, std::mem::replace(&mut section, Section::Main))
+                    .and_then(|_| new_section(line))
+                    .map(|s| section = s)
+            } else if let Some((key, value)) = line.split_once('=') {
+                parse_value(&mut params, &mut section, key.trim(), value.trim())
+            } else {
+                Err(format!("expected 'name = value', found '{line}'"))
+            };
+            result.map_err(|message| EffectError::Parse(line_number, message))?;
+        }
+
+        finish_section(&mut params, section)
+            .map_err(|message| EffectError::Parse(text.lines().count(), message))?;
+        Ok(ParticleEffect { params })
+    }
+
+    /// Create a particle system with the parameters of the effect
+    pub fn create(&self) -> ParticleSystem {
+        let mut ret = ParticleSystem::new();
+        self.apply(&mut ret);
+        ret
+    }
+
+    /// Set all the parameters of a system to the effect, including its forces and colliders.
+    /// The position and the live particles are kept.
+    pub fn apply(&self, system: &mut ParticleSystem) {
+        let p = &self.params;
+        system.spawn_rate = p.spawn_rate;
+        system.speed = p.speed;
+        system.speed_spread = p.speed_spread;
+        system.size = p.size;
+        system.size_spread = p.size_spread;
+        system.life = p.life;
+        system.life_spread = p.life_spread;
+        system.friction_factor = p.friction_factor;
+        system.rotation = p.rotation;
+        system.rotation_spread = p.rotation_spread;
+        system.angular_velocity = p.angular_velocity;
+        system.angular_velocity_spread = p.angular_velocity_spread;
+        system.orientation = p.orientation;
+        system.sort = p.sort;
+        system.max_particles = p.max_particles;
+        system.overflow_policy = p.overflow_policy;
+
+        system.emitter = p.emitter.clone();
+        system.direction = p.direction;
+        system.spread = p.spread;
+        system.spread_shape = p.spread_shape;
+        system.emit_direction = p.emit_direction;
+
+        system.color_over_life = p.color_over_life.clone();
+        system.alpha_over_life = p.alpha_over_life.clone();
+        system.size_over_life = p.size_over_life.clone();
+        system.speed_over_life = p.speed_over_life.clone();
+        system.point_forces = p.point_forces.clone();
+        system.directional_force = p.directional_force;
+        system.colliders = p.colliders.clone();
+    }
+}
+
+/// Reloads an effect file when its modification time changes, so effects can be edited while the app runs
+pub struct EffectWatcher {
+    filename: String,
+    modified: Option<SystemTime>,
+}
+
+impl EffectWatcher {
+    /// The first `poll` loads the file
+    pub fn new(filename: &str) -> EffectWatcher {
+        EffectWatcher {
+            filename: filename.to_string(),
+            modified: None,
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Check if the file changed, returns the reloaded effect or the load error if it did.
+    /// Only checks the modification time if nothing changed, so it can be called every frame.
+    pub fn poll(&mut self) -> Option<Result<ParticleEffect, EffectError>> {
+        // A missing file may be in the middle of being saved, so keep the last result until it is back
+        let modified = std::fs::metadata(&self.filename)
+            .and_then(|m| m.modified())
+            .ok()?;
+        if self.modified == Some(modified) {
+            return None;
+        }
+        self.modified = Some(modified);
+        Some(ParticleEffect::load(&self.filename))
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+// The part of the file the current line is in, forces and colliders are added at the end of their section
+enum Section {
+    Main,
+    PointForce {
+        pos: vec3,
+        strength: f32,
+        linear_attenuation: f32,
+        quadratic_attenuation: f32,
+    },
+    Collider {
+        shape: Option<ColliderShape>,
+        restitution: f32,
+        friction: f32,
+        kill_on_contact: bool,
+    },
+}
+
+fn new_section(header: &str) -> Result<Section, String> {
+    match header {
+        "[point_force]" => Ok(Section::PointForce {
+            pos: vec3(0.0, 0.0, 0.0),
+            strength: 0.0,
+            linear_attenuation: 0.0,
+            quadratic_attenuation: 0.0,
+        }),
+        "[collider]" => Ok(Section::Collider {
+            shape: None,
+            restitution: 0.5,
+            friction: 0.5,
+            kill_on_contact: false,
+        }),
+        _ => Err(format!("unknown section '{header}'")),
+    }
+}
+
+fn finish_section(params: &mut ParticleSystem, section: Section) -> Result<(), String> {
+    match section {
+        Section::Main => {}
+        Section::PointForce {
+            pos,
+            strength,
+            linear_attenuation,
+            quadratic_attenuation,
+        } => params.point_forces.push(PointForce::new(
+            pos,
+            strength,
+            linear_attenuation,
+            quadratic_attenuation,
+        )),
+        Section::Collider {
+            shape,
+            restitution,
+            friction,
+            kill_on_contact,
+        } => {
+            let Some(shape) = shape else {
+                return Err("collider has no shape".to_string());
+            };
+            params.colliders.push(Collider {
+                shape,
+                restitution,
+                friction,
+                kill_on_contact,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse_value(
+    params: &mut ParticleSystem,
+    section: &mut Section,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    let mut words = value.split_whitespace();
+    let words = &mut words;
+    match section {
+        Section::Main => match key {
+            "spawn_rate" => params.spawn_rate = next_f32(words)?,
+            "speed" => params.speed = next_f32(words)?,
+            "speed_spread" => params.speed_spread = next_f32(words)?,
+            "size" => params.size = next_f32(words)?,
+            "size_spread" => params.size_spread = next_f32(words)?,
+            "life" => params.life = next_f32(words)?,
+            "life_spread" => params.life_spread = next_f32(words)?,
+            "friction_factor" => params.friction_factor = next_f32(words)?,
+            "rotation" => params.rotation = next_f32(words)?,
+            "rotation_spread" => params.rotation_spread = next_f32(words)?,
+            "angular_velocity" => params.angular_velocity = next_f32(words)?,
+            "angular_velocity_spread" => params.angular_velocity_spread = next_f32(words)?,
+            "orientation" => params.orientation = parse_orientation(words)?,
+            "sort" => {
+                params.sort = match next_word(words)? {
+                    "none" => ParticleSort::None,
+                    "back_to_front" => ParticleSort::BackToFront,
+                    word => return Err(format!("unknown sort '{word}'")),
+                }
+            }
+            "max_particles" => {
+                params.max_particles = match next_word(words)? {
+                    "none" => None,
+                    word => Some(
+                        word.parse()
+                            .map_err(|_| format!("expected a count or none, found '{word}'"))?,
+                    ),
+                }
+            }
+            "overflow_policy" => {
+                params.overflow_policy = match next_word(words)? {
+                    "stop_spawning" => OverflowPolicy::StopSpawning,
+                    "kill_oldest" => OverflowPolicy::KillOldest,
+                    "reduce_spawn_rate" => OverflowPolicy::ReduceSpawnRate,
+                    word => return Err(format!("unknown overflow policy '{word}'")),
+                }
+            }
+            "emitter" => params.emitter = parse_emitter(words)?,
+            "direction" => params.direction = next_direction(words)?,
+            "spread" => params.spread = next_f32(words)?,
+            "spread_shape" => {
+                params.spread_shape = match next_word(words)? {
+                    "square" => SpreadShape::Square,
+                    "cone" => SpreadShape::Cone,
+                    word => return Err(format!("unknown spread shape '{word}'")),
+                }
+            }
+            "emit_direction" => {
+                params.emit_direction = match next_word(words)? {
+                    "fixed" => EmitDirection::Fixed,
+                    "normal" => EmitDirection::Normal,
+                    word => return Err(format!("unknown emit direction '{word}'")),
+                }
+            }
+            "color_over_life" => {
+                params.color_over_life = match value {
+                    "fire" => Gradient::fire(),
+                    "ice" => Gradient::ice(),
+                    "smoke" => Gradient::smoke(),
+                    "rainbow" => Gradient::rainbow(),
+                    _ => {
+                        let (interpolation, keys) = parse_keys(value, next_vec4)?;
+                        Gradient {
+                            keys,
+                            interpolation,
+                        }
+                    }
+                };
+                return Ok(());
+            }
+            "alpha_over_life" | "size_over_life" | "speed_over_life" => {
+                let (interpolation, keys) = parse_keys(value, next_f32)?;
+                let curve = Curve {
+                    keys,
+                    interpolation,
+                };
+                match key {
+                    "alpha_over_life" => params.alpha_over_life = curve,
+                    "size_over_life" => params.size_over_life = curve,
+                    _ => params.speed_over_life = curve,
+                }
+                return Ok(());
+            }
+            "directional_force" => params.directional_force = next_vec3(words)?,
+            _ => return Err(format!("unknown parameter '{key}'")),
+        },
+        Section::PointForce {
+            pos,
+            strength,
+            linear_attenuation,
+            quadratic_attenuation,
+        } => match key {
+            "pos" => *pos = next_vec3(words)?,
+            "strength" => *strength = next_f32(words)?,
+            "linear_attenuation" => *linear_attenuation = next_f32(words)?,
+            "quadratic_attenuation" => *quadratic_attenuation = next_f32(words)?,
+            _ => return Err(format!("unknown point force parameter '{key}'")),
+        },
+        Section::Collider {
+            shape,
+            restitution,
+            friction,
+            kill_on_contact,
+        } => match key {
+            "shape" => *shape = Some(parse_collider_shape(words)?),
+            "restitution" => *restitution = next_f32(words)?,
+            "friction" => *friction = next_f32(words)?,
+            "kill_on_contact" => *kill_on_contact = next_bool(words)?,
+            _ => return Err(format!("unknown collider parameter '{key}'")),
+        },
+    }
+    end(words)
+}
+
+fn parse_orientation(words: &mut SplitWhitespace) -> Result<ParticleOrientation, String> {
+    Ok(match next_word(words)? {
+        "camera_facing" => ParticleOrientation::CameraFacing,
+        "velocity_stretched" => ParticleOrientation::VelocityStretched {
+            stretch: next_f32(words)?,
+        },
+        "axis_locked" => ParticleOrientation::AxisLocked {
+            axis: next_direction(words)?,
+        },
+        "world_aligned" => ParticleOrientation::WorldAligned {
+            normal: next_direction(words)?,
+        },
+        word => return Err(format!("unknown orientation '{word}'")),
+    })
+}
+
+fn parse_emitter(words: &mut SplitWhitespace) -> Result<EmitterShape, String> {
+    Ok(match next_word(words)? {
+        "point" => EmitterShape::Point,
+        "sphere" => EmitterShape::Sphere {
+            radius: next_f32(words)?,
+            surface: next_flag(words, "surface")?,
+        },
+        "hemisphere" => EmitterShape::Hemisphere {
+            radius: next_f32(words)?,
+            surface: next_flag(words, "surface")?,
+        },
+        "box" => EmitterShape::Box {
+            half_extents: next_vec3(words)?,
+        },
+        "disk" => EmitterShape::Disk {
+            radius: next_f32(words)?,
+        },
+        "cone" => EmitterShape::Cone {
+            radius: next_f32(words)?,
+            angle: next_f32(words)?,
+        },
+        "line" => EmitterShape::Line {
+            start: next_vec3(words)?,
+            end: next_vec3(words)?,
+        },
+        word => return Err(format!("unknown emitter '{word}'")),
+    })
+}
+
+fn parse_collider_shape(words: &mut SplitWhitespace) -> Result<ColliderShape, String> {
+    Ok(match next_word(words)? {
+        "plane" => ColliderShape::Plane(Plane::new(next_direction(words)?, next_f32(words)?)),
+        "box" => ColliderShape::Aabb {
+            aabb: Aabb::new(next_vec3(words)?, next_vec3(words)?),
+            inside: next_flag(words, "inside")?,
+        },
+        "sphere" => ColliderShape::Sphere {
+            sphere: Sphere::new(next_vec3(words)?, next_f32(words)?),
+            inside: next_flag(words, "inside")?,
+        },
+        word => return Err(format!("unknown collider shape '{word}'")),
+    })
+}
+
+// Parse an optional interpolation followed by comma separated `time: value` keys
+fn parse_keys<T>(
+    value: &str,
+    next_value: fn(&mut SplitWhitespace) -> Result<T, String>,
+) -> Result<(Interpolation, Vec<Keyframe<T>>), String> {
+    let (interpolation, keys_text) = match value.split_once(char::is_whitespace) {
+        Some(("step", rest)) => (Interpolation::Step, rest),
+        Some(("linear", rest)) => (Interpolation::Linear, rest),
+        Some(("smooth", rest)) => (Interpolation::Smooth, rest),
+        _ => (Interpolation::Linear, value),
+    };
+
+    let mut keys = Vec::new();
+    for key in keys_text.split(',') {
+        let Some((time, key_value)) = key.split_once(':') else {
+            return Err(format!("expected 'time: value', found '{}'", key.trim()));
+        };
+        let time = parse_f32(time.trim())?;
+        if keys.last().is_some_and(|k: &Keyframe<T>| k.time > time) {
+            return Err("keys are not sorted by time".to_string());
+        }
+        let mut words = key_value.split_whitespace();
+        let value = next_value(&mut words)?;
+        end(&mut words)?;
+        keys.push(Keyframe { time, value });
+    }
+    Ok((interpolation, keys))
+}
+
+fn next_word<'a>(words: &mut SplitWhitespace<'a>) -> Result<&'a str, String> {
+    words.next().ok_or_else(|| "missing value".to_string())
+}
+
+fn parse_f32(word: &str) -> Result<f32, String> {
+    match word.parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("expected a number, found '{word}'")),
+    }
+}
+
+fn next_f32(words: &mut SplitWhitespace) -> Result<f32, String> {
+    parse_f32(next_word(words)?)
+}
+
+fn next_vec3(words: &mut SplitWhitespace) -> Result<vec3, String> {
+    Ok(vec3(next_f32(words)?, next_f32(words)?, next_f32(words)?))
+}
+
+// A vec3 normalized, for directions, axes and normals
+fn next_direction(words: &mut SplitWhitespace) -> Result<vec3, String> {
+    let v = next_vec3(words)?;
+    let len = length(&v);
+    if len <= f32::EPSILON {
+        return Err("expected a direction, found a zero vector".to_string());
+    }
+    Ok(v / len)
+}
+
+fn next_vec4(words: &mut SplitWhitespace) -> Result<vec4, String> {
+    Ok(vec4(
+        next_f32(words)?,
+        next_f32(words)?,
+        next_f32(words)?,
+        next_f32(words)?,
+    ))
+}
+
+fn next_bool(words: &mut SplitWhitespace) -> Result<bool, String> {
+    match next_word(words)? {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        word => Err(format!("expected true or false, found '{word}'")),
+    }
+}
+
+// An optional trailing word, e.g. `inside`
+fn next_flag(words: &mut SplitWhitespace, flag: &str) -> Result<bool, String> {
+    match words.next() {
+        None => Ok(false),
+        Some(word) if word == flag => Ok(true),
+        Some(word) => Err(format!("expected '{flag}' or nothing, found '{word}'")),
+    }
+}
+
+fn end(words: &mut SplitWhitespace) -> Result<(), String> {
+    match words.next() {
+        None => Ok(()),
+        Some(word) => Err(format!("unexpected '{word}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_rand::GameRand;
+
+    fn parse(text: &str) -> ParticleSystem {
+        match ParticleEffect::parse(text) {
+            Ok(effect) => effect.create(),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    // The line number and message of a parse error
+    fn parse_error(text: &str) -> (usize, String) {
+        match ParticleEffect::parse(text) {
+            Err(EffectError::Parse(line, message)) => (line, message),
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("no error parsing '{text}'"),
+        }
+    }
+
+    fn keys<T: Copy>(keys: &[Keyframe<T>]) -> Vec<(f32, T)> {
+        keys.iter().map(|k| (k.time, k.value)).collect()
+    }
+
+    #[test]
+    fn main_parameters() {
+        let system = parse(
+            "# Every parameter
+            spawn_rate = 400
+            speed = 70.5
+            speed_spread = 20
+            size = 15
+            size_spread = 5
+            life = 3
+            life_spread = 0.25
+            friction_factor = 0.95
+            rotation = 1.5
+            rotation_spread = -0.5
+            angular_velocity = 2
+            angular_velocity_spread = 1e-1
+            orientation = velocity_stretched 0.1
+            sort = back_to_front
+            max_particles = 1500
+            overflow_policy = kill_oldest
+            emitter = sphere 10 surface
+            direction = 0 0 -2
+            spread = 0.5
+            spread_shape = cone
+            emit_direction = normal   # Trailing comment
+            directional_force = 0 -10 0.5",
+        );
+        assert_eq!(system.spawn_rate, 400.0);
+        assert_eq!(system.speed, 70.5);
+        assert_eq!(system.speed_spread, 20.0);
+        assert_eq!(system.size, 15.0);
+        assert_eq!(system.size_spread, 5.0);
+        assert_eq!(system.life, 3.0);
+        assert_eq!(system.life_spread, 0.25);
+        assert_eq!(system.friction_factor, 0.95);
+        assert_eq!(system.rotation, 1.5);
+        assert_eq!(system.rotation_spread, -0.5);
+        assert_eq!(system.angular_velocity, 2.0);
+        assert_eq!(system.angular_velocity_spread, 0.1);
+        assert_eq!(
+            system.orientation,
+            ParticleOrientation::VelocityStretched { stretch: 0.1 }
+        );
+        assert_eq!(system.sort, ParticleSort::BackToFront);
+        assert_eq!(system.max_particles, Some(1500));
+        assert_eq!(system.overflow_policy, OverflowPolicy::KillOldest);
+        assert!(matches!(
+            system.emitter,
+            EmitterShape::Sphere {
+                radius: 10.0,
+                surface: true
+            }
+        ));
+        assert_eq!(system.direction, vec3(0.0, 0.0, -1.0));
+        assert_eq!(system.spread, 0.5);
+        assert_eq!(system.spread_shape, SpreadShape::Cone);
+        assert_eq!(system.emit_direction, EmitDirection::Normal);
+        assert_eq!(system.directional_force, vec3(0.0, -10.0, 0.5));
+    }
+
+    #[test]
+    fn defaults() {
+        let system = parse("");
+        let default = ParticleSystem::new();
+        assert_eq!(system.spawn_rate, default.spawn_rate);
+        assert_eq!(system.direction, default.direction);
+        assert_eq!(system.spread_shape, default.spread_shape);
+        assert_eq!(system.max_particles, default.max_particles);
+        assert!(system.point_forces.is_empty() && system.colliders.is_empty());
+    }
+
+    type Check = fn(&ParticleSystem) -> bool;
+
+    #[test]
+    fn variants() {
+        let cases: &[(&str, Check)] = &[
+            ("sort = none", |s: &ParticleSystem| {
+                s.sort == ParticleSort::None
+            }),
+            ("max_particles = none", |s| s.max_particles.is_none()),
+            ("overflow_policy = stop_spawning", |s| {
+                s.overflow_policy == OverflowPolicy::StopSpawning
+            }),
+            ("overflow_policy = reduce_spawn_rate", |s| {
+                s.overflow_policy == OverflowPolicy::ReduceSpawnRate
+            }),
+            ("spread_shape = square", |s| {
+                s.spread_shape == SpreadShape::Square
+            }),
+            ("emit_direction = fixed", |s| {
+                s.emit_direction == EmitDirection::Fixed
+            }),
+            ("orientation = camera_facing", |s| {
+                s.orientation == ParticleOrientation::CameraFacing
+            }),
+            ("orientation = axis_locked 0 2 0", |s| {
+                s.orientation
+                    == ParticleOrientation::AxisLocked {
+                        axis: vec3(0.0, 1.0, 0.0),
+                    }
+            }),
+            ("orientation = world_aligned 3 0 0", |s| {
+                s.orientation
+                    == ParticleOrientation::WorldAligned {
+                        normal: vec3(1.0, 0.0, 0.0),
+                    }
+            }),
+            ("emitter = point", |s| {
+                matches!(s.emitter, EmitterShape::Point)
+            }),
+            ("emitter = sphere 2", |s| {
+                matches!(
+                    s.emitter,
+                    EmitterShape::Sphere {
+                        radius: 2.0,
+                        surface: false
+                    }
+                )
+            }),
+            ("emitter = hemisphere 3 surface", |s| {
+                matches!(
+                    s.emitter,
+                    EmitterShape::Hemisphere {
+                        radius: 3.0,
+                        surface: true
+                    }
+                )
+            }),
+            ("emitter = hemisphere 3", |s| {
+                matches!(
+                    s.emitter,
+                    EmitterShape::Hemisphere {
+                        radius: 3.0,
+                        surface: false
+                    }
+                )
+            }),
+            ("emitter = box 1 2 3", |s| {
+                let size = vec3(1.0, 2.0, 3.0);
+                matches!(s.emitter, EmitterShape::Box { half_extents } if half_extents == size)
+            }),
+            ("emitter = disk 4", |s| {
+                matches!(s.emitter, EmitterShape::Disk { radius: 4.0 })
+            }),
+            ("emitter = cone 5 0.5", |s| {
+                matches!(
+                    s.emitter,
+                    EmitterShape::Cone {
+                        radius: 5.0,
+                        angle: 0.5
+                    }
+                )
+            }),
+            ("emitter = line 0 0 0 1 2 3", |s| {
+                matches!(s.emitter, EmitterShape::Line { start, end }
+                    if start == vec3(0.0, 0.0, 0.0) && end == vec3(1.0, 2.0, 3.0))
+            }),
+        ];
+        for (text, check) in cases {
+            assert!(check(&parse(text)), "{text}");
+        }
+    }
+
+    #[test]
+    fn curves() {
+        let system = parse(
+            "alpha_over_life = 0: 1, 1: 0
+            size_over_life = step 0: 1, 0.5: 2, 1: 3
+            speed_over_life = smooth 0: 1,1:0.5
+            color_over_life = linear 0: 1 0 0 1, 1: 0 0 1 0",
+        );
+        assert_eq!(system.alpha_over_life.interpolation, Interpolation::Linear);
+        assert_eq!(keys(&system.alpha_over_life.keys), [(0.0, 1.0), (1.0, 0.0)]);
+        assert_eq!(system.size_over_life.interpolation, Interpolation::Step);
+        assert_eq!(
+            keys(&system.size_over_life.keys),
+            [(0.0, 1.0), (0.5, 2.0), (1.0, 3.0)]
+        );
+        assert_eq!(system.speed_over_life.interpolation, Interpolation::Smooth);
+        assert_eq!(keys(&system.speed_over_life.keys), [(0.0, 1.0), (1.0, 0.5)]);
+        assert_eq!(system.color_over_life.interpolation, Interpolation::Linear);
+        assert_eq!(
+            keys(&system.color_over_life.keys),
+            [
+                (0.0, vec4(1.0, 0.0, 0.0, 1.0)),
+                (1.0, vec4(0.0, 0.0, 1.0, 0.0))
+            ]
+        );
+
+        for (name, scheme) in [
+            ("fire", ColorScheme::Fire),
+            ("ice", ColorScheme::Ice),
+            ("smoke", ColorScheme::Smoke),
+            ("rainbow", ColorScheme::Rainbow),
+        ] {
+            let system = parse(&format!("color_over_life = {name}"));
+            let mut expected = ParticleSystem::new();
+            expected.set_color_scheme(scheme);
+            assert_eq!(
+                keys(&system.color_over_life.keys),
+                keys(&expected.color_over_life.keys)
+            );
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let system = parse(
+            "spawn_rate = 5
+
+            [point_force]
+            pos = 0 100 0
+            strength = 50
+            linear_attenuation = 0.01
+            quadratic_attenuation = 0.001
+
+            [point_force]
+
+            [collider]
+            shape = plane 0 2 0 -5
+            restitution = 0.25
+            friction = 0.75
+            kill_on_contact = true
+
+            [collider]
+            shape = box -100 0 -100 100 200 100 inside
+
+            [collider]
+            shape = box -1 -1 -1 1 1 1
+
+            [collider]
+            shape = sphere 1 2 3 4 inside
+
+            [collider]
+            shape = sphere 1 2 3 4",
+        );
+        assert_eq!(system.spawn_rate, 5.0);
+
+        let expected = PointForce::new(vec3(0.0, 100.0, 0.0), 50.0, 0.01, 0.001);
+        assert_eq!(system.point_forces.len(), 2);
+        assert_eq!(
+            format!("{:?}", system.point_forces[0]),
+            format!("{expected:?}")
+        );
+        let expected = PointForce::new(vec3(0.0, 0.0, 0.0), 0.0, 0.0, 0.0);
+        assert_eq!(
+            format!("{:?}", system.point_forces[1]),
+            format!("{expected:?}")
+        );
+
+        let c = &system.colliders;
+        assert_eq!(c.len(), 5);
+        assert!(matches!(c[0].shape, ColliderShape::Plane(plane)
+            if plane.normal == vec3(0.0, 1.0, 0.0) && plane.d == -5.0));
+        assert_eq!(
+            (c[0].restitution, c[0].friction, c[0].kill_on_contact),
+            (0.25, 0.75, true)
+        );
+
+        let room = Aabb::new(vec3(-100.0, 0.0, -100.0), vec3(100.0, 200.0, 100.0));
+        assert!(matches!(c[1].shape, ColliderShape::Aabb { aabb, inside: true } if aabb == room));
+        let default = Collider::new(c[1].shape);
+        assert_eq!(
+            (c[1].restitution, c[1].friction, c[1].kill_on_contact),
+            (
+                default.restitution,
+                default.friction,
+                default.kill_on_contact
+            )
+        );
+        let unit = Aabb::new(vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0));
+        assert!(matches!(c[2].shape, ColliderShape::Aabb { aabb, inside: false } if aabb == unit));
+
+        let sphere = Sphere::new(vec3(1.0, 2.0, 3.0), 4.0);
+        assert!(
+            matches!(c[3].shape, ColliderShape::Sphere { sphere: s, inside: true } if s == sphere)
+        );
+        assert!(
+            matches!(c[4].shape, ColliderShape::Sphere { sphere: s, inside: false } if s == sphere)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("speed = 1\nsped = 2", 2, "unknown parameter 'sped'"),
+            ("\n\nspeed = fast", 3, "expected a number, found 'fast'"),
+            ("speed = nan", 1, "expected a number, found 'nan'"),
+            ("speed = inf", 1, "expected a number, found 'inf'"),
+            (
+                "speed = -infinity",
+                1,
+                "expected a number, found '-infinity'",
+            ),
+            ("speed = 1 2", 1, "unexpected '2'"),
+            ("speed", 1, "expected 'name = value', found 'speed'"),
+            ("speed =", 1, "missing value"),
+            ("directional_force = 0 1", 1, "missing value"),
+            (
+                "direction = 0 0 0",
+                1,
+                "expected a direction, found a zero vector",
+            ),
+            (
+                "orientation = axis_locked 0 0 0",
+                1,
+                "expected a direction, found a zero vector",
+            ),
+            (
+                "orientation = world_aligned 0 0 0",
+                1,
+                "expected a direction, found a zero vector",
+            ),
+            (
+                "orientation = sideways",
+                1,
+                "unknown orientation 'sideways'",
+            ),
+            ("emitter = torus 1", 1, "unknown emitter 'torus'"),
+            (
+                "emitter = sphere 1 inside",
+                1,
+                "expected 'surface' or nothing, found 'inside'",
+            ),
+            ("sort = random", 1, "unknown sort 'random'"),
+            (
+                "max_particles = -1",
+                1,
+                "expected a count or none, found '-1'",
+            ),
+            (
+                "overflow_policy = ignore",
+                1,
+                "unknown overflow policy 'ignore'",
+            ),
+            ("spread_shape = round", 1, "unknown spread shape 'round'"),
+            ("emit_direction = up", 1, "unknown emit direction 'up'"),
+            (
+                "size_over_life = 1: 0, 0: 1",
+                1,
+                "keys are not sorted by time",
+            ),
+            (
+                "size_over_life = 0 1",
+                1,
+                "expected 'time: value', found '0 1'",
+            ),
+            ("size_over_life = 0: 1 2", 1, "unexpected '2'"),
+            ("color_over_life = 0: 1 1 1", 1, "missing value"),
+            ("[emitter]", 1, "unknown section '[emitter]'"),
+            (
+                "[point_force]\nspeed = 1",
+                2,
+                "unknown point force parameter 'speed'",
+            ),
+            (
+                "[collider]\npos = 0 0 0",
+                2,
+                "unknown collider parameter 'pos'",
+            ),
+            (
+                "[collider]\nshape = plane 0 0 0 1",
+                2,
+                "expected a direction, found a zero vector",
+            ),
+            (
+                "[collider]\nshape = cylinder",
+                2,
+                "unknown collider shape 'cylinder'",
+            ),
+            (
+                "[collider]\nkill_on_contact = yes",
+                2,
+                "expected true or false, found 'yes'",
+            ),
+            (
+                "[collider]\nrestitution = 1\n\n[point_force]",
+                4,
+                "collider has no shape",
+            ),
+            (
+                "[collider]\nrestitution = 1\n# End",
+                3,
+                "collider has no shape",
+            ),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(parse_error(text), (line, message.to_string()), "{text}");
+        }
+    }
+
+    // The light effect gives the sparks that were set up in code before effect files
+    #[test]
+    fn light_effect_matches_original() {
+        let effect =
+            ParticleEffect::load(concat!(env!("CARGO_MANIFEST_DIR"), "/data/light.effect"));
+        let system = effect.unwrap().create();
+        assert_eq!(system.spawn_rate, 400.0);
+        assert_eq!(system.speed, 70.0);
+        assert_eq!(system.speed_spread, 20.0);
+        assert_eq!(system.life, 3.0);
+        assert_eq!(system.life_spread, 0.0);
+        assert_eq!(system.directional_force, vec3(0.0, -10.0, 0.0));
+        assert_eq!(system.friction_factor, 0.95);
+        assert_eq!(system.size, 15.0);
+        assert_eq!(system.size_spread, 5.0);
+
+        let original = Gradient::new(&[
+            (0.0, vec4(0.3, 0.31, 0.1, 0.0)),
+            (5.0 / 11.0, vec4(0.3, 0.06, 0.0, 0.0)),
+            (6.0 / 11.0, vec4(0.25, 0.05, 0.0, 0.0)),
+            (1.0, vec4(0.0, 0.0, 0.0, 0.0)),
+        ]);
+        assert_eq!(system.color_over_life.interpolation, original.interpolation);
+        let gradient = keys(&system.color_over_life.keys);
+        for ((time, value), (original_time, original_value)) in
+            gradient.iter().zip(keys(&original.keys))
+        {
+            assert!((time - original_time).abs() < 1e-6);
+            assert_eq!(*value, original_value);
+        }
+        assert_eq!(gradient.len(), original.keys.len());
+    }
+
+    #[test]
+    fn light_effect_is_not_throttled() {
+        let effect =
+            ParticleEffect::load(concat!(env!("CARGO_MANIFEST_DIR"), "/data/light.effect"));
+        let mut system = effect.unwrap().create();
+        let mut rand = GameRand::new(50);
+        for frame in 1..=600 {
+            system.update(frame as f32 / 60.0, &mut rand);
+        }
+        assert!(system.get_particle_count() >= 1150);
+        assert_eq!(system.get_dropped_spawns(), 0);
+    }
+}
@@ -0,0 +1,349 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::game_rand::GameRand;
+use crate::particle_system::ParticleSystem;
+use crate::vector::*;
+
+struct ManagedSystem {
+    system: ParticleSystem,
+    rand: GameRand,
+    // This system's share of the global particle limit for the next update
+    limit: usize,
+}
+
+type SystemFn = Arc<dyn Fn(&mut ManagedSystem) + Send + Sync>;
+
+// A group of systems lent to a worker thread for one call, and the function to run on each
+struct Job {
+    systems: Vec<ManagedSystem>,
+    f: SystemFn,
+}
+
+// A thread kept running between updates. It runs the jobs it is sent and sends the systems back.
+struct Worker {
+    jobs: Option<Sender<Job>>,
+    done: Receiver<Vec<ManagedSystem>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new() -> Worker {
+        let (jobs, job_receiver) = channel::<Job>();
+        let (done_sender, done) = channel();
+        let thread = std::thread::spawn(move || {
+            for mut job in job_receiver {
+                job.systems.iter_mut().for_each(|s| (job.f)(s));
+                if done_sender.send(job.systems).is_err() {
+                    break;
+                }
+            }
+        });
+        Worker {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, job: Job) {
+        // Only fails if the thread has exited, which receive reports
+        let _ = self.jobs.as_ref().unwrap().send(job);
+    }
+
+    fn receive(&self) -> Vec<ManagedSystem> {
+        self.done.recv().expect("particle worker thread panicked")
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the job channel ends the thread
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Owns many particle systems and updates them on worker threads.
+/// Each system has its own random number stream, so results do not depend on the thread count.
+pub struct ParticleManager {
+    /// The most threads used for updates, including the calling thread.
+    /// Worker threads are started when first needed and kept until the manager is dropped.
+    pub thread_count: usize,
+    /// The most particles all systems may have together, None for no limit.
+    /// Shared between the systems in proportion to their spawn rates.
+    pub max_particles: Option<usize>,
+
+    rand: GameRand,
+    systems: Vec<ManagedSystem>,
+    workers: Vec<Worker>,
+}
+
+impl ParticleManager {
+    /// * `seed` - The seed the random number streams of the systems are split from
+    pub fn new(seed: u32) -> ParticleManager {
+        ParticleManager {
+            thread_count: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_particles: None,
+            rand: GameRand::new(seed),
+            systems: Vec::new(),
+            workers: Vec::new(),
+        }
+    }
+
+    /// Add a system, returns its id
+    pub fn add(&mut self, system: ParticleSystem) -> usize {
+        let id = self.systems.len();
+        self.systems.push(ManagedSystem {
+            system,
+            rand: self.rand.split(id as u32),
+            limit: usize::MAX,
+        });
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.systems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.systems.is_empty()
+    }
+
+    pub fn get(&self, id: usize) -> &ParticleSystem {
+        &self.systems[id].system
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> &mut ParticleSystem {
+        &mut self.systems[id].system
+    }
+
+    /// Get the total number of particles in all systems
+    pub fn get_particle_count(&self) -> usize {
+        self.systems
+            .iter()
+            .map(|s| s.system.get_particle_count())
+            .sum()
+    }
+
+    /// Get the total number of particles not spawned due to particle limits in all systems
+    pub fn get_dropped_spawns(&self) -> u64 {
+        self.systems
+            .iter()
+            .map(|s| s.system.get_dropped_spawns())
+            .sum()
+    }
+
+    /// Returns true if any system has too many particles for 16 bit indices
+    pub fn needs_u32_indices(&self) -> bool {
+        self.systems.iter().any(|s| s.system.needs_u32_indices())
+    }
+
+    /// Update all systems
+    /// * `time_stamp` - The current time in seconds
+    pub fn update(&mut self, time_stamp: f32) {
+        self.set_limits();
+        self.for_each_parallel(move |s| {
+            s.system.update_with_limit(time_stamp, &mut s.rand, s.limit)
+        });
+    }
+
+    // Share the global particle limit between the systems. Over the limit each system is scaled down
+    // in proportion to its particle count, otherwise the free space is split by spawn rate.
+    fn set_limits(&mut self) {
+        let Some(max_particles) = self.max_particles else {
+            self.systems.iter_mut().for_each(|s| s.limit = usize::MAX);
+            return;
+        };
+
+        let total = self.get_particle_count();
+        if total >= max_particles {
+            for s in &mut self.systems {
+                let count = s.system.get_particle_count();
+                s.limit = (count as u64 * max_particles as u64 / total.max(1) as u64) as usize;
+            }
+            return;
+        }
+
+        let free = (max_particles - total) as f32;
+        let total_rate: f32 = self
+            .systems
+            .iter()
+            .map(|s| s.system.spawn_rate.max(0.0))
+            .sum();
+        for s in &mut self.systems {
+            let share = if total_rate > 0.0 {
+                (free * s.system.spawn_rate.max(0.0) / total_rate) as usize
+            } else {
+                0
+            };
+            s.limit = s.system.get_particle_count() + share;
+        }
+    }
+
+    /// Build the vertex and index arrays of all systems, read them with `ParticleSystem::last_vertex_array`
+    /// and `ParticleSystem::last_index_array`. The parameters are passed to `ParticleSystem::get_vertex_array`.
+    /// Systems with too many particles for 16 bit indices build `ParticleSystem::last_index_array_u32` instead.
+    pub fn build_vertex_arrays(&mut self, dx: vec3, dy: vec3, use_colors: bool, tex3d: bool) {
+        self.for_each_parallel(move |s| {
+            s.system.get_vertex_array(dx, dy, use_colors, tex3d);
+            if s.system.needs_u32_indices() {
+                s.system.get_index_array_u32();
+            } else {
+                s.system.get_index_array();
+            }
+        });
+    }
+
+    // Run a function on every system, splitting the systems into one contiguous group per thread.
+    // The calling thread does the first group, the others are moved to the worker threads and back.
+    fn for_each_parallel<F: Fn(&mut ManagedSystem) + Send + Sync + 'static>(&mut self, f: F) {
+        self.workers.truncate(self.thread_count.saturating_sub(1));
+        let thread_count = self.thread_count.clamp(1, self.systems.len().max(1));
+        if thread_count == 1 {
+            self.systems.iter_mut().for_each(f);
+            return;
+        }
+
+        while self.workers.len() < thread_count - 1 {
+            self.workers.push(Worker::new());
+        }
+
+        let group_size = self.systems.len().div_ceil(thread_count);
+        let group_count = self.systems.len().div_ceil(group_size);
+        let f: SystemFn = Arc::new(f);
+
+        // Split from the back, so each group is copied once
+        for group in (1..group_count).rev() {
+            let systems = self.systems.split_off(group * group_size);
+            self.workers[group - 1].send(Job {
+                systems,
+                f: f.clone(),
+            });
+        }
+        self.systems.iter_mut().for_each(|s| f(s));
+        for worker in &self.workers[..group_count - 1] {
+            self.systems.append(&mut worker.receive());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use crate::particle_system::ColorScheme;
+
+    const FRAME_TIME: f32 = 1.0 / 60.0;
+
+    // Systems with different spawn rates and directions, sharing a global limit
+    fn test_manager(system_count: usize, thread_count: usize) -> ParticleManager {
+        let mut manager = ParticleManager::new(48);
+        manager.thread_count = thread_count;
+        manager.max_particles = Some(system_count * 1000);
+        for i in 0..system_count {
+            let mut system = ParticleSystem::new();
+            system.pos = vec3(i as f32 * 100.0, 0.0, 0.0);
+            system.spawn_rate = 200.0 + 100.0 * i as f32;
+            system.speed = 70.0;
+            system.life = 3.0;
+            system.directional_force = vec3(0.0, -10.0, i as f32);
+            system.set_color_scheme(ColorScheme::Fire);
+            manager.add(system);
+        }
+        manager
+    }
+
+    fn run(manager: &mut ParticleManager, frames: u32) {
+        let (dx, dy) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        for frame in 1..=frames {
+            manager.update(frame as f32 * FRAME_TIME);
+            manager.build_vertex_arrays(dx, dy, true, false);
+        }
+    }
+
+    #[test]
+    fn thread_count_does_not_change_results() {
+        let mut single = test_manager(7, 1);
+        run(&mut single, 240);
+        assert!(single.get_dropped_spawns() > 0);
+        for thread_count in [2, 3, 8] {
+            let mut manager = test_manager(7, thread_count);
+            run(&mut manager, 240);
+            for id in 0..manager.len() {
+                let (a, b) = (single.get(id), manager.get(id));
+                assert_eq!(a.get_particle_count(), b.get_particle_count());
+                assert_eq!(a.last_vertex_array(), b.last_vertex_array());
+                assert_eq!(a.last_index_array(), b.last_index_array());
+            }
+            assert_eq!(single.get_dropped_spawns(), manager.get_dropped_spawns());
+        }
+    }
+
+    // The same worker threads run every call, and lowering the thread count stops the extra ones
+    #[test]
+    fn workers_are_kept_between_calls() {
+        use std::collections::HashSet;
+        use std::sync::Mutex;
+        use std::thread::ThreadId;
+
+        let mut manager = test_manager(7, 4);
+        let ids = Arc::new(Mutex::new(HashSet::<ThreadId>::new()));
+        for _ in 0..10 {
+            let ids = ids.clone();
+            manager.for_each_parallel(move |_| {
+                ids.lock().unwrap().insert(std::thread::current().id());
+            });
+        }
+        assert_eq!(ids.lock().unwrap().len(), 4);
+        assert_eq!(manager.workers.len(), 3);
+
+        manager.thread_count = 2;
+        run(&mut manager, 1);
+        assert_eq!(manager.workers.len(), 1);
+        manager.thread_count = 1;
+        run(&mut manager, 1);
+        assert!(manager.workers.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "particle worker thread panicked")]
+    fn worker_panics_are_passed_on() {
+        let mut manager = test_manager(4, 2);
+        let caller = std::thread::current().id();
+        manager.for_each_parallel(move |_| {
+            if std::thread::current().id() != caller {
+                panic!("worker failed");
+            }
+        });
+    }
+
+    // Systems without particles show the cost of handing the systems to the worker threads and back
+    #[test]
+    #[ignore]
+    fn bench_threads() {
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .max(2);
+        for max_particles in [0, 8000] {
+            for thread_count in [1, threads] {
+                let mut manager = test_manager(8, thread_count);
+                manager.max_particles = Some(max_particles);
+                run(&mut manager, 240);
+                let count = manager.get_particle_count();
+                let mut frame = 240;
+                bench(
+                    &format!("update, {count} particles, {thread_count} threads"),
+                    100,
+                    || {
+                        frame += 1;
+                        manager.update(frame as f32 * FRAME_TIME);
+                    },
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
+use crate::color::Gradient;
+use crate::curve::Curve;
+use crate::emitter::{EmitDirection, EmitterShape, SpreadShape};
 use crate::game_rand::GameRand;
+use crate::geometry::{Aabb, Plane, Sphere};
 use crate::vector::*;
 
 use std::mem::size_of;
@@ -10,15 +14,115 @@ pub enum ColorScheme {
     Rainbow,
 }
 
+/// How particle quads are oriented
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParticleOrientation {
+    /// Facing the camera, rotated by the particle rotation
+    CameraFacing,
+    /// Facing the camera with the texture top pointing along the velocity on screen, for sparks.
+    /// The quad is lengthened by the distance moved on screen in `stretch` seconds. Rotation is ignored.
+    VelocityStretched { stretch: f32 },
+    /// Turned to face the camera around a normalized world axis, with the texture top pointing along it,
+    /// for flames. Rotation is ignored.
+    AxisLocked { axis: vec3 },
+    /// Lying in the world plane with the given normalized normal, rotated around it by the particle rotation.
+    /// The quad is the size of a camera facing quad.
+    WorldAligned { normal: vec3 },
+}
+
+/// The order particles are drawn in
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParticleSort {
+    /// Storage order, for additive blending
+    None,
+    /// Furthest from the camera first, for alpha blending
+    BackToFront,
+}
+
+/// What happens when spawning would take a system over its particle limit
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Drop the new particles
+    StopSpawning,
+    /// Kill the particles closest to the end of their life to make room
+    KillOldest,
+    /// Lower the spawn rate as the particle count gets near the limit, and drop new particles at the limit
+    ReduceSpawnRate,
+}
+
+/// The most particles 16 bit indices can address
+pub const MAX_U16_PARTICLES: usize = 0x10000 / 4;
+
+// The fraction of the particle limit where ReduceSpawnRate starts lowering the spawn rate
+const REDUCE_SPAWN_START: f32 = 0.75;
+
 struct Particle {
     pos: vec3,
     size: f32,
+    rotation: f32,
 
     dir: vec3,
+    angular_velocity: f32,
     life: f32,
     inv_initial_life: f32,
 }
 
+// Particle data as a structure of arrays, with one element per particle in each array
+struct Particles {
+    pos: Vec<vec3>,
+    dir: Vec<vec3>,
+    size: Vec<f32>,
+    rotation: Vec<f32>,
+    angular_velocity: Vec<f32>,
+    life: Vec<f32>,
+    inv_initial_life: Vec<f32>,
+}
+
+impl Particles {
+    fn with_capacity(capacity: usize) -> Particles {
+        Particles {
+            pos: Vec::with_capacity(capacity),
+            dir: Vec::with_capacity(capacity),
+            size: Vec::with_capacity(capacity),
+            rotation: Vec::with_capacity(capacity),
+            angular_velocity: Vec::with_capacity(capacity),
+            life: Vec::with_capacity(capacity),
+            inv_initial_life: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.pos.len()
+    }
+
+    fn push(&mut self, p: Particle) {
+        self.pos.push(p.pos);
+        self.dir.push(p.dir);
+        self.size.push(p.size);
+        self.rotation.push(p.rotation);
+        self.angular_velocity.push(p.angular_velocity);
+        self.life.push(p.life);
+        self.inv_initial_life.push(p.inv_initial_life);
+    }
+
+    // Remove a particle by moving the last particle into its place
+    fn swap_remove(&mut self, i: usize) {
+        self.pos.swap_remove(i);
+        self.dir.swap_remove(i);
+        self.size.swap_remove(i);
+        self.rotation.swap_remove(i);
+        self.angular_velocity.swap_remove(i);
+        self.life.swap_remove(i);
+        self.inv_initial_life.swap_remove(i);
+    }
+
+    // Normalized age, 0 when spawned and 1 at the end of its life
+    fn age(&self, i: usize) -> f32 {
+        1.0 - self.life[i] * self.inv_initial_life[i]
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct PointForce {
     pos: vec3,
     strength: f32,
@@ -26,6 +130,120 @@ pub struct PointForce {
     quadratic_attenuation: f32,
 }
 
+impl PointForce {
+    /// * `pos` - The point particles are pulled towards, or pushed away from with a negative strength
+    /// * `strength` - The acceleration per unit of distance from the point, before attenuation
+    /// * `linear_attenuation` - The strength is divided by
+    ///   1 + distance * linear_attenuation + distance * distance * quadratic_attenuation
+    /// * `quadratic_attenuation` - See `linear_attenuation`
+    pub fn new(
+        pos: vec3,
+        strength: f32,
+        linear_attenuation: f32,
+        quadratic_attenuation: f32,
+    ) -> PointForce {
+        PointForce {
+            pos,
+            strength,
+            linear_attenuation,
+            quadratic_attenuation,
+        }
+    }
+}
+
+/// A shape particles collide with. Particles are treated as points.
+#[derive(Copy, Clone, Debug)]
+pub enum ColliderShape {
+    /// Particles are kept on the front side of the plane, the plane must be normalized
+    Plane(Plane),
+    /// Particles are kept outside the box, or inside it if `inside` is set, e.g. for the bounds of a room
+    Aabb { aabb: Aabb, inside: bool },
+    /// Particles are kept outside the sphere, or inside it if `inside` is set
+    Sphere { sphere: Sphere, inside: bool },
+}
+
+impl ColliderShape {
+    /// Returns the nearest allowed position and the contact normal pointing into the allowed space,
+    /// or None if the point is not penetrating the shape
+    pub fn resolve(&self, p: &vec3) -> Option<(vec3, vec3)> {
+        match self {
+            ColliderShape::Plane(plane) => {
+                let dist = plane.distance(p);
+                if dist >= 0.0 {
+                    return None;
+                }
+                Some((*p - plane.normal * dist, plane.normal))
+            }
+            ColliderShape::Aabb { aabb, inside: true } => {
+                if aabb.contains_point(p) {
+                    return None;
+                }
+                let closest = aabb.closest_point(p);
+                Some((closest, normalize(&(closest - *p))))
+            }
+            ColliderShape::Aabb {
+                aabb,
+                inside: false,
+            } => {
+                if !aabb.contains_point(p) {
+                    return None;
+                }
+                // Push out through the nearest face
+                let faces = [
+                    (p.x - aabb.min.x, vec3(-1.0, 0.0, 0.0)),
+                    (aabb.max.x - p.x, vec3(1.0, 0.0, 0.0)),
+                    (p.y - aabb.min.y, vec3(0.0, -1.0, 0.0)),
+                    (aabb.max.y - p.y, vec3(0.0, 1.0, 0.0)),
+                    (p.z - aabb.min.z, vec3(0.0, 0.0, -1.0)),
+                    (aabb.max.z - p.z, vec3(0.0, 0.0, 1.0)),
+                ];
+                let (depth, normal) = faces
+                    .into_iter()
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .unwrap();
+                Some((*p + normal * depth, normal))
+            }
+            ColliderShape::Sphere { sphere, inside } => {
+                let d = *p - sphere.center;
+                let dist = length(&d);
+                if (dist > sphere.radius) == *inside {
+                    let dir = if dist > 0.0 {
+                        d / dist
+                    } else {
+                        vec3(0.0, 1.0, 0.0)
+                    };
+                    let normal = if *inside { -dir } else { dir };
+                    return Some((sphere.center + dir * sphere.radius, normal));
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Collider {
+    pub shape: ColliderShape,
+    /// Fraction of the velocity along the contact normal kept when bouncing, 0 stops and 1 is a perfect bounce
+    pub restitution: f32,
+    /// Fraction of the velocity along the surface removed per second of contact, from 0 to 1.
+    /// Scaled by the frame time like `friction_factor`, so sliding slows the same at any frame rate.
+    pub friction: f32,
+    /// Kill particles touching the collider instead of bouncing them
+    pub kill_on_contact: bool,
+}
+
+impl Collider {
+    pub fn new(shape: ColliderShape) -> Collider {
+        Collider {
+            shape,
+            restitution: 0.5,
+            friction: 0.5,
+            kill_on_contact: false,
+        }
+    }
+}
+
 pub struct ParticleSystem {
     pub pos: vec3,
     pub spawn_rate: f32,
@@ -36,17 +254,62 @@ pub struct ParticleSystem {
     pub life: f32,
     pub life_spread: f32,
     pub friction_factor: f32,
+    /// Initial rotation in radians
+    pub rotation: f32,
+    /// Spread of the initial rotation, no random value is used when it is 0
+    pub rotation_spread: f32,
+    /// Rotation speed in radians per second
+    pub angular_velocity: f32,
+    /// Spread of the rotation speed, no random value is used when it is 0
+    pub angular_velocity_spread: f32,
+    pub orientation: ParticleOrientation,
+    pub sort: ParticleSort,
+    /// The most particles the system may have, None for no limit
+    pub max_particles: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+
+    pub emitter: EmitterShape,
+    /// Normalized direction new particles move in
+    pub direction: vec3,
+    /// How far new particle directions may differ from the emit direction, see SpreadShape
+    pub spread: f32,
+    pub spread_shape: SpreadShape,
+    pub emit_direction: EmitDirection,
 
-    pub colors: [vec4; 12],
+    /// Colour over the normalized particle age, 0 when spawned and 1 at the end of its life
+    pub color_over_life: Gradient,
+    /// Multiplier for the colour alpha over the normalized particle age
+    pub alpha_over_life: Curve,
+    /// Multiplier for the particle size over the normalized particle age
+    pub size_over_life: Curve,
+    /// Multiplier for the particle speed over the normalized particle age
+    pub speed_over_life: Curve,
     pub point_forces: Vec<PointForce>,
     pub directional_force: vec3,
+    /// Shapes particles collide with after the forces are applied
+    pub colliders: Vec<Collider>,
 
     last_time: f32,
     particle_credit: f32,
+    // Spawns dropped by the particle limit
+    dropped_spawns: u64,
+    // Fraction of a spawn lowered away by ReduceSpawnRate, counted as dropped once whole
+    dropped_credit: f32,
 
-    particles: Vec<Particle>,
+    particles: Particles,
     vertex_array: Vec<u8>,
     index_array: Vec<u16>,
+    index_array_u32: Vec<u32>,
+    // Used sizes of the arrays from the last time they were built
+    vertex_array_size: usize,
+    index_array_size: usize,
+    index_array_u32_size: usize,
+
+    // Buffers for sorting, kept between frames to avoid allocating
+    sort_keys: Vec<u32>,
+    sort_indices: Vec<u32>,
+    sort_temp_keys: Vec<u32>,
+    sort_temp_indices: Vec<u32>,
 }
 
 impl ParticleSystem {
@@ -61,17 +324,46 @@ impl ParticleSystem {
             life: 2.5,
             life_spread: 0.5,
             friction_factor: 0.7,
+            rotation: 0.0,
+            rotation_spread: 0.0,
+            angular_velocity: 0.0,
+            angular_velocity_spread: 0.0,
+            orientation: ParticleOrientation::CameraFacing,
+            sort: ParticleSort::None,
+            max_particles: None,
+            overflow_policy: OverflowPolicy::StopSpawning,
+
+            emitter: EmitterShape::Point,
+            direction: vec3(0.0, 1.0, 0.0),
+            spread: 0.3,
+            spread_shape: SpreadShape::Square,
+            emit_direction: EmitDirection::Fixed,
 
-            colors: [vec4(0.0, 0.0, 0.0, 0.0); 12],
+            color_over_life: Gradient::constant(vec4(0.0, 0.0, 0.0, 0.0)),
+            alpha_over_life: Curve::constant(1.0),
+            size_over_life: Curve::constant(1.0),
+            speed_over_life: Curve::constant(1.0),
             point_forces: Vec::with_capacity(2),
             directional_force: vec3(0.0, 0.0, 0.0),
+            colliders: Vec::new(),
 
             last_time: 0.0,
             particle_credit: 0.0,
+            dropped_spawns: 0,
+            dropped_credit: 0.0,
 
-            particles: Vec::with_capacity(20),
+            particles: Particles::with_capacity(20),
             vertex_array: Vec::new(),
             index_array: Vec::new(),
+            index_array_u32: Vec::new(),
+            vertex_array_size: 0,
+            index_array_size: 0,
+            index_array_u32_size: 0,
+
+            sort_keys: Vec::new(),
+            sort_indices: Vec::new(),
+            sort_temp_keys: Vec::new(),
+            sort_temp_indices: Vec::new(),
         }
     }
 
@@ -79,67 +371,94 @@ impl ParticleSystem {
         self.particles.len()
     }
 
+    /// Get the number of particles not spawned due to the particle limit since the last reset
+    pub fn get_dropped_spawns(&self) -> u64 {
+        self.dropped_spawns
+    }
+
+    pub fn reset_dropped_spawns(&mut self) {
+        self.dropped_spawns = 0;
+        self.dropped_credit = 0.0;
+    }
+
+    /// Returns true if there are too many particles for `get_index_array`, use `get_index_array_u32` instead
+    pub fn needs_u32_indices(&self) -> bool {
+        self.particles.len() > MAX_U16_PARTICLES
+    }
+
     pub fn set_color_scheme(&mut self, color_scheme: ColorScheme) {
-        match color_scheme {
-            ColorScheme::Fire => {
-                for i in 0..4 {
-                    let f = i as f32;
-                    self.colors[i] = vec4(f / 4.0, 0.0, 0.0, 0.0);
-                    self.colors[i + 4] = vec4(1.0, f / 4.0, 0.0, 0.0);
-                    self.colors[i + 8] = vec4((3.0 - f) / 3.0, (3.0 - f) / 3.0, 1.0, 0.0);
-                }
-            }
-            ColorScheme::Ice => {
-                for i in 0..6 {
-                    let f = i as f32;
-                    self.colors[i] = vec4(0.0, 0.0, f / 6.0, 0.0);
-                    self.colors[i + 6] = vec4(f / 5.0, 1.0, 1.0, 0.0);
-                }
-            }
-            ColorScheme::Smoke => {
-                for i in 0..12 {
-                    let f: f32 = i as f32 / 44.0;
-                    self.colors[i] = vec4(f, f, f, f);
-                }
-            }
-            ColorScheme::Rainbow => {
-                self.colors[0] = vec4(0.0, 0.0, 0.0, 0.0);
-                self.colors[1] = vec4(0.0, 0.0, 0.25, 0.0);
-                self.colors[2] = vec4(0.0, 0.0, 0.5, 0.0);
-                self.colors[3] = vec4(0.0, 0.0, 1.0, 0.0);
-                self.colors[4] = vec4(0.0, 0.5, 1.0, 0.0);
-                self.colors[5] = vec4(0.0, 1.0, 1.0, 0.0);
-                self.colors[6] = vec4(0.0, 1.0, 0.5, 0.0);
-                self.colors[7] = vec4(0.0, 1.0, 0.0, 0.0);
-                self.colors[8] = vec4(0.5, 1.0, 0.0, 0.0);
-                self.colors[9] = vec4(1.0, 1.0, 0.0, 0.0);
-                self.colors[10] = vec4(1.0, 0.5, 0.0, 0.0);
-                self.colors[11] = vec4(1.0, 0.0, 0.0, 0.0);
-            }
-        }
+        self.color_over_life = match color_scheme {
+            ColorScheme::Fire => Gradient::fire(),
+            ColorScheme::Ice => Gradient::ice(),
+            ColorScheme::Smoke => Gradient::smoke(),
+            ColorScheme::Rainbow => Gradient::rainbow(),
+        };
     }
 
     pub fn update(&mut self, time_stamp: f32, rand: &mut GameRand) {
+        self.update_with_limit(time_stamp, rand, usize::MAX);
+    }
+
+    /// Update with a particle limit on top of `max_particles`, e.g. a share of a global budget
+    /// * `time_stamp` - The current time in seconds
+    /// * `rand` - The random number generator to use for new particles
+    /// * `limit` - The most particles the system may have after spawning
+    pub fn update_with_limit(&mut self, time_stamp: f32, rand: &mut GameRand, limit: usize) {
         let time = time_stamp - self.last_time;
         self.last_time = time_stamp;
 
-        self.particle_credit += time * self.spawn_rate;
-        let len = self.particle_credit as u32;
-        self.particle_credit -= len as f32;
+        let limit = self.max_particles.map_or(limit, |max| max.min(limit));
+        let count = self.particles.len();
+        let requested = time * self.spawn_rate;
+        if self.overflow_policy == OverflowPolicy::ReduceSpawnRate && limit != usize::MAX {
+            let scale = if count < limit {
+                let start = limit as f32 * REDUCE_SPAWN_START;
+                ((limit - count) as f32 / (limit as f32 - start)).min(1.0)
+            } else {
+                0.0
+            };
+            self.particle_credit += requested * scale;
+            self.dropped_credit += requested * (1.0 - scale);
+            let dropped = self.dropped_credit as u32;
+            self.dropped_credit -= dropped as f32;
+            self.dropped_spawns += dropped as u64;
+        } else {
+            self.particle_credit += requested;
+        }
+        let requested_len = self.particle_credit as usize;
+        self.particle_credit -= requested_len as f32;
 
-        let mut random = |mean: f32, diff: f32| {
-            let r: f32 = 2.0 * rand.next_random01() - 1.0;
-            mean + r * r.abs() * diff
+        let len = match self.overflow_policy {
+            OverflowPolicy::KillOldest => {
+                let len = requested_len.min(limit);
+                self.kill_oldest((count + len).saturating_sub(limit));
+                len
+            }
+            OverflowPolicy::StopSpawning | OverflowPolicy::ReduceSpawnRate => {
+                requested_len.min(limit.saturating_sub(count))
+            }
         };
+        self.dropped_spawns += (requested_len - len) as u64;
 
         for _ in 0..len {
-            let life = random(self.life, self.life_spread);
+            let life = rand.spread(self.life, self.life_spread);
+            let (offset, normal) = self.emitter.sample(rand, &self.direction);
+            let axis = match self.emit_direction {
+                EmitDirection::Fixed => self.direction,
+                EmitDirection::Normal => normal,
+            };
             let p = Particle {
-                pos: self.pos,
-                dir: normalize(&vec3(random(0.0, 0.3), 1.0, random(0.0, 0.3)))
-                    * random(self.speed, self.speed_spread),
+                pos: self.pos + offset,
+                dir: self.spread_shape.sample(rand, &axis, self.spread)
+                    * rand.spread(self.speed, self.speed_spread),
 
-                size: random(self.size, self.size_spread),
+                size: rand.spread(self.size, self.size_spread),
+                rotation: spread_if_nonzero(rand, self.rotation, self.rotation_spread),
+                angular_velocity: spread_if_nonzero(
+                    rand,
+                    self.angular_velocity,
+                    self.angular_velocity_spread,
+                ),
                 life,
                 inv_initial_life: 1.0 / life,
             };
@@ -147,58 +466,136 @@ impl ParticleSystem {
         }
 
         let friction = self.friction_factor.powf(time);
+        let particles = &mut self.particles;
 
-        self.particles.retain_mut(|p| {
-            p.life -= time;
-            if p.life < 0.0 {
-                return false;
+        // Age the particles, replacing dead particles with the last one
+        let mut i = 0;
+        while i < particles.len() {
+            particles.life[i] -= time;
+            if particles.life[i] < 0.0 {
+                particles.swap_remove(i);
+            } else {
+                i += 1;
             }
+        }
 
-            let mut v = vec3(0.0, 0.0, 0.0);
-            for f in &self.point_forces {
-                let dir = f.pos - p.pos;
-                let dist = dot(&dir, &dir);
-                v += dir
-                    * (f.strength
-                        / (1.0
-                            + dist.sqrt() * f.linear_attenuation
-                            + dist * f.quadratic_attenuation));
+        // Each pass runs over contiguous arrays so the simple ones can be vectorized
+        if self.point_forces.is_empty() {
+            let acceleration = self.directional_force * time;
+            for dir in &mut particles.dir {
+                *dir += acceleration;
+                *dir *= friction;
             }
+        } else {
+            for (dir, pos) in particles.dir.iter_mut().zip(&particles.pos) {
+                let mut v = vec3(0.0, 0.0, 0.0);
+                for f in &self.point_forces {
+                    let dir = f.pos - *pos;
+                    let dist = dot(&dir, &dir);
+                    v += dir
+                        * (f.strength
+                            / (1.0
+                                + dist.sqrt() * f.linear_attenuation
+                                + dist * f.quadratic_attenuation));
+                }
 
-            p.dir += (self.directional_force + v) * time;
-            p.dir *= friction;
+                *dir += (self.directional_force + v) * time;
+                *dir *= friction;
+            }
+        }
 
-            p.pos += p.dir * time;
-            true
-        });
+        for i in 0..particles.len() {
+            let step = time * self.speed_over_life.sample(particles.age(i));
+            particles.pos[i] += particles.dir[i] * step;
+        }
+
+        for (rotation, angular_velocity) in particles
+            .rotation
+            .iter_mut()
+            .zip(&particles.angular_velocity)
+        {
+            *rotation += angular_velocity * time;
+        }
+
+        if self.colliders.is_empty() {
+            return;
+        }
+        let mut i = 0;
+        'particles: while i < particles.len() {
+            for c in &self.colliders {
+                let Some((pos, normal)) = c.shape.resolve(&particles.pos[i]) else {
+                    continue;
+                };
+                if c.kill_on_contact {
+                    particles.swap_remove(i);
+                    continue 'particles;
+                }
+                particles.pos[i] = pos;
+                let dir = &mut particles.dir[i];
+                let normal_speed = dot(dir, &normal);
+                if normal_speed < 0.0 {
+                    let normal_dir = normal * normal_speed;
+                    let tangent_dir = *dir - normal_dir;
+                    let friction = (1.0 - c.friction).powf(time);
+                    *dir = tangent_dir * friction - normal_dir * c.restitution;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    // Kill the particles closest to the end of their life
+    fn kill_oldest(&mut self, count: usize) {
+        let count = count.min(self.particles.len());
+        if count == 0 {
+            return;
+        }
+
+        let order = &mut self.sort_indices;
+        order.clear();
+        order.extend(0..self.particles.len() as u32);
+        if count < order.len() {
+            let particles = &self.particles;
+            order.select_nth_unstable_by(count, |&a, &b| {
+                particles
+                    .age(b as usize)
+                    .total_cmp(&particles.age(a as usize))
+            });
+        }
+
+        // Remove the highest indices first, so the particles moved into the gaps are never ones to remove
+        let oldest = &mut order[..count];
+        oldest.sort_unstable_by(|a, b| b.cmp(a));
+        for &i in oldest.iter() {
+            self.particles.swap_remove(i as usize);
+        }
     }
 
     pub fn update_time(&mut self, time_stamp: f32) {
         self.last_time = time_stamp;
     }
 
+    /// Get the indices of the particle quads.
+    /// Panics if `needs_u32_indices` returns true, use `get_index_array_u32` then.
     pub fn get_index_array(&mut self) -> &[u16] {
-        let old_size = self.index_array.len();
+        assert!(
+            !self.needs_u32_indices(),
+            "Too many particles for u16 indices"
+        );
         let new_size = self.particles.len() * 6;
-
-        if new_size > old_size {
-            self.index_array.reserve(new_size);
-
-            let start_index = (old_size / 6) as u16;
-            let end_index = self.particles.len() as u16;
-            for i in start_index..end_index {
-                self.index_array.push(4 * i);
-                self.index_array.push(4 * i + 1);
-                self.index_array.push(4 * i + 3);
-                self.index_array.push(4 * i + 3);
-                self.index_array.push(4 * i + 1);
-                self.index_array.push(4 * i + 2);
-            }
-        }
-
+        extend_quad_indices(&mut self.index_array, new_size);
+        self.index_array_size = new_size;
         &self.index_array[..new_size] // Only return up to the used size
     }
 
+    /// Get the indices of the particle quads as 32 bit indices
+    pub fn get_index_array_u32(&mut self) -> &[u32] {
+        let new_size = self.particles.len() * 6;
+        extend_quad_indices(&mut self.index_array_u32, new_size);
+        self.index_array_u32_size = new_size;
+        &self.index_array_u32[..new_size] // Only return up to the used size
+    }
+
     unsafe fn copy_to_buffer<T>(buffer: *mut u8, data: T) -> *mut u8
     where
         T: Copy,
@@ -209,6 +606,53 @@ impl ParticleSystem {
         buffer.add(size_of::<T>())
     }
 
+    // Sort the particle indices by distance along the view direction, furthest first.
+    // Particles at the same distance keep their storage order.
+    fn sort_back_to_front(&mut self, view: vec3) {
+        let count = self.particles.len();
+        self.sort_keys.clear();
+        self.sort_keys
+            .extend(self.particles.pos.iter().map(|p| sort_key(dot(p, &view))));
+        self.sort_indices.clear();
+        self.sort_indices.extend(0..count as u32);
+        self.sort_temp_keys.resize(count, 0);
+        self.sort_temp_indices.resize(count, 0);
+
+        // Stable least significant digit radix sort, 8 bits per pass
+        for shift in (0..32).step_by(8) {
+            let digit = |key: u32| ((key >> shift) & 0xff) as usize;
+            let mut offsets = [0usize; 256];
+            for &key in &self.sort_keys {
+                offsets[digit(key)] += 1;
+            }
+            if count == 0 || offsets[digit(self.sort_keys[0])] == count {
+                continue; // All keys have the same digit
+            }
+
+            let mut total = 0;
+            for offset in &mut offsets {
+                let n = *offset;
+                *offset = total;
+                total += n;
+            }
+            for (&key, &index) in self.sort_keys.iter().zip(&self.sort_indices) {
+                let dest = &mut offsets[digit(key)];
+                self.sort_temp_keys[*dest] = key;
+                self.sort_temp_indices[*dest] = index;
+                *dest += 1;
+            }
+            std::mem::swap(&mut self.sort_keys, &mut self.sort_temp_keys);
+            std::mem::swap(&mut self.sort_indices, &mut self.sort_temp_indices);
+        }
+    }
+
+    /// Build the particle quads, oriented by the orientation mode and ordered by the sort mode
+    /// * `dx` - The camera right vector, scaled by the particle size for the quad half width
+    /// * `dy` - The camera up vector, scaled by the particle size for the quad half height.
+    ///   The camera looks along -cross(dx, dy). If dx and dy are parallel there is no view direction,
+    ///   the sort keeps the storage order and the other orientations use camera facing quads.
+    /// * `use_colors` - If the vertices get a colour after the texcoords
+    /// * `tex3d` - If the vertices get the normalized particle age as a third texcoord
     pub fn get_vertex_array(&mut self, dx: vec3, dy: vec3, use_colors: bool, tex3d: bool) -> &[u8] {
         let mut vertex_size = size_of::<vec3>() + size_of::<vec2>();
         if use_colors {
@@ -229,37 +673,46 @@ impl ParticleSystem {
             vec2(1.0, 1.0),
             vec2(0.0, 1.0),
         ];
-        let vect = [-dx + dy, dx + dy, dx - dy, -dx - dy];
+        let view = view_direction(&dx, &dy);
+        if self.sort == ParticleSort::BackToFront {
+            self.sort_back_to_front(view);
+        }
 
-        let mut frac: f32 = 0.0;
         let mut color: vec4 = vec4(0.0, 0.0, 0.0, 0.0);
         let dest_range = self.vertex_array.as_mut_ptr_range();
         let mut dest_ptr = dest_range.start;
-        for p in &self.particles {
-            if use_colors || tex3d {
-                frac = p.life * p.inv_initial_life;
-            }
+        for i in 0..self.particles.len() {
+            let i = match self.sort {
+                ParticleSort::None => i,
+                ParticleSort::BackToFront => self.sort_indices[i] as usize,
+            };
+            let p = &self.particles;
+            let age = p.age(i);
+            let particle_size = p.size[i] * self.size_over_life.sample(age);
+            let (x, y) = quad_axes(
+                self.orientation,
+                p.rotation[i],
+                p.dir[i],
+                particle_size,
+                dx,
+                dy,
+                view,
+            );
+            let vect = [-x + y, x + y, x - y, -x - y];
 
             if use_colors {
-                let mut col_frac = 11.0 * frac;
-                let col_int = col_frac as i32;
-                col_frac -= col_int as f32;
-
-                color = lerp(
-                    &self.colors[col_int as usize],
-                    &self.colors[col_int as usize + 1],
-                    col_frac,
-                );
+                color = self.color_over_life.sample(age);
+                color.w *= self.alpha_over_life.sample(age);
             }
 
             for j in 0..4 {
-                let pos = p.pos + vect[j] * p.size;
+                let pos = p.pos[i] + vect[j];
                 unsafe {
                     dest_ptr = Self::copy_to_buffer(dest_ptr, pos);
                     dest_ptr = Self::copy_to_buffer(dest_ptr, COORDS[j]);
 
                     if tex3d {
-                        dest_ptr = Self::copy_to_buffer(dest_ptr, 1.0 - frac);
+                        dest_ptr = Self::copy_to_buffer(dest_ptr, age);
                     }
 
                     if use_colors {
@@ -270,6 +723,757 @@ impl ParticleSystem {
         }
         debug_assert!(dest_ptr <= dest_range.end);
 
+        self.vertex_array_size = size;
         &self.vertex_array[..size] // Only return up to the used size
     }
+
+    /// The index array from the last `get_index_array` call
+    pub fn last_index_array(&self) -> &[u16] {
+        &self.index_array[..self.index_array_size]
+    }
+
+    /// The index array from the last `get_index_array_u32` call
+    pub fn last_index_array_u32(&self) -> &[u32] {
+        &self.index_array_u32[..self.index_array_u32_size]
+    }
+
+    /// The vertex array from the last `get_vertex_array` call
+    pub fn last_vertex_array(&self) -> &[u8] {
+        &self.vertex_array[..self.vertex_array_size]
+    }
+}
+
+// Extend a quad index array to a size, two triangles per quad. Existing indices are kept.
+fn extend_quad_indices<T>(indices: &mut Vec<T>, size: usize)
+where
+    T: TryFrom<usize>,
+    T::Error: std::fmt::Debug,
+{
+    if size <= indices.len() {
+        return;
+    }
+    indices.reserve(size - indices.len());
+
+    let index = |i: usize| T::try_from(i).expect("Index out of range");
+    for i in indices.len() / 6..size / 6 {
+        for offset in [0, 1, 3, 3, 1, 2] {
+            indices.push(index(4 * i + offset));
+        }
+    }
+}
+
+// GameRand::spread without using a random value when there is no spread. Used for the rotation, so systems
+// without it get the same random sequence as before rotation was added.
+fn spread_if_nonzero(rand: &mut GameRand, mean: f32, spread: f32) -> f32 {
+    if spread == 0.0 {
+        mean
+    } else {
+        rand.spread(mean, spread)
+    }
+}
+
+// Map a float to an integer with the same ordering
+fn sort_key(f: f32) -> u32 {
+    let bits = f.to_bits();
+    if bits & 0x8000_0000 != 0 {
+        !bits
+    } else {
+        bits | 0x8000_0000
+    }
+}
+
+// The normalized direction toward the camera, or 0 if the camera vectors are parallel
+fn view_direction(dx: &vec3, dy: &vec3) -> vec3 {
+    let view = cross(dx, dy);
+    let len = length(&view);
+    if len > f32::EPSILON {
+        view / len
+    } else {
+        vec3(0.0, 0.0, 0.0)
+    }
+}
+
+// The half axes of a particle quad, the texcoords run from -x to +x and from +y to -y
+fn quad_axes(
+    orientation: ParticleOrientation,
+    rotation: f32,
+    velocity: vec3,
+    size: f32,
+    dx: vec3,
+    dy: vec3,
+    view: vec3,
+) -> (vec3, vec3) {
+    let rotate = |x: vec3, y: vec3| {
+        if rotation == 0.0 {
+            return (x, y);
+        }
+        let (s, c) = rotation.sin_cos();
+        (x * c + y * s, y * c - x * s)
+    };
+
+    match orientation {
+        ParticleOrientation::CameraFacing => rotate(dx * size, dy * size),
+        ParticleOrientation::VelocityStretched { stretch } => {
+            let v = velocity - view * dot(&velocity, &view);
+            let speed = length(&v);
+            if speed <= f32::EPSILON {
+                return (dx * size, dy * size);
+            }
+            let y = v / speed;
+            let x = cross(&y, &view);
+            if x == vec3(0.0, 0.0, 0.0) {
+                return (dx * size, dy * size);
+            }
+            (
+                x * (length(&dx) * size),
+                y * (length(&dy) * size + 0.5 * speed * stretch),
+            )
+        }
+        ParticleOrientation::AxisLocked { axis } => {
+            let x = cross(&axis, &view);
+            let len = length(&x);
+            if len <= f32::EPSILON {
+                return (dx * size, dy * size);
+            }
+            (x * (length(&dx) * size / len), axis * (length(&dy) * size))
+        }
+        ParticleOrientation::WorldAligned { normal } => {
+            let (t, b) = orthonormal_basis(&normal);
+            rotate(t * (length(&dx) * size), b * (length(&dy) * size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use crate::curve::Interpolation;
+    use std::f32::consts::PI;
+    use std::hint::black_box;
+
+    const FRAME_TIME: f32 = 1.0 / 60.0;
+
+    // A system like the room light sparks, run until the particle count is steady
+    fn steady_system(spawn_rate: f32, rand: &mut GameRand) -> (ParticleSystem, f32) {
+        let mut system = ParticleSystem::new();
+        system.spawn_rate = spawn_rate;
+        system.speed = 70.0;
+        system.speed_spread = 20.0;
+        system.life = 3.0;
+        system.life_spread = 0.5;
+        system.size = 15.0;
+        system.size_spread = 5.0;
+        system.directional_force = vec3(0.0, -10.0, 0.0);
+        system.set_color_scheme(ColorScheme::Fire);
+
+        let mut time = 0.0;
+        while time < 4.0 {
+            time += FRAME_TIME;
+            system.update(time, rand);
+        }
+        (system, time)
+    }
+
+    // The default spread is the direction particles had before spread shapes were added,
+    // normalize(vec3(spread(0, 0.3), 1, spread(0, 0.3)))
+    #[test]
+    fn default_spread_matches_original() {
+        let system = ParticleSystem::new();
+        let mut rand = GameRand::new(42);
+        let mut original = GameRand::new(42);
+        for _ in 0..1000 {
+            let dir = system
+                .spread_shape
+                .sample(&mut rand, &system.direction, system.spread);
+            let x = original.spread(0.0, 0.3);
+            let z = original.spread(0.0, 0.3);
+            assert_eq!(dir, normalize(&vec3(x, 1.0, z)));
+        }
+    }
+
+    #[test]
+    fn cone_spread_stays_in_cone() {
+        let axis = normalize(&vec3(1.0, -2.0, 0.5));
+        let mut rand = GameRand::new(42);
+        for _ in 0..1000 {
+            let dir = SpreadShape::Cone.sample(&mut rand, &axis, 0.3);
+            assert!((length(&dir) - 1.0).abs() < 1e-5);
+            assert!(dot(&dir, &axis) >= 0.3f32.cos() - 1e-5);
+        }
+    }
+
+    #[test]
+    fn emit_direction() {
+        let mut system = ParticleSystem::new();
+        system.pos = vec3(10.0, -5.0, 3.0);
+        system.spawn_rate = 1000.0;
+        system.spread = 0.0;
+        system.emitter = EmitterShape::Sphere {
+            radius: 2.0,
+            surface: true,
+        };
+
+        // Fixed sends every particle along the system direction wherever it spawns
+        let mut rand = GameRand::new(42);
+        system.update(0.1, &mut rand);
+        assert!(system.get_particle_count() > 50);
+        for dir in &system.particles.dir {
+            assert!(length(&(normalize(dir) - system.direction)) < 1e-5);
+        }
+
+        // Normal sends them straight out from the sphere
+        system.emit_direction = EmitDirection::Normal;
+        system.particles = Particles::with_capacity(0);
+        system.update(0.2, &mut rand);
+        assert!(system.get_particle_count() > 50);
+        for (dir, pos) in system.particles.dir.iter().zip(&system.particles.pos) {
+            let out = normalize(&(*pos - system.pos));
+            assert!(length(&(normalize(dir) - out)) < 1e-4, "{dir:?} {out:?}");
+        }
+    }
+
+    // Without rotation spreads each particle uses the random values the original particles did:
+    // life, two direction offsets, speed and size
+    #[test]
+    fn rotation_without_spread_uses_no_randoms() {
+        let mut system = ParticleSystem::new();
+        system.rotation = 0.5;
+        system.angular_velocity = 2.0;
+        let mut rand = GameRand::new(42);
+        system.update(0.2, &mut rand);
+        assert_eq!(system.get_particle_count(), 2);
+
+        let mut original = GameRand::new(42);
+        for i in 0..2 {
+            let life = original.spread(2.5, 0.5);
+            original.spread(0.0, 0.3);
+            original.spread(0.0, 0.3);
+            original.spread(100.0, 25.0);
+            let size = original.spread(100.0, 10.0);
+            assert_eq!(system.particles.size[i], size);
+            assert!((system.particles.life[i] - (life - 0.2)).abs() < 1e-6);
+        }
+        assert_eq!(rand.next_random(), original.next_random());
+    }
+
+    #[test]
+    fn velocity_stretched_length() {
+        let orientation = ParticleOrientation::VelocityStretched { stretch: 0.5 };
+        let (dx, dy) = (vec3(2.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0));
+        let view = vec3(0.0, 0.0, 1.0);
+        let velocity = vec3(10.0, 0.0, 7.0);
+        let (x, y) = quad_axes(orientation, 0.0, velocity, 3.0, dx, dy, view);
+
+        // The full length is the camera facing length plus the 5 units moved on screen in 0.5 seconds
+        assert!((2.0 * length(&y) - (2.0 * 6.0 + 5.0)).abs() < 1e-5);
+        assert!((normalize(&y).x - 1.0).abs() < 1e-6);
+        assert!((length(&x) - 6.0).abs() < 1e-5);
+
+        // Stopped particles are camera facing
+        let (x, y) = quad_axes(orientation, 0.0, view, 3.0, dx, dy, view);
+        assert_eq!((x, y), (dx * 3.0, dy * 3.0));
+    }
+
+    #[test]
+    fn world_aligned_size() {
+        let (dx, dy) = (vec3(0.0, 0.0, 2.0), vec3(0.0, 1.5, 0.0));
+        let orientation = ParticleOrientation::WorldAligned {
+            normal: vec3(0.0, 1.0, 0.0),
+        };
+        let view = view_direction(&dx, &dy);
+        let facing = quad_axes(
+            ParticleOrientation::CameraFacing,
+            0.0,
+            view,
+            3.0,
+            dx,
+            dy,
+            view,
+        );
+        for rotation in [0.0, 1.0] {
+            let (x, y) = quad_axes(orientation, rotation, view, 3.0, dx, dy, view);
+            assert!(dot(&x, &vec3(0.0, 1.0, 0.0)).abs() < 1e-6);
+            assert!(dot(&y, &vec3(0.0, 1.0, 0.0)).abs() < 1e-6);
+            if rotation == 0.0 {
+                assert!((length(&x) - length(&facing.0)).abs() < 1e-5);
+                assert!((length(&y) - length(&facing.1)).abs() < 1e-5);
+            }
+        }
+    }
+
+    // A system that spawns nothing, with particles moving from the given positions with the given velocities
+    fn moving_particles(particles: &[(vec3, vec3)]) -> ParticleSystem {
+        let mut system = ParticleSystem::new();
+        system.spawn_rate = 0.0;
+        system.friction_factor = 1.0;
+        for &(pos, dir) in particles {
+            system.particles.push(Particle {
+                pos,
+                size: 1.0,
+                rotation: 0.0,
+                dir,
+                angular_velocity: 0.0,
+                life: 10.0,
+                inv_initial_life: 0.1,
+            });
+        }
+        system
+    }
+
+    fn ground(restitution: f32, friction: f32) -> Collider {
+        let plane = Plane::from_point_normal(&vec3(0.0, 0.0, 0.0), &vec3(0.0, 1.0, 0.0));
+        let mut collider = Collider::new(ColliderShape::Plane(plane));
+        collider.restitution = restitution;
+        collider.friction = friction;
+        collider
+    }
+
+    #[test]
+    fn plane_bounce() {
+        let mut system = moving_particles(&[(vec3(0.0, 1.0, 0.0), vec3(3.0, -10.0, 0.0))]);
+        system.colliders.push(ground(0.5, 0.0));
+        system.update(0.2, &mut GameRand::new(1));
+
+        // Moved to (0.6, -1, 0), pushed back onto the plane with half the speed along the normal reflected
+        assert!(length(&(system.particles.pos[0] - vec3(0.6, 0.0, 0.0))) < 1e-5);
+        assert!(length(&(system.particles.dir[0] - vec3(3.0, 5.0, 0.0))) < 1e-5);
+    }
+
+    #[test]
+    fn sliding_friction_is_frame_rate_independent() {
+        for frames in [30, 60, 144] {
+            let mut system = moving_particles(&[(vec3(0.0, 0.0, 0.0), vec3(10.0, 0.0, 0.0))]);
+            system.directional_force = vec3(0.0, -10.0, 0.0);
+            system.colliders.push(ground(0.0, 0.5));
+            let mut rand = GameRand::new(1);
+            for frame in 1..=frames {
+                system.update(frame as f32 / frames as f32, &mut rand);
+            }
+            // Half the speed is removed by a second of contact
+            let speed = system.particles.dir[0].x;
+            assert!((speed - 5.0).abs() < 1e-3, "{frames} fps: {speed}");
+        }
+    }
+
+    #[test]
+    fn inside_box_keeps_particles_in_room() {
+        let room = Aabb::new(vec3(-10.0, 0.0, -10.0), vec3(10.0, 20.0, 10.0));
+        let mut system = ParticleSystem::new();
+        system.pos = vec3(0.0, 10.0, 0.0);
+        system.spawn_rate = 200.0;
+        system.speed = 50.0;
+        system.spread = PI;
+        system.spread_shape = SpreadShape::Cone;
+        system.directional_force = vec3(0.0, -10.0, 0.0);
+        system.colliders.push(Collider::new(ColliderShape::Aabb {
+            aabb: room,
+            inside: true,
+        }));
+
+        let mut rand = GameRand::new(1);
+        let mut time = 0.0;
+        for _ in 0..120 {
+            time += FRAME_TIME;
+            system.update(time, &mut rand);
+            for pos in &system.particles.pos {
+                assert!(room.contains_point(pos), "{pos:?}");
+            }
+        }
+        assert!(system.get_particle_count() > 100);
+    }
+
+    #[test]
+    fn kill_on_contact() {
+        let still = vec3(0.0, 0.0, 0.0);
+        let mut system = moving_particles(&[
+            (vec3(1.0, 1.0, 0.0), still),
+            (vec3(2.0, -1.0, 0.0), still),
+            (vec3(3.0, -1.0, 0.0), still),
+            (vec3(4.0, 1.0, 0.0), still),
+            (vec3(5.0, -1.0, 0.0), still),
+            (vec3(6.0, 1.0, 0.0), still),
+        ]);
+        let mut collider = ground(0.5, 0.5);
+        collider.kill_on_contact = true;
+        system.colliders.push(collider);
+        system.update(0.1, &mut GameRand::new(1));
+
+        // Particles swapped into the place of killed ones are tested too
+        let mut x: Vec<f32> = system.particles.pos.iter().map(|p| p.x).collect();
+        x.sort_by(f32::total_cmp);
+        assert_eq!(x, [1.0, 4.0, 6.0]);
+        assert_eq!(system.particles.life.len(), 3);
+    }
+
+    #[test]
+    fn sort_key_order() {
+        let values = [
+            f32::NEG_INFINITY,
+            -1e30,
+            -2.5,
+            -1.0,
+            -f32::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f32::MIN_POSITIVE,
+            1.0,
+            2.5,
+            1e30,
+            f32::INFINITY,
+        ];
+        for pair in values.windows(2) {
+            assert!(sort_key(pair[0]) < sort_key(pair[1]), "{pair:?}");
+        }
+
+        let mut rand = GameRand::new(46);
+        for _ in 0..10000 {
+            let a = rand.rand_range_f32(-1000.0, 1000.0);
+            let b = rand.rand_range_f32(-1000.0, 1000.0);
+            assert_eq!(sort_key(a).cmp(&sort_key(b)), a.total_cmp(&b));
+        }
+    }
+
+    // Positions with many equal distances, negative, zero and positive, to test the order and the stability
+    fn sort_test_system(count: usize, rand: &mut GameRand) -> ParticleSystem {
+        let particles: Vec<(vec3, vec3)> = (0..count)
+            .map(|_| {
+                let z = rand.rand_range_i32(-300, 300) as f32 * 0.25;
+                let pos = vec3(rand.rand_range_f32(-9.0, 9.0), 0.0, z);
+                (pos, vec3(0.0, 0.0, 0.0))
+            })
+            .collect();
+        let mut system = moving_particles(&particles);
+        system.sort = ParticleSort::BackToFront;
+        system
+    }
+
+    #[test]
+    fn back_to_front_order_is_stable() {
+        let mut rand = GameRand::new(46);
+        let (dx, dy) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        let view = view_direction(&dx, &dy);
+        for count in [0, 1, 2, 17, 1000] {
+            let mut system = sort_test_system(count, &mut rand);
+            system.sort_back_to_front(view);
+
+            // Furthest from the camera first, equal distances in storage order
+            let distance = |i: u32| dot(&system.particles.pos[i as usize], &view);
+            let mut expected: Vec<u32> = (0..count as u32).collect();
+            expected.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+            assert_eq!(system.sort_indices, expected);
+        }
+    }
+
+    #[test]
+    fn parallel_camera_vectors() {
+        let mut system = sort_test_system(100, &mut GameRand::new(46));
+        system.orientation = ParticleOrientation::VelocityStretched { stretch: 0.1 };
+        let dx = vec3(0.0, 1.0, 2.0);
+        let vertices = system.get_vertex_array(dx, dx, false, false);
+        for bytes in vertices.chunks(4) {
+            assert!(f32::from_le_bytes(bytes.try_into().unwrap()).is_finite());
+        }
+        assert_eq!(system.sort_indices, (0..100).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_sort() {
+        let count = crate::MAX_PFX_PARTICLES as usize;
+        let mut system = sort_test_system(count, &mut GameRand::new(46));
+        let (dx, dy) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 0.8, 0.6));
+        let view = view_direction(&dx, &dy);
+        bench(
+            &format!("sort_back_to_front, {count} particles"),
+            1000,
+            || {
+                system.sort_back_to_front(black_box(view));
+            },
+        );
+        for sort in [ParticleSort::None, ParticleSort::BackToFront] {
+            system.sort = sort;
+            bench(
+                &format!("get_vertex_array {sort:?}, {count} particles"),
+                1000,
+                || {
+                    black_box(system.get_vertex_array(dx, dy, true, true));
+                },
+            );
+        }
+    }
+
+    // A straightforward array of structures update to check the passes over the arrays against.
+    // Does not support the particle limits.
+    struct ReferenceSystem {
+        particles: Vec<Particle>,
+        particle_credit: f32,
+        last_time: f32,
+    }
+
+    impl ReferenceSystem {
+        fn update(&mut self, system: &ParticleSystem, time_stamp: f32, rand: &mut GameRand) {
+            let time = time_stamp - self.last_time;
+            self.last_time = time_stamp;
+            self.particle_credit += time * system.spawn_rate;
+            let len = self.particle_credit as usize;
+            self.particle_credit -= len as f32;
+
+            for _ in 0..len {
+                let life = rand.spread(system.life, system.life_spread);
+                let (offset, normal) = system.emitter.sample(rand, &system.direction);
+                let axis = match system.emit_direction {
+                    EmitDirection::Fixed => system.direction,
+                    EmitDirection::Normal => normal,
+                };
+                let dir = system.spread_shape.sample(rand, &axis, system.spread);
+                let speed = rand.spread(system.speed, system.speed_spread);
+                let size = rand.spread(system.size, system.size_spread);
+                let rotation = spread_if_nonzero(rand, system.rotation, system.rotation_spread);
+                let angular_velocity = spread_if_nonzero(
+                    rand,
+                    system.angular_velocity,
+                    system.angular_velocity_spread,
+                );
+                self.particles.push(Particle {
+                    pos: system.pos + offset,
+                    size,
+                    rotation,
+                    dir: dir * speed,
+                    angular_velocity,
+                    life,
+                    inv_initial_life: 1.0 / life,
+                });
+            }
+
+            let friction = system.friction_factor.powf(time);
+            self.particles.retain_mut(|p| {
+                p.life -= time;
+                if p.life < 0.0 {
+                    return false;
+                }
+
+                let mut force = vec3(0.0, 0.0, 0.0);
+                for f in &system.point_forces {
+                    let d = f.pos - p.pos;
+                    let dist = dot(&d, &d);
+                    force += d
+                        * (f.strength
+                            / (1.0
+                                + dist.sqrt() * f.linear_attenuation
+                                + dist * f.quadratic_attenuation));
+                }
+                p.dir += (system.directional_force + force) * time;
+                p.dir *= friction;
+
+                let age = 1.0 - p.life * p.inv_initial_life;
+                p.pos += p.dir * (time * system.speed_over_life.sample(age));
+                p.rotation += p.angular_velocity * time;
+
+                for c in &system.colliders {
+                    let Some((pos, normal)) = c.shape.resolve(&p.pos) else {
+                        continue;
+                    };
+                    if c.kill_on_contact {
+                        return false;
+                    }
+                    p.pos = pos;
+                    let normal_speed = dot(&p.dir, &normal);
+                    if normal_speed < 0.0 {
+                        let normal_dir = normal * normal_speed;
+                        let tangent_dir = p.dir - normal_dir;
+                        let friction = (1.0 - c.friction).powf(time);
+                        p.dir = tangent_dir * friction - normal_dir * c.restitution;
+                    }
+                }
+                true
+            });
+        }
+
+        // The particle values as bits, sorted, as the two versions remove particles in different orders
+        fn sorted_bits(&self) -> Vec<[u32; 10]> {
+            let mut bits: Vec<[u32; 10]> = self
+                .particles
+                .iter()
+                .map(|p| {
+                    particle_bits(p.pos, p.dir, p.size, p.rotation, p.angular_velocity, p.life)
+                })
+                .collect();
+            bits.sort();
+            bits
+        }
+    }
+
+    fn particle_bits(
+        pos: vec3,
+        dir: vec3,
+        size: f32,
+        rotation: f32,
+        angular_velocity: f32,
+        life: f32,
+    ) -> [u32; 10] {
+        [
+            pos.x,
+            pos.y,
+            pos.z,
+            dir.x,
+            dir.y,
+            dir.z,
+            size,
+            rotation,
+            angular_velocity,
+            life,
+        ]
+        .map(f32::to_bits)
+    }
+
+    fn sorted_bits(system: &ParticleSystem) -> Vec<[u32; 10]> {
+        let p = &system.particles;
+        let mut bits: Vec<[u32; 10]> = (0..p.len())
+            .map(|i| {
+                particle_bits(
+                    p.pos[i],
+                    p.dir[i],
+                    p.size[i],
+                    p.rotation[i],
+                    p.angular_velocity[i],
+                    p.life[i],
+                )
+            })
+            .collect();
+        bits.sort();
+        bits
+    }
+
+    // Sparks with every per particle feature: forces, curves, rotation and bouncing and killing colliders
+    fn reference_test_system(point_forces: bool) -> ParticleSystem {
+        let mut system = ParticleSystem::new();
+        system.spawn_rate = 500.0;
+        system.spread = 2.0;
+        system.spread_shape = SpreadShape::Cone;
+        system.friction_factor = 0.8;
+        system.directional_force = vec3(0.0, -40.0, 10.0);
+        system.rotation_spread = 3.0;
+        system.angular_velocity = 1.0;
+        system.angular_velocity_spread = 0.5;
+        system.speed_over_life = Curve::new(&[(0.0, 1.0), (1.0, 0.2)], Interpolation::Smooth);
+        if point_forces {
+            system
+                .point_forces
+                .push(PointForce::new(vec3(30.0, 50.0, 0.0), 20.0, 0.01, 0.001));
+        }
+        system.colliders.push(ground(0.5, 0.3));
+        let mut sphere = Collider::new(ColliderShape::Sphere {
+            sphere: Sphere::new(vec3(0.0, 40.0, 0.0), 15.0),
+            inside: false,
+        });
+        sphere.kill_on_contact = true;
+        system.colliders.push(sphere);
+        system
+    }
+
+    #[test]
+    fn update_matches_reference() {
+        for point_forces in [false, true] {
+            let mut system = reference_test_system(point_forces);
+            let mut reference = ReferenceSystem {
+                particles: Vec::new(),
+                particle_credit: 0.0,
+                last_time: 0.0,
+            };
+            let mut rand = GameRand::new(47);
+            let mut reference_rand = GameRand::new(47);
+            let mut time = 0.0;
+            for _ in 0..300 {
+                time += FRAME_TIME;
+                system.update(time, &mut rand);
+                reference.update(&system, time, &mut reference_rand);
+                assert_eq!(sorted_bits(&system), reference.sorted_bits());
+            }
+            assert!(system.get_particle_count() > 100);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_update_vs_reference() {
+        let mut system = reference_test_system(true);
+        let params = reference_test_system(true);
+        let mut reference = ReferenceSystem {
+            particles: Vec::new(),
+            particle_credit: 0.0,
+            last_time: 0.0,
+        };
+        let mut rand = GameRand::new(47);
+        let mut time = 0.0;
+        while time < 4.0 {
+            time += FRAME_TIME;
+            system.update(time, &mut rand);
+            reference.update(&params, time, &mut rand);
+        }
+
+        let count = system.get_particle_count();
+        let mut system_time = time;
+        bench(&format!("update, {count} particles"), 300, || {
+            system_time += FRAME_TIME;
+            system.update(system_time, &mut rand);
+        });
+        let count = reference.particles.len();
+        bench(&format!("reference update, {count} particles"), 300, || {
+            time += FRAME_TIME;
+            reference.update(&params, time, &mut rand);
+        });
+    }
+
+    fn still_particles(count: usize) -> ParticleSystem {
+        let particles = vec![(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0)); count];
+        moving_particles(&particles)
+    }
+
+    #[test]
+    fn index_arrays() {
+        let mut system = still_particles(MAX_U16_PARTICLES);
+        assert!(!system.needs_u32_indices());
+        let indices = system.get_index_array();
+        assert_eq!(indices.len(), MAX_U16_PARTICLES * 6);
+        assert_eq!(indices[..6], [0, 1, 3, 3, 1, 2]);
+        assert_eq!(
+            *indices.iter().max().unwrap() as usize,
+            MAX_U16_PARTICLES * 4 - 1
+        );
+
+        let mut system = still_particles(MAX_U16_PARTICLES + 1);
+        assert!(system.needs_u32_indices());
+        let indices = system.get_index_array_u32();
+        assert_eq!(indices.len(), (MAX_U16_PARTICLES + 1) * 6);
+        assert_eq!(
+            *indices.last().unwrap() as usize,
+            (MAX_U16_PARTICLES + 1) * 4 - 2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many particles for u16 indices")]
+    fn too_many_particles_for_u16_indices() {
+        still_particles(MAX_U16_PARTICLES + 1).get_index_array();
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_update_and_vertices() {
+        let dx = vec3(1.0, 0.0, 0.0);
+        let dy = vec3(0.0, 1.0, 0.0);
+        for spawn_rate in [400.0, 4000.0] {
+            let mut rand = GameRand::new(1);
+            let (mut system, mut time) = steady_system(spawn_rate, &mut rand);
+            let count = system.get_particle_count();
+            bench(&format!("update, {count} particles"), 100, || {
+                time += FRAME_TIME;
+                system.update(time, &mut rand);
+            });
+            bench(&format!("get_vertex_array, {count} particles"), 100, || {
+                black_box(system.get_vertex_array(dx, dy, true, true));
+            });
+        }
+    }
 }
@@ -0,0 +1,225 @@
+// Statistical quality tests for GameRand, only built for tests.
+// Each test computes a statistic that is approximately standard normal for a good generator.
+// The known answers for the generator output are unit tests in game_rand.
+
+use crate::game_rand::GameRand;
+
+// Statistics outside this many standard deviations fail. Seeds are fixed, so results are repeatable.
+pub const Z_LIMIT: f64 = 4.0;
+const SAMPLE_COUNT: usize = 1_000_000;
+const SEED: u32 = 12345;
+
+// Returns a statistic that is approximately standard normal for a good generator
+type StatTest = fn(&mut GameRand) -> f64;
+
+fn check(test: StatTest) {
+    let z = test(&mut GameRand::new(SEED));
+    assert!(z.abs() < Z_LIMIT, "z = {z}");
+}
+
+macro_rules! stat_tests {
+    ($($test:ident => $stat:ident),+ $(,)?) => {
+        $(
+            #[test]
+            fn $test() {
+                check($stat);
+            }
+        )+
+    };
+}
+
+stat_tests! {
+    uniform_high_bits => chi_square_high_bits,
+    uniform_low_bits => chi_square_low_bits,
+    uniform_random01 => chi_square_random01,
+    uniform_range_small => chi_square_range_small,
+    uniform_range_large => chi_square_range_large,
+    uniform_range_i32 => chi_square_range_i32,
+    no_serial_correlation => serial_correlation,
+    gap_lengths => gap,
+    birthday_spacing_repeats => birthday_spacings,
+    runs_up_and_down => runs_up_down,
+    mean_and_range_random01_f64 => mean_random01_f64,
+}
+
+// -------------------------------------------------------------------------------------------
+
+// Convert a chi-square statistic to an approximately normal z-score (Wilson-Hilferty)
+fn chi_square_z(chi_square: f64, degrees_of_freedom: usize) -> f64 {
+    let k = degrees_of_freedom as f64;
+    let v = 2.0 / (9.0 * k);
+    ((chi_square / k).cbrt() - (1.0 - v)) / v.sqrt()
+}
+
+/// Chi-square z-score of observed bucket counts against expected probabilities
+pub fn chi_square_test(observed: &[u64], probabilities: &[f64]) -> f64 {
+    let total: u64 = observed.iter().sum();
+    let chi_square: f64 = observed
+        .iter()
+        .zip(probabilities)
+        .map(|(&o, &p)| {
+            let e = p * total as f64;
+            (o as f64 - e) * (o as f64 - e) / e
+        })
+        .sum();
+    chi_square_z(chi_square, observed.len() - 1)
+}
+
+fn chi_square_uniform<F: FnMut() -> usize>(bucket_count: usize, mut bucket: F) -> f64 {
+    let mut counts = vec![0u64; bucket_count];
+    for _ in 0..SAMPLE_COUNT {
+        counts[bucket()] += 1;
+    }
+    chi_square_test(&counts, &vec![1.0 / bucket_count as f64; bucket_count])
+}
+
+fn chi_square_high_bits(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(256, || (rand.next_random() >> 24) as usize)
+}
+
+fn chi_square_low_bits(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(256, || (rand.next_random() & 0xff) as usize)
+}
+
+fn chi_square_random01(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(1000, || (rand.next_random01() * 1000.0) as usize)
+}
+
+fn chi_square_range_small(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(10, || rand.rand_range(5, 14) as usize - 5)
+}
+
+// A range of 3 * 2^30, where taking the modulo would make the lowest third twice as likely
+fn chi_square_range_large(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(3, || (rand.rand_range(0, (3 << 30) - 1) >> 30) as usize)
+}
+
+fn chi_square_range_i32(rand: &mut GameRand) -> f64 {
+    chi_square_uniform(101, || (rand.rand_range_i32(-50, 50) + 50) as usize)
+}
+
+// Lag 1 correlation of next_random01, scaled by sqrt(n) to be approximately standard normal
+fn serial_correlation(rand: &mut GameRand) -> f64 {
+    let n = SAMPLE_COUNT;
+    let first = rand.next_random01() as f64;
+    let mut prev = first;
+    let (mut sum, mut sum_sq, mut sum_prod) = (0.0, 0.0, 0.0);
+    for i in 0..n {
+        // Wrap around at the end so each value is used twice
+        let val = if i + 1 < n {
+            rand.next_random01() as f64
+        } else {
+            first
+        };
+        sum += prev;
+        sum_sq += prev * prev;
+        sum_prod += prev * val;
+        prev = val;
+    }
+    let n_f = n as f64;
+    let r = (n_f * sum_prod - sum * sum) / (n_f * sum_sq - sum * sum);
+    r * n_f.sqrt()
+}
+
+// Lengths of the gaps between values in [0.25, 0.5), against the geometric distribution (Knuth 3.3.2 D)
+fn gap(rand: &mut GameRand) -> f64 {
+    const MAX_GAP: usize = 16;
+    const P: f64 = 0.25;
+
+    let mut counts = [0u64; MAX_GAP + 1];
+    for _ in 0..SAMPLE_COUNT / 4 {
+        let mut length = 0;
+        loop {
+            let val = rand.next_random01();
+            if (0.25..0.5).contains(&val) {
+                break;
+            }
+            length += 1;
+        }
+        counts[length.min(MAX_GAP)] += 1;
+    }
+
+    let mut probabilities = [0.0; MAX_GAP + 1];
+    for (r, p) in probabilities.iter_mut().enumerate() {
+        *p = if r < MAX_GAP {
+            P * (1.0 - P).powi(r as i32)
+        } else {
+            (1.0 - P).powi(MAX_GAP as i32)
+        };
+    }
+    chi_square_test(&counts, &probabilities)
+}
+
+// Marsaglia's birthday spacings test: 512 birthdays in a year of 2^24 days.
+// The number of repeated spacings between sorted birthdays is approximately Poisson with mean 2.
+fn birthday_spacings(rand: &mut GameRand) -> f64 {
+    const BIRTHDAYS: usize = 512;
+    const TRIALS: usize = 2000;
+    const MAX_REPEATS: usize = 6;
+    const LAMBDA: f64 = (BIRTHDAYS * BIRTHDAYS * BIRTHDAYS) as f64 / (4.0 * (1 << 24) as f64);
+
+    let mut counts = [0u64; MAX_REPEATS + 1];
+    let mut days = [0u32; BIRTHDAYS];
+    let mut spacings = [0u32; BIRTHDAYS];
+    for _ in 0..TRIALS {
+        for day in &mut days {
+            *day = rand.next_random() >> 8;
+        }
+        days.sort_unstable();
+        spacings[0] = days[0];
+        for i in 1..BIRTHDAYS {
+            spacings[i] = days[i] - days[i - 1];
+        }
+        spacings.sort_unstable();
+        let repeats = spacings.windows(2).filter(|s| s[0] == s[1]).count();
+        counts[repeats.min(MAX_REPEATS)] += 1;
+    }
+
+    let mut probabilities = [0.0; MAX_REPEATS + 1];
+    let mut p = (-LAMBDA).exp();
+    let mut total = 0.0;
+    for (k, prob) in probabilities.iter_mut().take(MAX_REPEATS).enumerate() {
+        *prob = p;
+        total += p;
+        p *= LAMBDA / (k + 1) as f64;
+    }
+    probabilities[MAX_REPEATS] = 1.0 - total;
+    chi_square_test(&counts, &probabilities)
+}
+
+// Number of ascending and descending runs, mean (2n - 1) / 3 and variance (16n - 29) / 90
+fn runs_up_down(rand: &mut GameRand) -> f64 {
+    let n = SAMPLE_COUNT;
+    let mut runs = 1;
+    let mut prev = rand.next_random01();
+    let mut prev_up = None;
+    for _ in 1..n {
+        let val = rand.next_random01();
+        if val != prev {
+            let up = val > prev;
+            if prev_up.is_some_and(|p| p != up) {
+                runs += 1;
+            }
+            prev_up = Some(up);
+        }
+        prev = val;
+    }
+    let n_f = n as f64;
+    let mean = (2.0 * n_f - 1.0) / 3.0;
+    let variance = (16.0 * n_f - 29.0) / 90.0;
+    (runs as f64 - mean) / variance.sqrt()
+}
+
+// Mean of next_random01_f64, the variance of a uniform value is 1/12
+fn mean_random01_f64(rand: &mut GameRand) -> f64 {
+    let mut sum = 0.0;
+    for _ in 0..SAMPLE_COUNT {
+        let val = rand.next_random01_f64();
+        if !(0.0..1.0).contains(&val) {
+            return f64::INFINITY;
+        }
+        sum += val;
+    }
+    let n = SAMPLE_COUNT as f64;
+    (sum / n - 0.5) / (1.0 / (12.0 * n)).sqrt()
+}
@@ -8,6 +8,7 @@ use windows_sys::Win32::Foundation::{HINSTANCE, PROC};
 use windows_sys::Win32::System::LibraryLoader::{FreeLibrary, GetProcAddress, LoadLibraryA};
 
 use crate::enum_sequential;
+use crate::vector::vec4;
 use crate::EnumLoadError;
 
 #[derive(Default, Clone, Copy)]
@@ -48,16 +49,33 @@ pub struct sg_color {
     a: f32,
 }
 
+impl From<vec4> for sg_color {
+    fn from(c: vec4) -> Self {
+        sg_color {
+            r: c.x,
+            g: c.y,
+            b: c.z,
+            a: c.w,
+        }
+    }
+}
+
+impl From<sg_color> for vec4 {
+    fn from(c: sg_color) -> Self {
+        vec4(c.r, c.g, c.b, c.a)
+    }
+}
+
 const SG_INVALID_SLOT_INDEX: u32 = 0;
 const SG_INVALID_ID: u32 = 0;
 const SG_NUM_SHADER_STAGES: u32 = 2;
 const SG_NUM_INFLIGHT_FRAMES: u32 = 2;
 const SG_MAX_COLOR_ATTACHMENTS: u32 = 4;
-const SG_MAX_SHADERSTAGE_BUFFERS: u32 = 8;
+pub const SG_MAX_SHADERSTAGE_BUFFERS: u32 = 8;
 const SG_MAX_SHADERSTAGE_IMAGES: u32 = 12;
 const SG_MAX_SHADERSTAGE_UBS: u32 = 4;
 const SG_MAX_UB_MEMBERS: u32 = 16;
-const SG_MAX_VERTEX_ATTRIBUTES: u32 = 16; /* NOTE: actual max vertex attrs can be less on GLES2, see sg_limits! */
+pub const SG_MAX_VERTEX_ATTRIBUTES: u32 = 16; /* NOTE: actual max vertex attrs can be less on GLES2, see sg_limits! */
 const SG_MAX_MIPMAPS: u32 = 16;
 const SG_MAX_TEXTUREARRAY_LAYERS: u32 = 128;
 
@@ -433,8 +451,8 @@ enum_sequential! {
 const SG_UNIFORMTYPE_NUM: u32 = sg_uniform_type::len() as u32;
 
 enum_sequential! {
-    #[derive(Default, Clone, Copy)]
-    enum sg_vertex_step {
+    #[derive(Default, Clone, Copy, PartialEq, Debug)]
+    pub enum sg_vertex_step {
         #[default]
         DEFAULT,     /* value 0 reserved for default-init */
         PER_VERTEX,
@@ -467,8 +485,8 @@ enum_sequential! {
 const SG_PRIMITIVETYPE_NUM: u32 = sg_primitive_type::len() as u32;
 
 enum_sequential! {
-    #[derive(Default, Clone, Copy)]
-    enum sg_vertex_format {
+    #[derive(Default, Clone, Copy, PartialEq, Debug)]
+    pub enum sg_vertex_format {
         #[default]
         INVALID,
         FLOAT,
@@ -777,23 +795,23 @@ struct sg_shader_stage_t {
 }
 
 #[derive(Default, Clone, Copy)]
-struct sg_buffer_layout_desc {
-    stride: i32,
-    step_func: sg_vertex_step,
-    step_rate: i32,
+pub struct sg_buffer_layout_desc {
+    pub stride: i32,
+    pub step_func: sg_vertex_step,
+    pub step_rate: i32,
 }
 
 #[derive(Default, Clone, Copy)]
-struct sg_vertex_attr_desc {
-    buffer_index: i32,
-    offset: i32,
-    format: sg_vertex_format,
+pub struct sg_vertex_attr_desc {
+    pub buffer_index: i32,
+    pub offset: i32,
+    pub format: sg_vertex_format,
 }
 
 #[derive(Default, Clone, Copy)]
-struct sg_layout_desc {
-    buffers: [sg_buffer_layout_desc; SG_MAX_SHADERSTAGE_BUFFERS as usize],
-    attrs: [sg_vertex_attr_desc; SG_MAX_VERTEX_ATTRIBUTES as usize],
+pub struct sg_layout_desc {
+    pub buffers: [sg_buffer_layout_desc; SG_MAX_SHADERSTAGE_BUFFERS as usize],
+    pub attrs: [sg_vertex_attr_desc; SG_MAX_VERTEX_ATTRIBUTES as usize],
 }
 
 #[derive(Default, Clone, Copy)]
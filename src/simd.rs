@@ -0,0 +1,269 @@
+// SSE implementation of the vec4 and mat4 arithmetic, see vector::scalar for the reference version.
+// SSE2 is part of the x86_64 baseline, so the intrinsics are always safe to call here.
+// The AVX mat4 multiply is used when compiled with the avx target feature.
+
+use std::arch::x86_64::*;
+
+use crate::vector::{mat4, vec4};
+
+#[inline(always)]
+fn load(v: &vec4) -> __m128 {
+    // vec4 is repr(C) with 4 consecutive f32s
+    unsafe { _mm_loadu_ps(v as *const vec4 as *const f32) }
+}
+
+#[inline(always)]
+fn store(r: __m128) -> vec4 {
+    let mut ret = vec4(0.0, 0.0, 0.0, 0.0);
+    unsafe { _mm_storeu_ps(&mut ret as *mut vec4 as *mut f32, r) };
+    ret
+}
+
+#[inline(always)]
+pub fn add(a: vec4, b: vec4) -> vec4 {
+    unsafe { store(_mm_add_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn sub(a: vec4, b: vec4) -> vec4 {
+    unsafe { store(_mm_sub_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn mul(a: vec4, b: vec4) -> vec4 {
+    unsafe { store(_mm_mul_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn div(a: vec4, b: vec4) -> vec4 {
+    unsafe { store(_mm_div_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn neg(a: vec4) -> vec4 {
+    unsafe { store(_mm_xor_ps(load(&a), _mm_set1_ps(-0.0))) }
+}
+
+#[inline(always)]
+pub fn dot(a: vec4, b: vec4) -> f32 {
+    // Sum in the same order as the scalar version: (x + y) + (z + w)
+    unsafe {
+        let m = _mm_mul_ps(load(&a), load(&b));
+        let pairs = _mm_add_ps(m, _mm_shuffle_ps::<0b10_11_00_01>(m, m));
+        let sum = _mm_add_ss(pairs, _mm_movehl_ps(pairs, pairs));
+        _mm_cvtss_f32(sum)
+    }
+}
+
+#[inline(always)]
+pub fn min(a: vec4, b: vec4) -> vec4 {
+    // A NaN in either gives `b`, the scalar version matches this rather than f32::min
+    unsafe { store(_mm_min_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn max(a: vec4, b: vec4) -> vec4 {
+    unsafe { store(_mm_max_ps(load(&a), load(&b))) }
+}
+
+#[inline(always)]
+pub fn abs(a: vec4) -> vec4 {
+    unsafe { store(_mm_andnot_ps(_mm_set1_ps(-0.0), load(&a))) }
+}
+
+#[inline(always)]
+fn mul_cols(c: &[__m128; 4], v: __m128) -> __m128 {
+    unsafe {
+        let x = _mm_shuffle_ps::<0b00_00_00_00>(v, v);
+        let y = _mm_shuffle_ps::<0b01_01_01_01>(v, v);
+        let z = _mm_shuffle_ps::<0b10_10_10_10>(v, v);
+        let w = _mm_shuffle_ps::<0b11_11_11_11>(v, v);
+        _mm_add_ps(
+            _mm_add_ps(_mm_mul_ps(c[0], x), _mm_mul_ps(c[1], y)),
+            _mm_add_ps(_mm_mul_ps(c[2], z), _mm_mul_ps(c[3], w)),
+        )
+    }
+}
+
+#[inline(always)]
+pub fn mat4_mul_vec4(m: &mat4, v: vec4) -> vec4 {
+    let c = m.cols.each_ref().map(load);
+    store(mul_cols(&c, load(&v)))
+}
+
+#[cfg(not(target_feature = "avx"))]
+pub fn mat4_mul_mat4(a: &mat4, b: &mat4) -> mat4 {
+    let c = a.cols.each_ref().map(load);
+    let cols = b.cols.each_ref().map(|col| store(mul_cols(&c, load(col))));
+    mat4 { cols }
+}
+
+#[cfg(target_feature = "avx")]
+pub fn mat4_mul_mat4(a: &mat4, b: &mat4) -> mat4 {
+    // Two result columns per 256 bit register
+    let mut ret = mat4::IDENTITY;
+    unsafe {
+        let src = b.cols.as_ptr() as *const f32;
+        let dst = ret.cols.as_mut_ptr() as *mut f32;
+        let c = a.cols.each_ref().map(|col| {
+            let c = load(col);
+            _mm256_set_m128(c, c)
+        });
+        for i in 0..2 {
+            let v = _mm256_loadu_ps(src.add(i * 8));
+            let x = _mm256_shuffle_ps::<0b00_00_00_00>(v, v);
+            let y = _mm256_shuffle_ps::<0b01_01_01_01>(v, v);
+            let z = _mm256_shuffle_ps::<0b10_10_10_10>(v, v);
+            let w = _mm256_shuffle_ps::<0b11_11_11_11>(v, v);
+            let r = _mm256_add_ps(
+                _mm256_add_ps(_mm256_mul_ps(c[0], x), _mm256_mul_ps(c[1], y)),
+                _mm256_add_ps(_mm256_mul_ps(c[2], z), _mm256_mul_ps(c[3], w)),
+            );
+            _mm256_storeu_ps(dst.add(i * 8), r);
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use crate::game_rand::GameRand;
+    use crate::vector::scalar;
+    use std::hint::black_box;
+
+    // Bits of the components. NaNs are all mapped to one value, the compiler does not keep NaN signs and payloads.
+    fn bits(v: vec4) -> [u32; 4] {
+        [v.x, v.y, v.z, v.w].map(float_bits)
+    }
+
+    fn float_bits(f: f32) -> u32 {
+        if f.is_nan() {
+            u32::MAX
+        } else {
+            f.to_bits()
+        }
+    }
+
+    fn mat_bits(m: &mat4) -> [[u32; 4]; 4] {
+        m.cols.map(bits)
+    }
+
+    // Special values in every lane position, followed by random values
+    fn test_vectors() -> Vec<vec4> {
+        let special = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.5,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NAN,
+            1e-40,
+        ];
+        let mut ret = Vec::new();
+        for (i, &a) in special.iter().enumerate() {
+            for &b in &special[i..] {
+                ret.push(vec4(a, b, -a, 2.0));
+                ret.push(vec4(b, 3.0, a, -b));
+            }
+        }
+        let mut rand = GameRand::new(33);
+        for _ in 0..200 {
+            let mut r = || rand.rand_range_f32(-100.0, 100.0);
+            ret.push(vec4(r(), r(), r(), r()));
+        }
+        ret
+    }
+
+    #[test]
+    fn matches_scalar() {
+        let vectors = test_vectors();
+        for &a in &vectors {
+            assert_eq!(bits(neg(a)), bits(scalar::neg(a)));
+            assert_eq!(bits(abs(a)), bits(scalar::abs(a)));
+            for &b in &vectors {
+                assert_eq!(bits(add(a, b)), bits(scalar::add(a, b)));
+                assert_eq!(bits(sub(a, b)), bits(scalar::sub(a, b)));
+                assert_eq!(bits(mul(a, b)), bits(scalar::mul(a, b)));
+                assert_eq!(bits(div(a, b)), bits(scalar::div(a, b)));
+                assert_eq!(bits(min(a, b)), bits(scalar::min(a, b)), "{a:?} {b:?}");
+                assert_eq!(bits(max(a, b)), bits(scalar::max(a, b)), "{a:?} {b:?}");
+                assert_eq!(float_bits(dot(a, b)), float_bits(scalar::dot(a, b)));
+            }
+        }
+    }
+
+    #[test]
+    fn matrices_match_scalar() {
+        let vectors = test_vectors();
+        for cols in vectors.chunks_exact(4) {
+            let m = mat4(cols[0], cols[1], cols[2], cols[3]);
+            for &v in &vectors {
+                assert_eq!(
+                    bits(mat4_mul_vec4(&m, v)),
+                    bits(scalar::mat4_mul_vec4(&m, v))
+                );
+            }
+            let n = mat4(cols[3], cols[1], cols[0], cols[2]);
+            assert_eq!(
+                mat_bits(&mat4_mul_mat4(&m, &n)),
+                mat_bits(&scalar::mat4_mul_mat4(&m, &n))
+            );
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_simd_vs_scalar() {
+        let vectors = test_vectors()[64..].to_vec();
+        let matrices: Vec<mat4> = vectors
+            .chunks_exact(4)
+            .map(|c| mat4(c[0], c[1], c[2], c[3]))
+            .collect();
+
+        bench("vec4 mul add, SIMD", 1000, || {
+            let mut sum = vec4::splat(0.0);
+            for &v in black_box(&vectors) {
+                sum = add(sum, mul(v, v));
+            }
+            black_box(sum);
+        });
+        bench("vec4 mul add, scalar", 1000, || {
+            let mut sum = vec4::splat(0.0);
+            for &v in black_box(&vectors) {
+                sum = scalar::add(sum, scalar::mul(v, v));
+            }
+            black_box(sum);
+        });
+        bench("mat4 * vec4, SIMD", 1000, || {
+            for m in black_box(&matrices) {
+                for &v in &vectors {
+                    black_box(mat4_mul_vec4(m, v));
+                }
+            }
+        });
+        bench("mat4 * vec4, scalar", 1000, || {
+            for m in black_box(&matrices) {
+                for &v in &vectors {
+                    black_box(scalar::mat4_mul_vec4(m, v));
+                }
+            }
+        });
+        bench("mat4 * mat4, SIMD", 1000, || {
+            for a in black_box(&matrices) {
+                for b in &matrices {
+                    black_box(mat4_mul_mat4(a, b));
+                }
+            }
+        });
+        bench("mat4 * mat4, scalar", 1000, || {
+            for a in black_box(&matrices) {
+                for b in &matrices {
+                    black_box(scalar::mat4_mul_mat4(a, b));
+                }
+            }
+        });
+    }
+}
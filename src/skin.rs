@@ -0,0 +1,396 @@
+use crate::curve::{sample_keys, Interpolation, Keyframe};
+use crate::model::{AttributeFormat, AttributeType, Batch};
+use crate::vector::*;
+
+/// Max number of joints that can influence a single vertex
+pub const MAX_JOINT_INFLUENCES: usize = 4;
+
+#[derive(Copy, Clone)]
+pub struct JointTransform {
+    pub translation: vec3,
+    pub rotation: quat,
+    pub scale: vec3,
+}
+
+impl JointTransform {
+    pub const IDENTITY: JointTransform = JointTransform {
+        translation: vec3(0.0, 0.0, 0.0),
+        rotation: quat::IDENTITY,
+        scale: vec3(1.0, 1.0, 1.0),
+    };
+
+    pub fn to_matrix(&self) -> mat4 {
+        let rotation = self.rotation.to_mat3() * mat3::scale(&self.scale);
+        mat4::from_mat3(&rotation, &self.translation)
+    }
+}
+
+pub struct Joint {
+    pub name: String,
+    /// Index of the parent joint, always less than the index of this joint
+    pub parent: Option<usize>,
+    /// Transform from model space to the joint space in the bind pose
+    pub inverse_bind: mat4,
+    /// Local transform used when an animation does not animate this joint
+    pub rest: JointTransform,
+}
+
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    /// Fill a pose with the rest transforms of each joint
+    pub fn get_rest_pose(&self, pose: &mut [JointTransform]) {
+        for (out, joint) in pose.iter_mut().zip(&self.joints) {
+            *out = joint.rest;
+        }
+    }
+
+    /// Calculate the skinning matrices (model space joint transform * inverse bind) for a pose
+    /// * `pose` - The local transform of each joint
+    /// * `out_matrices` - Receives one matrix per joint
+    pub fn get_joint_matrices(&self, pose: &[JointTransform], out_matrices: &mut [mat4]) {
+        debug_assert!(pose.len() >= self.joints.len());
+        debug_assert!(out_matrices.len() >= self.joints.len());
+
+        // Calculate model space transforms, parents are always processed before children
+        for (i, joint) in self.joints.iter().enumerate() {
+            let local = pose[i].to_matrix();
+            out_matrices[i] = match joint.parent {
+                Some(parent) => {
+                    debug_assert!(parent < i);
+                    out_matrices[parent] * local
+                }
+                None => local,
+            };
+        }
+
+        for (out, joint) in out_matrices.iter_mut().zip(&self.joints) {
+            *out = *out * joint.inverse_bind;
+        }
+    }
+}
+
+/// The animated channels of a joint. Keys are sorted by time, empty channels use the rest transform.
+pub struct JointTrack {
+    pub joint: usize,
+    pub translations: Vec<Keyframe<vec3>>,
+    pub rotations: Vec<Keyframe<quat>>,
+    pub scales: Vec<Keyframe<vec3>>,
+}
+
+pub struct AnimationClip {
+    pub name: String,
+    pub duration: f32,
+    pub interpolation: Interpolation,
+    pub tracks: Vec<JointTrack>,
+}
+
+impl AnimationClip {
+    /// Sample the local joint transforms at a point in time
+    /// * `skeleton` - The skeleton the clip animates
+    /// * `time` - The time in seconds since the start of the clip
+    /// * `looping` - If the clip wraps at the end, otherwise the time is clamped to the clip range
+    /// * `pose` - Receives the local transform of each joint
+    pub fn sample(
+        &self,
+        skeleton: &Skeleton,
+        time: f32,
+        looping: bool,
+        pose: &mut [JointTransform],
+    ) {
+        let time = if looping && self.duration > 0.0 {
+            time.rem_euclid(self.duration)
+        } else {
+            time.clamp(0.0, self.duration)
+        };
+
+        skeleton.get_rest_pose(pose);
+        for track in &self.tracks {
+            let out = &mut pose[track.joint];
+            if let Some(t) = sample_keys(&track.translations, time, self.interpolation, lerp) {
+                out.translation = t;
+            }
+            if let Some(r) = sample_keys(&track.rotations, time, self.interpolation, slerp) {
+                out.rotation = r;
+            }
+            if let Some(s) = sample_keys(&track.scales, time, self.interpolation, lerp) {
+                out.scale = s;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SkinError {
+    MissingAttribute(AttributeType),
+    JointOutOfRange(u32),
+}
+
+/// Deform the vertices of a batch on the CPU using linear blend skinning.
+/// Normals are transformed by the joint matrices directly, so joints are expected to have uniform scale.
+/// * `batch` - The batch to skin, must have vertex, bone index and bone weight attributes
+/// * `joint_matrices` - The skinning matrices from `Skeleton::get_joint_matrices`
+/// * `out_positions` - Receives the deformed position of each vertex
+/// * `out_normals` - Receives the deformed normal of each vertex, if the batch has normals
+pub fn skin_batch(
+    batch: &Batch,
+    joint_matrices: &[mat4],
+    out_positions: &mut Vec<vec3>,
+    out_normals: &mut Vec<vec3>,
+) -> Result<(), SkinError> {
+    let position_format = batch
+        .find_format(AttributeType::Vertex, 0)
+        .ok_or(SkinError::MissingAttribute(AttributeType::Vertex))?;
+    let index_format = batch
+        .find_format(AttributeType::BoneIndex, 0)
+        .ok_or(SkinError::MissingAttribute(AttributeType::BoneIndex))?;
+    let weight_format = batch
+        .find_format(AttributeType::BoneWeight, 0)
+        .ok_or(SkinError::MissingAttribute(AttributeType::BoneWeight))?;
+    let normal_format = batch.find_format(AttributeType::Normal, 0);
+
+    let weight_scale = match weight_format.attrib_format {
+        AttributeFormat::Float => 1.0,
+        AttributeFormat::UnsignedByte => 1.0 / 255.0,
+    };
+
+    out_positions.clear();
+    out_normals.clear();
+    for v in 0..batch.num_vertices {
+        let [x, y, z, _] = batch.read_attribute(position_format, v);
+        let pos = vec3(x, y, z);
+        let normal = normal_format.map(|f| {
+            let [x, y, z, _] = batch.read_attribute(f, v);
+            vec3(x, y, z)
+        });
+
+        let joints = batch.read_attribute(index_format, v);
+        let weights = batch.read_attribute(weight_format, v);
+
+        let mut skinned_pos = vec3(0.0, 0.0, 0.0);
+        let mut skinned_normal = vec3(0.0, 0.0, 0.0);
+        for i in 0..MAX_JOINT_INFLUENCES {
+            let weight = weights[i] * weight_scale;
+            if weight == 0.0 {
+                continue;
+            }
+            let joint = joints[i] as u32;
+            let m = joint_matrices
+                .get(joint as usize)
+                .ok_or(SkinError::JointOutOfRange(joint))?;
+            skinned_pos += m.transform_point(&pos) * weight;
+            if let Some(n) = &normal {
+                skinned_normal += m.transform_vector(n) * weight;
+            }
+        }
+
+        out_positions.push(skinned_pos);
+        if normal.is_some() {
+            let len_sq = length_squared(&skinned_normal);
+            if len_sq > 0.0 {
+                skinned_normal /= len_sq.sqrt();
+            }
+            out_normals.push(skinned_normal);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Format, PrimitiveType};
+
+    fn assert_near(a: vec3, b: vec3) {
+        assert!(length(&(a - b)) < 1e-5, "{a:?} != {b:?}");
+    }
+
+    // A root joint at the origin with a child one unit up the Y axis
+    fn test_skeleton() -> Skeleton {
+        let child_rest = JointTransform {
+            translation: vec3(0.0, 1.0, 0.0),
+            ..JointTransform::IDENTITY
+        };
+        Skeleton {
+            joints: vec![
+                Joint {
+                    name: "root".to_string(),
+                    parent: None,
+                    inverse_bind: mat4::IDENTITY,
+                    rest: JointTransform::IDENTITY,
+                },
+                Joint {
+                    name: "child".to_string(),
+                    parent: Some(0),
+                    inverse_bind: mat4::translation(&vec3(0.0, -1.0, 0.0)),
+                    rest: child_rest,
+                },
+            ],
+        }
+    }
+
+    // Vertices with a position, normal, 4 byte joint indices and float weights
+    fn test_batch(vertices: &[(vec3, vec3, [u8; 4], [f32; 4])]) -> Batch {
+        let formats = vec![
+            Format::new(AttributeType::Vertex, AttributeFormat::Float, 3, 0, 0),
+            Format::new(AttributeType::Normal, AttributeFormat::Float, 3, 12, 0),
+            Format::new(
+                AttributeType::BoneIndex,
+                AttributeFormat::UnsignedByte,
+                4,
+                24,
+                0,
+            ),
+            Format::new(AttributeType::BoneWeight, AttributeFormat::Float, 4, 28, 0),
+        ];
+        let mut data = Vec::new();
+        for (pos, normal, joints, weights) in vertices {
+            for f in [pos.x, pos.y, pos.z, normal.x, normal.y, normal.z] {
+                data.extend_from_slice(&f.to_le_bytes());
+            }
+            data.extend_from_slice(joints);
+            for f in weights {
+                data.extend_from_slice(&f.to_le_bytes());
+            }
+        }
+        Batch::new(PrimitiveType::Triangles, formats, 44, data, 0, Vec::new())
+    }
+
+    fn skin(pose: &[JointTransform], batch: &Batch) -> (Vec<vec3>, Vec<vec3>) {
+        let skeleton = test_skeleton();
+        let mut matrices = [mat4::IDENTITY; 2];
+        skeleton.get_joint_matrices(pose, &mut matrices);
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        skin_batch(batch, &matrices, &mut positions, &mut normals).unwrap();
+        (positions, normals)
+    }
+
+    fn test_vertices() -> Batch {
+        let up = vec3(0.0, 1.0, 0.0);
+        let right = vec3(1.0, 0.0, 0.0);
+        test_batch(&[
+            (vec3(1.0, 0.0, 0.0), up, [0, 0, 0, 0], [1.0, 0.0, 0.0, 0.0]),
+            (
+                vec3(0.0, 2.0, 0.0),
+                right,
+                [1, 0, 0, 0],
+                [1.0, 0.0, 0.0, 0.0],
+            ),
+            (
+                vec3(1.0, 1.0, 0.0),
+                right,
+                [0, 1, 0, 0],
+                [0.5, 0.5, 0.0, 0.0],
+            ),
+        ])
+    }
+
+    #[test]
+    fn rest_pose_is_bind_pose() {
+        let mut pose = [JointTransform::IDENTITY; 2];
+        test_skeleton().get_rest_pose(&mut pose);
+        let (positions, normals) = skin(&pose, &test_vertices());
+        assert_near(positions[0], vec3(1.0, 0.0, 0.0));
+        assert_near(positions[1], vec3(0.0, 2.0, 0.0));
+        assert_near(positions[2], vec3(1.0, 1.0, 0.0));
+        assert_near(normals[0], vec3(0.0, 1.0, 0.0));
+        assert_near(normals[1], vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn rotated_joint() {
+        let mut pose = [JointTransform::IDENTITY; 2];
+        test_skeleton().get_rest_pose(&mut pose);
+        pose[1].rotation = quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), std::f32::consts::FRAC_PI_2);
+        let (positions, normals) = skin(&pose, &test_vertices());
+
+        // Only bound to the root, so unchanged
+        assert_near(positions[0], vec3(1.0, 0.0, 0.0));
+        // One unit above the child joint, rotated to one unit left of it
+        assert_near(positions[1], vec3(-1.0, 1.0, 0.0));
+        assert_near(normals[1], vec3(0.0, 1.0, 0.0));
+        // Half way between the unmoved (1, 1, 0) and the rotated (0, 2, 0)
+        assert_near(positions[2], vec3(0.5, 1.5, 0.0));
+        assert_near(normals[2], normalize(&vec3(1.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn joint_out_of_range() {
+        let batch = test_batch(&[(
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            [5, 0, 0, 0],
+            [1.0, 0.0, 0.0, 0.0],
+        )]);
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let result = skin_batch(&batch, &[mat4::IDENTITY; 2], &mut positions, &mut normals);
+        assert!(matches!(result, Err(SkinError::JointOutOfRange(5))));
+    }
+
+    fn test_clip(interpolation: Interpolation) -> AnimationClip {
+        AnimationClip {
+            name: "test".to_string(),
+            duration: 2.0,
+            interpolation,
+            tracks: vec![JointTrack {
+                joint: 1,
+                translations: vec![
+                    Keyframe {
+                        time: 0.0,
+                        value: vec3(0.0, 1.0, 0.0),
+                    },
+                    Keyframe {
+                        time: 2.0,
+                        value: vec3(0.0, 3.0, 0.0),
+                    },
+                ],
+                rotations: Vec::new(),
+                scales: vec![Keyframe {
+                    time: 1.0,
+                    value: vec3(2.0, 2.0, 2.0),
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn sample_clip() {
+        let skeleton = test_skeleton();
+        let mut pose = [JointTransform::IDENTITY; 2];
+
+        test_clip(Interpolation::Linear).sample(&skeleton, 0.5, false, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 1.5, 0.0));
+        // A single key holds its value at all times
+        assert_near(pose[1].scale, vec3(2.0, 2.0, 2.0));
+        // Channels and joints without keys keep the rest transform
+        assert_eq!(pose[1].rotation, quat::IDENTITY);
+        assert_near(pose[0].translation, vec3(0.0, 0.0, 0.0));
+
+        test_clip(Interpolation::Step).sample(&skeleton, 1.5, false, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 1.0, 0.0));
+
+        test_clip(Interpolation::Smooth).sample(&skeleton, 0.5, false, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 1.3125, 0.0));
+    }
+
+    #[test]
+    fn sample_clip_wraps_or_clamps() {
+        let skeleton = test_skeleton();
+        let clip = test_clip(Interpolation::Linear);
+        let mut pose = [JointTransform::IDENTITY; 2];
+
+        clip.sample(&skeleton, 2.5, true, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 1.5, 0.0));
+        clip.sample(&skeleton, -0.5, true, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 2.5, 0.0));
+        clip.sample(&skeleton, 2.5, false, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 3.0, 0.0));
+        clip.sample(&skeleton, -0.5, false, &mut pose);
+        assert_near(pose[1].translation, vec3(0.0, 1.0, 0.0));
+    }
+}
@@ -1,4 +1,7 @@
-use std::{mem::size_of, ops::Index};
+use std::{
+    mem::size_of,
+    ops::{Index, IndexMut},
+};
 
 #[macro_export]
 macro_rules! static_assert {
@@ -7,8 +10,128 @@ macro_rules! static_assert {
     };
 }
 
+/// Functions shared by all the vector types, used by the generic `dot`, `lerp`, `min` etc.
+/// * `S` - The component type
+pub trait Vector<S = f32>:
+    Copy
+    + std::ops::Add<Output = Self>
+    + std::ops::Sub<Output = Self>
+    + std::ops::Mul<S, Output = Self>
+    + std::ops::Div<S, Output = Self>
+{
+    fn dot(&self, rhs: &Self) -> S;
+    fn min(&self, rhs: &Self) -> Self;
+    fn max(&self, rhs: &Self) -> Self;
+    fn abs(&self) -> Self;
+}
+
+/// Floating point component types, for the functions that need a square root
+pub trait Real: Copy {
+    fn sqrt(self) -> Self;
+}
+
+impl Real for f32 {
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+impl Real for f64 {
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+}
+
+macro_rules! vec_arith {
+    ($name:ident, $t:ty, $($field:ident),+) => {
+        impl std::ops::Add<$name> for $name {
+            type Output = $name;
+
+            fn add(self, rhs: $name) -> Self::Output {
+                $name { $($field: self.$field + rhs.$field),+ }
+            }
+        }
+
+        impl std::ops::Sub<$name> for $name {
+            type Output = $name;
+
+            fn sub(self, rhs: $name) -> Self::Output {
+                $name { $($field: self.$field - rhs.$field),+ }
+            }
+        }
+
+        /// Component-wise multiply
+        impl std::ops::Mul<$name> for $name {
+            type Output = $name;
+
+            fn mul(self, rhs: $name) -> Self::Output {
+                $name { $($field: self.$field * rhs.$field),+ }
+            }
+        }
+
+        /// Component-wise divide
+        impl std::ops::Div<$name> for $name {
+            type Output = $name;
+
+            fn div(self, rhs: $name) -> Self::Output {
+                $name { $($field: self.$field / rhs.$field),+ }
+            }
+        }
+
+        impl std::ops::Mul<$t> for $name {
+            type Output = $name;
+
+            fn mul(self, rhs: $t) -> Self::Output {
+                $name { $($field: self.$field * rhs),+ }
+            }
+        }
+
+        impl std::ops::Div<$t> for $name {
+            type Output = $name;
+
+            fn div(self, rhs: $t) -> Self::Output {
+                $name { $($field: self.$field / rhs),+ }
+            }
+        }
+
+        impl std::ops::Neg for $name {
+            type Output = $name;
+
+            fn neg(self) -> Self::Output {
+                $name { $($field: -self.$field),+ }
+            }
+        }
+
+        impl Vector<$t> for $name {
+            fn dot(&self, rhs: &Self) -> $t {
+                <$t>::default() $(+ self.$field * rhs.$field)+
+            }
+
+            fn min(&self, rhs: &Self) -> Self {
+                $name { $($field: self.$field.min(rhs.$field)),+ }
+            }
+
+            fn max(&self, rhs: &Self) -> Self {
+                $name { $($field: self.$field.max(rhs.$field)),+ }
+            }
+
+            fn abs(&self) -> Self {
+                $name { $($field: self.$field.abs()),+ }
+            }
+        }
+    };
+}
+
 macro_rules! vec_ops {
-    ($name:ident) => {
+    ($name:ident, $t:ty, $size:literal, $($field:ident : $index:literal),+) => {
+        impl std::ops::Mul<$name> for $t {
+            type Output = $name;
+
+            fn mul(self, rhs: $name) -> Self::Output {
+                rhs * self
+            }
+        }
+
         impl std::ops::AddAssign<$name> for $name {
             fn add_assign(&mut self, rhs: $name) {
                 *self = *self + rhs;
@@ -21,23 +144,108 @@ macro_rules! vec_ops {
             }
         }
 
-        impl std::ops::MulAssign<f32> for $name {
-            fn mul_assign(&mut self, rhs: f32) {
+        impl std::ops::MulAssign<$name> for $name {
+            fn mul_assign(&mut self, rhs: $name) {
+                *self = *self * rhs;
+            }
+        }
+
+        impl std::ops::DivAssign<$name> for $name {
+            fn div_assign(&mut self, rhs: $name) {
+                *self = *self / rhs;
+            }
+        }
+
+        impl std::ops::MulAssign<$t> for $name {
+            fn mul_assign(&mut self, rhs: $t) {
                 *self = *self * rhs;
             }
         }
 
-        impl std::ops::DivAssign<f32> for $name {
-            fn div_assign(&mut self, rhs: f32) {
+        impl std::ops::DivAssign<$t> for $name {
+            fn div_assign(&mut self, rhs: $t) {
                 *self = *self / rhs;
             }
         }
+
+        impl Index<usize> for $name {
+            type Output = $t;
+
+            fn index(&self, i: usize) -> &Self::Output {
+                match i {
+                    $($index => &self.$field,)+
+                    _ => panic!("{} index {} out of range", stringify!($name), i),
+                }
+            }
+        }
+
+        impl IndexMut<usize> for $name {
+            fn index_mut(&mut self, i: usize) -> &mut Self::Output {
+                match i {
+                    $($index => &mut self.$field,)+
+                    _ => panic!("{} index {} out of range", stringify!($name), i),
+                }
+            }
+        }
+
+        impl From<[$t; $size]> for $name {
+            fn from(a: [$t; $size]) -> Self {
+                let [$($field),+] = a;
+                $name { $($field),+ }
+            }
+        }
+
+        impl From<$name> for [$t; $size] {
+            fn from(v: $name) -> Self {
+                [$(v.$field),+]
+            }
+        }
     };
 }
 
+pub fn dot<S, T: Vector<S>>(a: &T, b: &T) -> S {
+    a.dot(b)
+}
+
+pub fn length_squared<S, T: Vector<S>>(vec: &T) -> S {
+    dot(vec, vec)
+}
+
+pub fn length<S: Real, T: Vector<S>>(vec: &T) -> S {
+    length_squared(vec).sqrt()
+}
+
+pub fn normalize<S: Real, T: Vector<S>>(vec: &T) -> T {
+    *vec / length(vec)
+}
+
+pub fn lerp<S, T: Vector<S>>(x: &T, y: &T, a: S) -> T {
+    *x + ((*y - *x) * a)
+}
+
+/// Component-wise minimum
+pub fn min<S, T: Vector<S>>(a: &T, b: &T) -> T {
+    a.min(b)
+}
+
+/// Component-wise maximum
+pub fn max<S, T: Vector<S>>(a: &T, b: &T) -> T {
+    a.max(b)
+}
+
+/// Component-wise absolute value
+pub fn abs<S, T: Vector<S>>(vec: &T) -> T {
+    vec.abs()
+}
+
+/// Component-wise clamp of each component to the range of the matching `lo` and `hi` components
+pub fn clamp<S, T: Vector<S>>(vec: &T, lo: &T, hi: &T) -> T {
+    vec.max(lo).min(hi)
+}
+
 #[repr(C)]
 #[allow(non_snake_case)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
 pub struct vec2 {
     pub x: f32,
     pub y: f32,
@@ -48,203 +256,1467 @@ pub const fn vec2(x: f32, y: f32) -> vec2 {
     vec2 { x, y }
 }
 
-impl std::ops::Mul<f32> for vec2 {
-    type Output = vec2;
+impl vec2 {
+    pub const fn splat(v: f32) -> vec2 {
+        vec2(v, v)
+    }
 
-    fn mul(self, rhs: f32) -> Self::Output {
-        vec2(self.x * rhs, self.y * rhs)
+    pub const fn extend(&self, z: f32) -> vec3 {
+        vec3(self.x, self.y, z)
     }
 }
+vec_arith!(vec2, f32, x, y);
+vec_ops!(vec2, f32, 2, x: 0, y: 1);
 
-impl std::ops::Div<f32> for vec2 {
-    type Output = vec2;
+// -------------------------------------------------------------------------------------------
 
-    fn div(self, rhs: f32) -> Self::Output {
-        vec2(self.x / rhs, self.y / rhs)
-    }
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
 }
+static_assert!(size_of::<vec3>() == 12);
 
-impl std::ops::Add<vec2> for vec2 {
-    type Output = vec2;
+pub const fn vec3(x: f32, y: f32, z: f32) -> vec3 {
+    vec3 { x, y, z }
+}
 
-    fn add(self, rhs: vec2) -> Self::Output {
-        vec2(self.x + rhs.x, self.y + rhs.y)
-    }
+pub fn cross(a: &vec3, b: &vec3) -> vec3 {
+    vec3(
+        a.y * b.z - a.z * b.y,
+        a.z * b.x - a.x * b.z,
+        a.x * b.y - a.y * b.x,
+    )
 }
 
-impl std::ops::Sub<vec2> for vec2 {
-    type Output = vec2;
+/// Build two unit vectors perpendicular to a unit vector and each other (Duff et al. 2017)
+/// * `n` - The normalized vector
+pub fn orthonormal_basis(n: &vec3) -> (vec3, vec3) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        vec3(b, sign + n.y * n.y * a, -n.y),
+    )
+}
 
-    fn sub(self, rhs: vec2) -> Self::Output {
-        vec2(self.x - rhs.x, self.y - rhs.y)
+impl vec3 {
+    pub const fn splat(v: f32) -> vec3 {
+        vec3(v, v, v)
     }
-}
 
-impl std::ops::Neg for vec2 {
-    type Output = vec2;
+    pub const fn xy(&self) -> vec2 {
+        vec2(self.x, self.y)
+    }
 
-    fn neg(self) -> Self::Output {
-        vec2(-self.x, -self.y)
+    pub const fn extend(&self, w: f32) -> vec4 {
+        vec4(self.x, self.y, self.z, w)
     }
 }
-vec_ops!(vec2);
+vec_arith!(vec3, f32, x, y, z);
+vec_ops!(vec3, f32, 3, x: 0, y: 1, z: 2);
 
 // -------------------------------------------------------------------------------------------
 
 #[repr(C)]
 #[allow(non_snake_case)]
-#[derive(PartialEq, Copy, Clone)]
-pub struct vec3 {
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct vec4 {
     pub x: f32,
     pub y: f32,
     pub z: f32,
+    pub w: f32,
 }
-static_assert!(size_of::<vec3>() == 12);
+static_assert!(size_of::<vec4>() == 16);
 
-pub const fn vec3(x: f32, y: f32, z: f32) -> vec3 {
-    vec3 { x, y, z }
+pub const fn vec4(x: f32, y: f32, z: f32, w: f32) -> vec4 {
+    vec4 { x, y, z, w }
 }
 
-pub fn dot(a: &vec3, b: &vec3) -> f32 {
-    (a.x * b.x) + (a.y * b.y) + (a.z * b.z)
-}
+impl vec4 {
+    pub const fn splat(v: f32) -> vec4 {
+        vec4(v, v, v, v)
+    }
 
-pub fn length_squared(vec: &vec3) -> f32 {
-    dot(vec, vec)
+    pub const fn xy(&self) -> vec2 {
+        vec2(self.x, self.y)
+    }
+
+    pub const fn xyz(&self) -> vec3 {
+        vec3(self.x, self.y, self.z)
+    }
 }
+vec_ops!(vec4, f32, 4, x: 0, y: 1, z: 2, w: 3);
 
-pub fn length(vec: &vec3) -> f32 {
-    length_squared(vec).sqrt()
+// vec4 arithmetic goes through vec4_impl, so it can use SIMD where available
+impl std::ops::Add<vec4> for vec4 {
+    type Output = vec4;
+
+    fn add(self, rhs: vec4) -> Self::Output {
+        vec4_impl::add(self, rhs)
+    }
 }
 
-pub fn normalize(vec: &vec3) -> vec3 {
-    *vec / length(vec)
+impl std::ops::Sub<vec4> for vec4 {
+    type Output = vec4;
+
+    fn sub(self, rhs: vec4) -> Self::Output {
+        vec4_impl::sub(self, rhs)
+    }
 }
 
-impl std::ops::Mul<f32> for vec3 {
-    type Output = vec3;
+/// Component-wise multiply
+impl std::ops::Mul<vec4> for vec4 {
+    type Output = vec4;
 
-    fn mul(self, rhs: f32) -> Self::Output {
-        vec3(self.x * rhs, self.y * rhs, self.z * rhs)
+    fn mul(self, rhs: vec4) -> Self::Output {
+        vec4_impl::mul(self, rhs)
     }
 }
 
-impl std::ops::Div<f32> for vec3 {
-    type Output = vec3;
+/// Component-wise divide
+impl std::ops::Div<vec4> for vec4 {
+    type Output = vec4;
 
-    fn div(self, rhs: f32) -> Self::Output {
-        vec3(self.x / rhs, self.y / rhs, self.z / rhs)
+    fn div(self, rhs: vec4) -> Self::Output {
+        vec4_impl::div(self, rhs)
     }
 }
 
-impl std::ops::Add<vec3> for vec3 {
-    type Output = vec3;
+impl std::ops::Mul<f32> for vec4 {
+    type Output = vec4;
 
-    fn add(self, rhs: vec3) -> Self::Output {
-        vec3(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    fn mul(self, rhs: f32) -> Self::Output {
+        vec4_impl::mul(self, vec4::splat(rhs))
     }
 }
 
-impl std::ops::Sub<vec3> for vec3 {
-    type Output = vec3;
+impl std::ops::Div<f32> for vec4 {
+    type Output = vec4;
 
-    fn sub(self, rhs: vec3) -> Self::Output {
-        vec3(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    fn div(self, rhs: f32) -> Self::Output {
+        vec4_impl::div(self, vec4::splat(rhs))
     }
 }
 
-impl std::ops::Neg for vec3 {
-    type Output = vec3;
+impl std::ops::Neg for vec4 {
+    type Output = vec4;
 
     fn neg(self) -> Self::Output {
-        vec3(-self.x, -self.y, -self.z)
+        vec4_impl::neg(self)
     }
 }
 
-impl Index<usize> for vec3 {
-    type Output = f32;
+impl Vector for vec4 {
+    fn dot(&self, rhs: &Self) -> f32 {
+        vec4_impl::dot(*self, *rhs)
+    }
 
-    fn index(&self, i: usize) -> &Self::Output {
-        match i {
-            0 => &self.x,
-            1 => &self.y,
-            2 => &self.z,
-            _ => panic!(),
-        }
+    fn min(&self, rhs: &Self) -> Self {
+        vec4_impl::min(*self, *rhs)
+    }
+
+    fn max(&self, rhs: &Self) -> Self {
+        vec4_impl::max(*self, *rhs)
+    }
+
+    fn abs(&self) -> Self {
+        vec4_impl::abs(*self)
     }
 }
 
-vec_ops!(vec3);
+#[cfg(all(target_arch = "x86_64", not(feature = "scalar_math")))]
+use crate::simd as vec4_impl;
+#[cfg(not(all(target_arch = "x86_64", not(feature = "scalar_math"))))]
+use scalar as vec4_impl;
+
+/// Reference implementation of the vec4 and mat4 arithmetic, used on targets without SIMD support
+/// or with the `scalar_math` feature. Must give the same results as the SIMD versions.
+#[allow(dead_code)]
+pub mod scalar {
+    use super::*;
+
+    pub fn add(a: vec4, b: vec4) -> vec4 {
+        vec4(a.x + b.x, a.y + b.y, a.z + b.z, a.w + b.w)
+    }
+
+    pub fn sub(a: vec4, b: vec4) -> vec4 {
+        vec4(a.x - b.x, a.y - b.y, a.z - b.z, a.w - b.w)
+    }
+
+    pub fn mul(a: vec4, b: vec4) -> vec4 {
+        vec4(a.x * b.x, a.y * b.y, a.z * b.z, a.w * b.w)
+    }
+
+    pub fn div(a: vec4, b: vec4) -> vec4 {
+        vec4(a.x / b.x, a.y / b.y, a.z / b.z, a.w / b.w)
+    }
+
+    pub fn neg(a: vec4) -> vec4 {
+        vec4(-a.x, -a.y, -a.z, -a.w)
+    }
+
+    pub fn dot(a: vec4, b: vec4) -> f32 {
+        (a.x * b.x + a.y * b.y) + (a.z * b.z + a.w * b.w)
+    }
+
+    /// Returns `b` for components where either is NaN or they compare equal, like the SSE minps instruction
+    pub fn min(a: vec4, b: vec4) -> vec4 {
+        let min = |a: f32, b: f32| if a < b { a } else { b };
+        vec4(min(a.x, b.x), min(a.y, b.y), min(a.z, b.z), min(a.w, b.w))
+    }
+
+    /// Returns `b` for components where either is NaN or they compare equal, like the SSE maxps instruction
+    pub fn max(a: vec4, b: vec4) -> vec4 {
+        let max = |a: f32, b: f32| if a > b { a } else { b };
+        vec4(max(a.x, b.x), max(a.y, b.y), max(a.z, b.z), max(a.w, b.w))
+    }
+
+    pub fn abs(a: vec4) -> vec4 {
+        vec4(a.x.abs(), a.y.abs(), a.z.abs(), a.w.abs())
+    }
+
+    pub fn mat4_mul_vec4(m: &mat4, v: vec4) -> vec4 {
+        let c = &m.cols;
+        add(
+            add(mul(c[0], vec4::splat(v.x)), mul(c[1], vec4::splat(v.y))),
+            add(mul(c[2], vec4::splat(v.z)), mul(c[3], vec4::splat(v.w))),
+        )
+    }
+
+    pub fn mat4_mul_mat4(a: &mat4, b: &mat4) -> mat4 {
+        let c = &b.cols;
+        mat4(
+            mat4_mul_vec4(a, c[0]),
+            mat4_mul_vec4(a, c[1]),
+            mat4_mul_vec4(a, c[2]),
+            mat4_mul_vec4(a, c[3]),
+        )
+    }
+}
 
 // -------------------------------------------------------------------------------------------
 
+/// Integer 2D vector, for pixel and grid coordinates
 #[repr(C)]
 #[allow(non_snake_case)]
-#[derive(PartialEq, Copy, Clone)]
-pub struct vec4 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
-    pub w: f32,
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Default)]
+pub struct ivec2 {
+    pub x: i32,
+    pub y: i32,
 }
-static_assert!(size_of::<vec4>() == 16);
+static_assert!(size_of::<ivec2>() == 8);
 
-pub const fn vec4(x: f32, y: f32, z: f32, w: f32) -> vec4 {
-    vec4 { x, y, z, w }
+pub const fn ivec2(x: i32, y: i32) -> ivec2 {
+    ivec2 { x, y }
 }
 
-pub fn lerp(x: &vec4, y: &vec4, a: f32) -> vec4 {
-    *x + ((*y - *x) * a)
-}
+impl ivec2 {
+    pub const fn splat(v: i32) -> ivec2 {
+        ivec2(v, v)
+    }
 
-impl std::ops::Mul<f32> for vec4 {
-    type Output = vec4;
+    pub const fn extend(&self, z: i32) -> ivec3 {
+        ivec3(self.x, self.y, z)
+    }
 
-    fn mul(self, rhs: f32) -> Self::Output {
-        vec4(self.x * rhs, self.y * rhs, self.z * rhs, self.w * rhs)
+    pub fn as_vec2(&self) -> vec2 {
+        vec2(self.x as f32, self.y as f32)
     }
 }
+vec_arith!(ivec2, i32, x, y);
+vec_ops!(ivec2, i32, 2, x: 0, y: 1);
 
-impl std::ops::Div<f32> for vec4 {
-    type Output = vec4;
+impl vec2 {
+    /// Convert to integers, rounding toward zero
+    pub fn as_ivec2(&self) -> ivec2 {
+        ivec2(self.x as i32, self.y as i32)
+    }
+}
 
-    fn div(self, rhs: f32) -> Self::Output {
-        vec4(self.x / rhs, self.y / rhs, self.z / rhs, self.w / rhs)
+// -------------------------------------------------------------------------------------------
+
+/// Integer 3D vector, for voxel and grid coordinates
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Default)]
+pub struct ivec3 {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+static_assert!(size_of::<ivec3>() == 12);
+
+pub const fn ivec3(x: i32, y: i32, z: i32) -> ivec3 {
+    ivec3 { x, y, z }
+}
+
+impl ivec3 {
+    pub const fn splat(v: i32) -> ivec3 {
+        ivec3(v, v, v)
+    }
+
+    pub const fn xy(&self) -> ivec2 {
+        ivec2(self.x, self.y)
+    }
+
+    pub fn as_vec3(&self) -> vec3 {
+        vec3(self.x as f32, self.y as f32, self.z as f32)
     }
 }
+vec_arith!(ivec3, i32, x, y, z);
+vec_ops!(ivec3, i32, 3, x: 0, y: 1, z: 2);
 
-impl std::ops::Add<vec4> for vec4 {
-    type Output = vec4;
+impl vec3 {
+    /// Convert to integers, rounding toward zero
+    pub fn as_ivec3(&self) -> ivec3 {
+        ivec3(self.x as i32, self.y as i32, self.z as i32)
+    }
 
-    fn add(self, rhs: vec4) -> Self::Output {
-        vec4(
-            self.x + rhs.x,
-            self.y + rhs.y,
-            self.z + rhs.z,
-            self.w + rhs.w,
+    /// Convert to integers, rounding down. Gives the cell containing the point in a grid of unit cells.
+    pub fn floor_ivec3(&self) -> ivec3 {
+        ivec3(
+            self.x.floor() as i32,
+            self.y.floor() as i32,
+            self.z.floor() as i32,
         )
     }
 }
 
-impl std::ops::Sub<vec4> for vec4 {
-    type Output = vec4;
+// -------------------------------------------------------------------------------------------
 
-    fn sub(self, rhs: vec4) -> Self::Output {
-        vec4(
-            self.x - rhs.x,
-            self.y - rhs.y,
-            self.z - rhs.z,
-            self.w - rhs.w,
+/// Double precision 3D vector, for large world positions and accumulated values
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct dvec3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+static_assert!(size_of::<dvec3>() == 24);
+
+pub const fn dvec3(x: f64, y: f64, z: f64) -> dvec3 {
+    dvec3 { x, y, z }
+}
+
+impl dvec3 {
+    pub const fn splat(v: f64) -> dvec3 {
+        dvec3(v, v, v)
+    }
+
+    pub fn cross(&self, rhs: &dvec3) -> dvec3 {
+        dvec3(
+            self.y * rhs.z - self.z * rhs.y,
+            self.z * rhs.x - self.x * rhs.z,
+            self.x * rhs.y - self.y * rhs.x,
         )
     }
+
+    /// Convert to single precision, use on values relative to a nearby origin to keep the precision
+    pub fn as_vec3(&self) -> vec3 {
+        vec3(self.x as f32, self.y as f32, self.z as f32)
+    }
 }
+vec_arith!(dvec3, f64, x, y, z);
+vec_ops!(dvec3, f64, 3, x: 0, y: 1, z: 2);
 
-impl std::ops::Neg for vec4 {
-    type Output = vec4;
+impl From<vec3> for dvec3 {
+    fn from(v: vec3) -> Self {
+        dvec3(v.x as f64, v.y as f64, v.z as f64)
+    }
+}
 
-    fn neg(self) -> Self::Output {
-        vec4(-self.x, -self.y, -self.z, -self.w)
+impl From<ivec3> for dvec3 {
+    fn from(v: ivec3) -> Self {
+        dvec3(v.x as f64, v.y as f64, v.z as f64)
     }
 }
 
-vec_ops!(vec4);
+// -------------------------------------------------------------------------------------------
+
+/// Column major 3x3 matrix
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct mat3 {
+    pub cols: [vec3; 3],
+}
+static_assert!(size_of::<mat3>() == 36);
+
+pub const fn mat3(c0: vec3, c1: vec3, c2: vec3) -> mat3 {
+    mat3 { cols: [c0, c1, c2] }
+}
+
+impl mat3 {
+    pub const IDENTITY: mat3 = mat3(
+        vec3(1.0, 0.0, 0.0),
+        vec3(0.0, 1.0, 0.0),
+        vec3(0.0, 0.0, 1.0),
+    );
+
+    pub fn scale(s: &vec3) -> mat3 {
+        mat3(
+            vec3(s.x, 0.0, 0.0),
+            vec3(0.0, s.y, 0.0),
+            vec3(0.0, 0.0, s.z),
+        )
+    }
+
+    /// Rotation of `angle` radians counter clockwise around a normalized axis
+    pub fn rotation(axis: &vec3, angle: f32) -> mat3 {
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+        let vec3 { x, y, z } = *axis;
+        mat3(
+            vec3(t * x * x + c, t * x * y + s * z, t * x * z - s * y),
+            vec3(t * x * y - s * z, t * y * y + c, t * y * z + s * x),
+            vec3(t * x * z + s * y, t * y * z - s * x, t * z * z + c),
+        )
+    }
+
+    /// The upper left 3x3 part of a 4x4 matrix
+    pub fn from_mat4(m: &mat4) -> mat3 {
+        let c = &m.cols;
+        mat3(
+            vec3(c[0].x, c[0].y, c[0].z),
+            vec3(c[1].x, c[1].y, c[1].z),
+            vec3(c[2].x, c[2].y, c[2].z),
+        )
+    }
+
+    pub fn transpose(&self) -> mat3 {
+        let c = &self.cols;
+        mat3(
+            vec3(c[0].x, c[1].x, c[2].x),
+            vec3(c[0].y, c[1].y, c[2].y),
+            vec3(c[0].z, c[1].z, c[2].z),
+        )
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let c = &self.cols;
+        dot(&c[0], &cross(&c[1], &c[2]))
+    }
+
+    /// Returns None if the matrix is singular
+    pub fn inverse(&self) -> Option<mat3> {
+        let c = &self.cols;
+        let det = self.determinant();
+        if det == 0.0 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let r0 = cross(&c[1], &c[2]) * inv_det;
+        let r1 = cross(&c[2], &c[0]) * inv_det;
+        let r2 = cross(&c[0], &c[1]) * inv_det;
+        Some(mat3(r0, r1, r2).transpose())
+    }
+}
+
+impl std::ops::Mul<vec3> for mat3 {
+    type Output = vec3;
+
+    fn mul(self, rhs: vec3) -> Self::Output {
+        self.cols[0] * rhs.x + self.cols[1] * rhs.y + self.cols[2] * rhs.z
+    }
+}
+
+impl std::ops::Mul<mat3> for mat3 {
+    type Output = mat3;
+
+    fn mul(self, rhs: mat3) -> Self::Output {
+        mat3(self * rhs.cols[0], self * rhs.cols[1], self * rhs.cols[2])
+    }
+}
+
+impl std::ops::Mul<f32> for mat3 {
+    type Output = mat3;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        mat3(self.cols[0] * rhs, self.cols[1] * rhs, self.cols[2] * rhs)
+    }
+}
+
+impl Index<usize> for mat3 {
+    type Output = vec3;
+
+    fn index(&self, i: usize) -> &Self::Output {
+        &self.cols[i]
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+/// Column major 4x4 matrix, matching the layout of a GLSL mat4 uniform
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct mat4 {
+    pub cols: [vec4; 4],
+}
+static_assert!(size_of::<mat4>() == 64);
+
+pub const fn mat4(c0: vec4, c1: vec4, c2: vec4, c3: vec4) -> mat4 {
+    mat4 {
+        cols: [c0, c1, c2, c3],
+    }
+}
+
+impl mat4 {
+    pub const IDENTITY: mat4 = mat4(
+        vec4(1.0, 0.0, 0.0, 0.0),
+        vec4(0.0, 1.0, 0.0, 0.0),
+        vec4(0.0, 0.0, 1.0, 0.0),
+        vec4(0.0, 0.0, 0.0, 1.0),
+    );
+
+    /// Expand a 3x3 matrix and translation to an affine transform
+    pub fn from_mat3(m: &mat3, translation: &vec3) -> mat4 {
+        let c = &m.cols;
+        mat4(
+            vec4(c[0].x, c[0].y, c[0].z, 0.0),
+            vec4(c[1].x, c[1].y, c[1].z, 0.0),
+            vec4(c[2].x, c[2].y, c[2].z, 0.0),
+            vec4(translation.x, translation.y, translation.z, 1.0),
+        )
+    }
+
+    pub fn translation(t: &vec3) -> mat4 {
+        mat4::from_mat3(&mat3::IDENTITY, t)
+    }
+
+    pub fn scale(s: &vec3) -> mat4 {
+        mat4::from_mat3(&mat3::scale(s), &vec3(0.0, 0.0, 0.0))
+    }
+
+    /// Rotation of `angle` radians counter clockwise around a normalized axis
+    pub fn rotation(axis: &vec3, angle: f32) -> mat4 {
+        mat4::from_mat3(&mat3::rotation(axis, angle), &vec3(0.0, 0.0, 0.0))
+    }
+
+    pub fn rotation_x(angle: f32) -> mat4 {
+        mat4::rotation(&vec3(1.0, 0.0, 0.0), angle)
+    }
+
+    pub fn rotation_y(angle: f32) -> mat4 {
+        mat4::rotation(&vec3(0.0, 1.0, 0.0), angle)
+    }
+
+    pub fn rotation_z(angle: f32) -> mat4 {
+        mat4::rotation(&vec3(0.0, 0.0, 1.0), angle)
+    }
+
+    /// Right handed perspective projection to OpenGL clip space (z in -1..1)
+    /// * `fov_y` - The vertical field of view in radians
+    /// * `aspect` - Width / height of the view
+    pub fn perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> mat4 {
+        let f = 1.0 / (fov_y * 0.5).tan();
+        let range = 1.0 / (near - far);
+        mat4(
+            vec4(f / aspect, 0.0, 0.0, 0.0),
+            vec4(0.0, f, 0.0, 0.0),
+            vec4(0.0, 0.0, (far + near) * range, -1.0),
+            vec4(0.0, 0.0, 2.0 * far * near * range, 0.0),
+        )
+    }
+
+    /// Right handed orthographic projection to OpenGL clip space (z in -1..1)
+    pub fn orthographic(left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32) -> mat4 {
+        let w = 1.0 / (right - left);
+        let h = 1.0 / (top - bottom);
+        let d = 1.0 / (far - near);
+        mat4(
+            vec4(2.0 * w, 0.0, 0.0, 0.0),
+            vec4(0.0, 2.0 * h, 0.0, 0.0),
+            vec4(0.0, 0.0, -2.0 * d, 0.0),
+            vec4(
+                -(right + left) * w,
+                -(top + bottom) * h,
+                -(far + near) * d,
+                1.0,
+            ),
+        )
+    }
+
+    /// Right handed view matrix looking from `eye` towards `target`
+    pub fn look_at(eye: &vec3, target: &vec3, up: &vec3) -> mat4 {
+        let f = normalize(&(*target - *eye));
+        let s = normalize(&cross(&f, up));
+        let u = cross(&s, &f);
+        mat4(
+            vec4(s.x, u.x, -f.x, 0.0),
+            vec4(s.y, u.y, -f.y, 0.0),
+            vec4(s.z, u.z, -f.z, 0.0),
+            vec4(-dot(&s, eye), -dot(&u, eye), dot(&f, eye), 1.0),
+        )
+    }
+
+    /// Transform a point, ignoring the projective row
+    pub fn transform_point(&self, p: &vec3) -> vec3 {
+        let r = self.cols[0] * p.x + self.cols[1] * p.y + self.cols[2] * p.z + self.cols[3];
+        vec3(r.x, r.y, r.z)
+    }
+
+    /// Transform a direction, ignoring the translation
+    pub fn transform_vector(&self, v: &vec3) -> vec3 {
+        let r = self.cols[0] * v.x + self.cols[1] * v.y + self.cols[2] * v.z;
+        vec3(r.x, r.y, r.z)
+    }
+
+    pub fn transpose(&self) -> mat4 {
+        let c = &self.cols;
+        mat4(
+            vec4(c[0].x, c[1].x, c[2].x, c[3].x),
+            vec4(c[0].y, c[1].y, c[2].y, c[3].y),
+            vec4(c[0].z, c[1].z, c[2].z, c[3].z),
+            vec4(c[0].w, c[1].w, c[2].w, c[3].w),
+        )
+    }
+
+    // 2x2 minors of the top two (s) and bottom two (c) rows, used by determinant and inverse
+    fn minors(&self) -> ([f32; 6], [f32; 6]) {
+        let [c0, c1, c2, c3] = &self.cols;
+        let s = [
+            c0.x * c1.y - c1.x * c0.y,
+            c0.x * c2.y - c2.x * c0.y,
+            c0.x * c3.y - c3.x * c0.y,
+            c1.x * c2.y - c2.x * c1.y,
+            c1.x * c3.y - c3.x * c1.y,
+            c2.x * c3.y - c3.x * c2.y,
+        ];
+        let c = [
+            c0.z * c1.w - c1.z * c0.w,
+            c0.z * c2.w - c2.z * c0.w,
+            c0.z * c3.w - c3.z * c0.w,
+            c1.z * c2.w - c2.z * c1.w,
+            c1.z * c3.w - c3.z * c1.w,
+            c2.z * c3.w - c3.z * c2.w,
+        ];
+        (s, c)
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let (s, c) = self.minors();
+        s[0] * c[5] - s[1] * c[4] + s[2] * c[3] + s[3] * c[2] - s[4] * c[1] + s[5] * c[0]
+    }
+
+    /// Returns None if the matrix is singular
+    pub fn inverse(&self) -> Option<mat4> {
+        let (s, c) = self.minors();
+        let det = s[0] * c[5] - s[1] * c[4] + s[2] * c[3] + s[3] * c[2] - s[4] * c[1] + s[5] * c[0];
+        if det == 0.0 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let [c0, c1, c2, c3] = &self.cols;
+        let inv = mat4(
+            vec4(
+                c1.y * c[5] - c2.y * c[4] + c3.y * c[3],
+                -c0.y * c[5] + c2.y * c[2] - c3.y * c[1],
+                c0.y * c[4] - c1.y * c[2] + c3.y * c[0],
+                -c0.y * c[3] + c1.y * c[1] - c2.y * c[0],
+            ),
+            vec4(
+                -c1.x * c[5] + c2.x * c[4] - c3.x * c[3],
+                c0.x * c[5] - c2.x * c[2] + c3.x * c[1],
+                -c0.x * c[4] + c1.x * c[2] - c3.x * c[0],
+                c0.x * c[3] - c1.x * c[1] + c2.x * c[0],
+            ),
+            vec4(
+                c1.w * s[5] - c2.w * s[4] + c3.w * s[3],
+                -c0.w * s[5] + c2.w * s[2] - c3.w * s[1],
+                c0.w * s[4] - c1.w * s[2] + c3.w * s[0],
+                -c0.w * s[3] + c1.w * s[1] - c2.w * s[0],
+            ),
+            vec4(
+                -c1.z * s[5] + c2.z * s[4] - c3.z * s[3],
+                c0.z * s[5] - c2.z * s[2] + c3.z * s[1],
+                -c0.z * s[4] + c1.z * s[2] - c3.z * s[0],
+                c0.z * s[3] - c1.z * s[1] + c2.z * s[0],
+            ),
+        );
+        Some(inv * inv_det)
+    }
+}
+
+impl std::ops::Mul<vec4> for mat4 {
+    type Output = vec4;
+
+    fn mul(self, rhs: vec4) -> Self::Output {
+        vec4_impl::mat4_mul_vec4(&self, rhs)
+    }
+}
+
+impl std::ops::Mul<mat4> for mat4 {
+    type Output = mat4;
+
+    fn mul(self, rhs: mat4) -> Self::Output {
+        vec4_impl::mat4_mul_mat4(&self, &rhs)
+    }
+}
+
+impl std::ops::Mul<f32> for mat4 {
+    type Output = mat4;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        mat4(
+            self.cols[0] * rhs,
+            self.cols[1] * rhs,
+            self.cols[2] * rhs,
+            self.cols[3] * rhs,
+        )
+    }
+}
+
+impl Index<usize> for mat4 {
+    type Output = vec4;
+
+    fn index(&self, i: usize) -> &Self::Output {
+        &self.cols[i]
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+/// Rotation quaternion, `w` is the scalar part
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct quat {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+static_assert!(size_of::<quat>() == 16);
+
+pub const fn quat(x: f32, y: f32, z: f32, w: f32) -> quat {
+    quat { x, y, z, w }
+}
+
+impl quat {
+    pub const IDENTITY: quat = quat(0.0, 0.0, 0.0, 1.0);
+
+    /// Rotation of `angle` radians counter clockwise around a normalized axis
+    pub fn from_axis_angle(axis: &vec3, angle: f32) -> quat {
+        let (s, c) = (angle * 0.5).sin_cos();
+        quat(axis.x * s, axis.y * s, axis.z * s, c)
+    }
+
+    /// Returns the normalized rotation axis and the angle in radians. The axis is +X for no rotation.
+    pub fn to_axis_angle(&self) -> (vec3, f32) {
+        let q = self.normalize();
+        let s = (1.0 - q.w * q.w).max(0.0).sqrt();
+        let angle = 2.0 * q.w.clamp(-1.0, 1.0).acos();
+        if s < 1e-6 {
+            return (vec3(1.0, 0.0, 0.0), angle);
+        }
+        (vec3(q.x / s, q.y / s, q.z / s), angle)
+    }
+
+    /// Rotation from euler angles in radians, applied in the order z (roll), x (pitch), y (yaw)
+    pub fn from_euler(angles: &vec3) -> quat {
+        quat::from_axis_angle(&vec3(0.0, 1.0, 0.0), angles.y)
+            * quat::from_axis_angle(&vec3(1.0, 0.0, 0.0), angles.x)
+            * quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), angles.z)
+    }
+
+    /// Euler angles in radians matching `from_euler`, x is in the -PI/2..PI/2 range
+    pub fn to_euler(&self) -> vec3 {
+        let m = self.to_mat3();
+        // Element at row r, column c is m.cols[c][r]
+        let sin_x = -m.cols[2].y;
+        let x = sin_x.clamp(-1.0, 1.0).asin();
+        if sin_x.abs() < 0.99999 {
+            vec3(
+                x,
+                m.cols[2].x.atan2(m.cols[2].z),
+                m.cols[0].y.atan2(m.cols[1].y),
+            )
+        } else {
+            // Gimbal lock, yaw and roll are around the same axis so put it all in yaw
+            vec3(x, (-m.cols[0].z).atan2(m.cols[0].x), 0.0)
+        }
+    }
+
+    /// Rotation from an orthonormal matrix
+    pub fn from_mat3(m: &mat3) -> quat {
+        let [c0, c1, c2] = &m.cols;
+        let trace = c0.x + c1.y + c2.z;
+        let q = if trace > 0.0 {
+            let s = 0.5 / (trace + 1.0).sqrt();
+            quat(
+                (c1.z - c2.y) * s,
+                (c2.x - c0.z) * s,
+                (c0.y - c1.x) * s,
+                0.25 / s,
+            )
+        } else if c0.x > c1.y && c0.x > c2.z {
+            let s = 2.0 * (1.0 + c0.x - c1.y - c2.z).sqrt();
+            quat(
+                0.25 * s,
+                (c1.x + c0.y) / s,
+                (c2.x + c0.z) / s,
+                (c1.z - c2.y) / s,
+            )
+        } else if c1.y > c2.z {
+            let s = 2.0 * (1.0 + c1.y - c0.x - c2.z).sqrt();
+            quat(
+                (c1.x + c0.y) / s,
+                0.25 * s,
+                (c2.y + c1.z) / s,
+                (c2.x - c0.z) / s,
+            )
+        } else {
+            let s = 2.0 * (1.0 + c2.z - c0.x - c1.y).sqrt();
+            quat(
+                (c2.x + c0.z) / s,
+                (c2.y + c1.z) / s,
+                0.25 * s,
+                (c0.y - c1.x) / s,
+            )
+        };
+        q.normalize()
+    }
+
+    pub fn to_mat3(&self) -> mat3 {
+        let quat { x, y, z, w } = *self;
+        mat3(
+            vec3(
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y + w * z),
+                2.0 * (x * z - w * y),
+            ),
+            vec3(
+                2.0 * (x * y - w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z + w * x),
+            ),
+            vec3(
+                2.0 * (x * z + w * y),
+                2.0 * (y * z - w * x),
+                1.0 - 2.0 * (x * x + y * y),
+            ),
+        )
+    }
+
+    pub fn to_mat4(&self) -> mat4 {
+        mat4::from_mat3(&self.to_mat3(), &vec3(0.0, 0.0, 0.0))
+    }
+
+    pub fn dot(&self, rhs: &quat) -> f32 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalize(&self) -> quat {
+        *self * (1.0 / self.length())
+    }
+
+    pub fn conjugate(&self) -> quat {
+        quat(-self.x, -self.y, -self.z, self.w)
+    }
+
+    pub fn inverse(&self) -> quat {
+        self.conjugate() * (1.0 / self.dot(self))
+    }
+}
+
+/// Normalized linear interpolation, taking the shortest path. Cheaper than `slerp` but not constant speed.
+pub fn nlerp(x: &quat, y: &quat, a: f32) -> quat {
+    let y = if x.dot(y) < 0.0 { -*y } else { *y };
+    (*x + (y - *x) * a).normalize()
+}
+
+/// Spherical linear interpolation, taking the shortest path
+pub fn slerp(x: &quat, y: &quat, a: f32) -> quat {
+    let mut d = x.dot(y);
+    let y = if d < 0.0 {
+        d = -d;
+        -*y
+    } else {
+        *y
+    };
+
+    // Fall back to nlerp when the rotations are almost equal to avoid dividing by sin(~0)
+    if d > 0.9995 {
+        return (*x + (y - *x) * a).normalize();
+    }
+
+    let theta = d.acos();
+    let inv_sin = 1.0 / theta.sin();
+    let s0 = ((1.0 - a) * theta).sin() * inv_sin;
+    let s1 = (a * theta).sin() * inv_sin;
+    *x * s0 + y * s1
+}
+
+impl std::ops::Mul<quat> for quat {
+    type Output = quat;
+
+    /// Combined rotation, applying `rhs` first
+    fn mul(self, rhs: quat) -> Self::Output {
+        quat(
+            self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+            self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+        )
+    }
+}
+
+impl std::ops::Mul<vec3> for quat {
+    type Output = vec3;
+
+    /// Rotate a vector
+    fn mul(self, rhs: vec3) -> Self::Output {
+        let u = vec3(self.x, self.y, self.z);
+        let t = cross(&u, &rhs) * 2.0;
+        rhs + t * self.w + cross(&u, &t)
+    }
+}
+
+impl std::ops::Mul<f32> for quat {
+    type Output = quat;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        quat(self.x * rhs, self.y * rhs, self.z * rhs, self.w * rhs)
+    }
+}
+
+impl std::ops::Add<quat> for quat {
+    type Output = quat;
+
+    fn add(self, rhs: quat) -> Self::Output {
+        quat(
+            self.x + rhs.x,
+            self.y + rhs.y,
+            self.z + rhs.z,
+            self.w + rhs.w,
+        )
+    }
+}
+
+impl std::ops::Sub<quat> for quat {
+    type Output = quat;
+
+    fn sub(self, rhs: quat) -> Self::Output {
+        quat(
+            self.x - rhs.x,
+            self.y - rhs.y,
+            self.z - rhs.z,
+            self.w - rhs.w,
+        )
+    }
+}
+
+impl std::ops::Neg for quat {
+    type Output = quat;
+
+    fn neg(self) -> Self::Output {
+        quat(-self.x, -self.y, -self.z, -self.w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Check every generated operator and function of a vector type against the same math done per component
+    macro_rules! test_vec {
+        ($test:ident, $name:ident, $t:ty, $size:literal, $a:expr, $b:expr) => {
+            #[test]
+            fn $test() {
+                let a_arr: [$t; $size] = core::array::from_fn(|i| $a[i]);
+                let b_arr: [$t; $size] = core::array::from_fn(|i| $b[i]);
+                let a = $name::from(a_arr);
+                let b = $name::from(b_arr);
+                let map =
+                    |f: &dyn Fn(usize) -> $t| $name::from(core::array::from_fn::<$t, $size, _>(f));
+                let two = 2 as $t;
+
+                assert_eq!(<[$t; $size]>::from(a), a_arr);
+                for i in 0..$size {
+                    assert_eq!(a[i], a_arr[i]);
+                }
+                let mut c = a;
+                c[$size - 1] = 7 as $t;
+                assert_eq!(c[$size - 1], 7 as $t);
+
+                assert_eq!(a + b, map(&|i| a_arr[i] + b_arr[i]));
+                assert_eq!(a - b, map(&|i| a_arr[i] - b_arr[i]));
+                assert_eq!(a * b, map(&|i| a_arr[i] * b_arr[i]));
+                assert_eq!(a / b, map(&|i| a_arr[i] / b_arr[i]));
+                assert_eq!(a * two, map(&|i| a_arr[i] * two));
+                assert_eq!(two * a, map(&|i| a_arr[i] * two));
+                assert_eq!(a / two, map(&|i| a_arr[i] / two));
+                assert_eq!(-a, map(&|i| -a_arr[i]));
+
+                let mut c = a;
+                c += b;
+                assert_eq!(c, a + b);
+                let mut c = a;
+                c -= b;
+                assert_eq!(c, a - b);
+                let mut c = a;
+                c *= b;
+                assert_eq!(c, a * b);
+                let mut c = a;
+                c /= b;
+                assert_eq!(c, a / b);
+                let mut c = a;
+                c *= two;
+                assert_eq!(c, a * two);
+                let mut c = a;
+                c /= two;
+                assert_eq!(c, a / two);
+
+                let expected_dot: $t = (0..$size).map(|i| a_arr[i] * b_arr[i]).sum();
+                assert_eq!(dot(&a, &b), expected_dot);
+                assert_eq!(length_squared(&a), dot(&a, &a));
+
+                assert_eq!(min(&a, &b), map(&|i| a_arr[i].min(b_arr[i])));
+                assert_eq!(max(&a, &b), map(&|i| a_arr[i].max(b_arr[i])));
+                assert_eq!(abs(&a), map(&|i| a_arr[i].abs()));
+                let lo = $name::splat(-1 as $t);
+                let hi = $name::splat(1 as $t);
+                assert_eq!(
+                    clamp(&a, &lo, &hi),
+                    map(&|i| a_arr[i].clamp(-1 as $t, 1 as $t))
+                );
+                assert_eq!(lerp(&a, &b, 0 as $t), a);
+                assert_eq!(lerp(&a, &b, 1 as $t), b);
+
+                assert_eq!($name::splat(3 as $t), map(&|_| 3 as $t));
+                assert_eq!($name::default(), map(&|_| 0 as $t));
+            }
+        };
+    }
+
+    // Check the functions only floating point vector types have
+    macro_rules! test_real_vec {
+        ($test:ident, $name:ident, $t:ty, $size:literal, $a:expr, $b:expr) => {
+            #[test]
+            fn $test() {
+                let a_arr: [$t; $size] = core::array::from_fn(|i| $a[i]);
+                let b_arr: [$t; $size] = core::array::from_fn(|i| $b[i]);
+                let a = $name::from(a_arr);
+                let b = $name::from(b_arr);
+
+                assert_eq!(length(&a), dot(&a, &a).sqrt());
+                assert!((length(&normalize(&a)) - 1.0).abs() < 1e-6);
+                assert_eq!(normalize(&a), a / length(&a));
+                let half = $name::from(core::array::from_fn::<$t, $size, _>(|i| {
+                    (a_arr[i] + b_arr[i]) * 0.5
+                }));
+                assert_eq!(lerp(&a, &b, 0.5), half);
+            }
+        };
+    }
+
+    const A: [f32; 4] = [1.5, -2.0, 3.25, -0.5];
+    const B: [f32; 4] = [-4.0, 0.5, 2.0, 8.0];
+    const A_F64: [f64; 3] = [1.5, -2.0, 3.25];
+    const B_F64: [f64; 3] = [-4.0, 0.5, 2.0];
+    // Odd and negative values, so integer division has to round toward zero
+    const A_I32: [i32; 3] = [7, -9, 3];
+    const B_I32: [i32; 3] = [-2, 4, 5];
+
+    test_vec!(vec2_ops, vec2, f32, 2, A, B);
+    test_vec!(vec3_ops, vec3, f32, 3, A, B);
+    test_vec!(vec4_ops, vec4, f32, 4, A, B);
+    test_vec!(ivec2_ops, ivec2, i32, 2, A_I32, B_I32);
+    test_vec!(ivec3_ops, ivec3, i32, 3, A_I32, B_I32);
+    test_vec!(dvec3_ops, dvec3, f64, 3, A_F64, B_F64);
+
+    test_real_vec!(vec2_real, vec2, f32, 2, A, B);
+    test_real_vec!(vec3_real, vec3, f32, 3, A, B);
+    test_real_vec!(vec4_real, vec4, f32, 4, A, B);
+    test_real_vec!(dvec3_real, dvec3, f64, 3, A_F64, B_F64);
+
+    #[test]
+    fn integer_division_rounds_toward_zero() {
+        assert_eq!(ivec3(7, -9, 3) / 2, ivec3(3, -4, 1));
+        assert_eq!(ivec2(7, -9) / ivec2(-2, 4), ivec2(-3, -2));
+    }
+
+    #[test]
+    fn integer_conversions() {
+        let p = vec3(1.7, -0.2, -2.5);
+        assert_eq!(p.as_ivec3(), ivec3(1, 0, -2));
+        assert_eq!(p.floor_ivec3(), ivec3(1, -1, -3));
+        assert_eq!(vec3(-3.0, 0.0, 2.0).floor_ivec3(), ivec3(-3, 0, 2));
+        assert_eq!(vec2(-0.5, 2.9).as_ivec2(), ivec2(0, 2));
+
+        assert_eq!(ivec2(3, -4).as_vec2(), vec2(3.0, -4.0));
+        assert_eq!(ivec3(3, -4, 5).as_vec3(), vec3(3.0, -4.0, 5.0));
+        assert_eq!(ivec2(3, -4).extend(5), ivec3(3, -4, 5));
+        assert_eq!(ivec3(3, -4, 5).xy(), ivec2(3, -4));
+
+        // Usable as grid cell keys
+        let cells: std::collections::HashSet<ivec3> =
+            [ivec3(1, 2, 3), ivec3(1, 2, 3), ivec3(-1, 2, 3)]
+                .into_iter()
+                .collect();
+        assert_eq!(cells.len(), 2);
+    }
+
+    #[test]
+    fn double_conversions() {
+        assert_eq!(
+            dvec3::from(vec3(1.5, -2.0, 0.1)),
+            dvec3(1.5, -2.0, 0.1f32 as f64)
+        );
+        assert_eq!(dvec3::from(ivec3(3, -4, 5)), dvec3(3.0, -4.0, 5.0));
+        assert_eq!(dvec3(1.5, -2.0, 3.25).as_vec3(), vec3(1.5, -2.0, 3.25));
+
+        // Differences of large positions keep their precision before converting to f32
+        let origin = dvec3(1.0e9, 0.0, -1.0e9);
+        let p = origin + dvec3(0.125, 1.0, -0.25);
+        assert_eq!((p - origin).as_vec3(), vec3(0.125, 1.0, -0.25));
+
+        let x = dvec3(1.0, 0.0, 0.0);
+        let y = dvec3(0.0, 1.0, 0.0);
+        assert_eq!(x.cross(&y), dvec3(0.0, 0.0, 1.0));
+        assert_eq!(y.cross(&x), dvec3(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_out_of_range() {
+        let _ = vec3(1.0, 2.0, 3.0)[3];
+    }
+
+    #[test]
+    fn swizzles() {
+        assert_eq!(vec2(1.0, 2.0).extend(3.0), vec3(1.0, 2.0, 3.0));
+        assert_eq!(vec3(1.0, 2.0, 3.0).extend(4.0), vec4(1.0, 2.0, 3.0, 4.0));
+        assert_eq!(vec3(1.0, 2.0, 3.0).xy(), vec2(1.0, 2.0));
+        assert_eq!(vec4(1.0, 2.0, 3.0, 4.0).xy(), vec2(1.0, 2.0));
+        assert_eq!(vec4(1.0, 2.0, 3.0, 4.0).xyz(), vec3(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn cross_product() {
+        let x = vec3(1.0, 0.0, 0.0);
+        let y = vec3(0.0, 1.0, 0.0);
+        let z = vec3(0.0, 0.0, 1.0);
+        assert_eq!(cross(&x, &y), z);
+        assert_eq!(cross(&y, &z), x);
+        assert_eq!(cross(&z, &x), y);
+        assert_eq!(cross(&y, &x), -z);
+
+        let a = vec3(1.5, -2.0, 3.25);
+        let b = vec3(-4.0, 0.5, 2.0);
+        let c = cross(&a, &b);
+        assert_eq!(c, vec3(-5.625, -16.0, -7.25));
+        assert_eq!(dot(&c, &a), 0.0);
+        assert_eq!(dot(&c, &b), 0.0);
+        assert_eq!(cross(&a, &a), vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn basis_is_orthonormal() {
+        for n in [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+            normalize(&vec3(1.0, 2.0, 3.0)),
+            normalize(&vec3(-3.0, 0.5, -0.1)),
+        ] {
+            let (t, b) = orthonormal_basis(&n);
+            for (u, v) in [(t, b), (t, n), (b, n)] {
+                assert!(dot(&u, &v).abs() < 1e-6);
+            }
+            assert!((length(&t) - 1.0).abs() < 1e-6);
+            assert!((length(&b) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    fn assert_vec3_near(a: vec3, b: vec3) {
+        assert!(length(&(a - b)) < 1e-4, "{a:?} != {b:?}");
+    }
+
+    fn assert_mat3_near(a: &mat3, b: &mat3) {
+        for i in 0..3 {
+            assert_vec3_near(a[i], b[i]);
+        }
+    }
+
+    fn assert_mat4_near(a: &mat4, b: &mat4) {
+        for i in 0..4 {
+            assert!(length(&(a[i] - b[i])) < 1e-4, "{a:?} != {b:?}");
+        }
+    }
+
+    // Column major, so the rows of the source text are the columns of the matrix
+    const M3: mat3 = mat3(
+        vec3(2.0, 1.0, 0.5),
+        vec3(-1.0, 3.0, 2.0),
+        vec3(0.0, 4.0, 1.0),
+    );
+    const M4: mat4 = mat4(
+        vec4(2.0, 1.0, 0.5, 0.0),
+        vec4(-1.0, 3.0, 2.0, 1.0),
+        vec4(0.0, 4.0, 1.0, -2.0),
+        vec4(5.0, -3.0, 2.0, 1.0),
+    );
+
+    #[test]
+    fn mat3_inverse_and_determinant() {
+        assert_eq!(mat3::IDENTITY.determinant(), 1.0);
+        assert_eq!(mat3::scale(&vec3(2.0, 3.0, 4.0)).determinant(), 24.0);
+        assert_eq!(M3.determinant(), -11.0);
+        assert_eq!(M3.transpose().determinant(), M3.determinant());
+
+        let inv = M3.inverse().unwrap();
+        assert_mat3_near(&(M3 * inv), &mat3::IDENTITY);
+        assert_mat3_near(&(inv * M3), &mat3::IDENTITY);
+        assert!((inv.determinant() * M3.determinant() - 1.0).abs() < 1e-5);
+
+        let v = vec3(0.5, -1.0, 2.0);
+        assert_eq!(M3 * v, vec3(2.0, 5.5, 0.25));
+        assert_vec3_near(inv * (M3 * v), v);
+
+        // Columns 0 and 2 are parallel
+        let singular = mat3(
+            vec3(1.0, 2.0, 3.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(2.0, 4.0, 6.0),
+        );
+        assert_eq!(singular.determinant(), 0.0);
+        assert!(singular.inverse().is_none());
+        assert!(mat3::scale(&vec3(1.0, 0.0, 1.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn mat4_inverse_and_determinant() {
+        assert_eq!(mat4::IDENTITY.determinant(), 1.0);
+        assert_eq!(mat4::scale(&vec3(2.0, 3.0, 4.0)).determinant(), 24.0);
+        assert_eq!(mat4::translation(&vec3(5.0, 6.0, 7.0)).determinant(), 1.0);
+        assert_eq!(M4.determinant(), 66.0);
+        assert_eq!(M4.transpose().determinant(), M4.determinant());
+
+        let inv = M4.inverse().unwrap();
+        assert_mat4_near(&(M4 * inv), &mat4::IDENTITY);
+        assert_mat4_near(&(inv * M4), &mat4::IDENTITY);
+
+        let t = mat4::translation(&vec3(5.0, 6.0, 7.0));
+        assert_mat4_near(
+            &t.inverse().unwrap(),
+            &mat4::translation(&vec3(-5.0, -6.0, -7.0)),
+        );
+
+        // Rows 1 and 3 are equal
+        let singular = mat4(
+            vec4(1.0, 2.0, 3.0, 2.0),
+            vec4(0.0, 1.0, 0.0, 1.0),
+            vec4(2.0, 4.0, 6.0, 4.0),
+            vec4(1.0, 5.0, 2.0, 5.0),
+        );
+        assert_eq!(singular.determinant(), 0.0);
+        assert!(singular.inverse().is_none());
+        assert!(mat4::scale(&vec3(1.0, 1.0, 0.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn mat4_transforms() {
+        let m = mat4::translation(&vec3(1.0, 2.0, 3.0)) * mat4::scale(&vec3(2.0, 2.0, 2.0));
+        assert_eq!(
+            m.transform_point(&vec3(1.0, 0.0, -1.0)),
+            vec3(3.0, 2.0, 1.0)
+        );
+        assert_eq!(
+            m.transform_vector(&vec3(1.0, 0.0, -1.0)),
+            vec3(2.0, 0.0, -2.0)
+        );
+        assert_eq!(m * vec4(1.0, 0.0, -1.0, 1.0), vec4(3.0, 2.0, 1.0, 1.0));
+        assert_eq!(mat3::from_mat4(&m), mat3::scale(&vec3(2.0, 2.0, 2.0)));
+        assert_eq!(M4.transpose().transpose(), M4);
+        assert_eq!(M4.transpose()[1], vec4(1.0, 3.0, 4.0, -3.0));
+    }
+
+    #[test]
+    fn rotations_are_counter_clockwise() {
+        let x = vec3(1.0, 0.0, 0.0);
+        let y = vec3(0.0, 1.0, 0.0);
+        let z = vec3(0.0, 0.0, 1.0);
+        let quarter = std::f32::consts::FRAC_PI_2;
+
+        // Looking down each axis towards the origin, a quarter turn takes the next axis to the one after
+        assert_vec3_near(mat4::rotation_x(quarter).transform_vector(&y), z);
+        assert_vec3_near(mat4::rotation_y(quarter).transform_vector(&z), x);
+        assert_vec3_near(mat4::rotation_z(quarter).transform_vector(&x), y);
+        assert_vec3_near(mat4::rotation_z(-quarter).transform_vector(&x), -y);
+
+        let axis = normalize(&vec3(1.0, 1.0, 1.0));
+        let third = mat3::rotation(&axis, std::f32::consts::TAU / 3.0);
+        assert_vec3_near(third * x, y);
+        assert_vec3_near(third * y, z);
+
+        let r = mat3::rotation(&normalize(&vec3(-2.0, 0.5, 1.0)), 0.7);
+        assert!((r.determinant() - 1.0).abs() < 1e-5);
+        assert_mat3_near(&r.inverse().unwrap(), &r.transpose());
+    }
+
+    #[test]
+    fn projections_map_near_and_far() {
+        let (near, far) = (0.5, 100.0);
+        let project = |m: &mat4, p: vec3| {
+            let clip = *m * p.extend(1.0);
+            clip.xyz() / clip.w
+        };
+
+        let persp = mat4::perspective(std::f32::consts::FRAC_PI_2, 2.0, near, far);
+        assert_vec3_near(project(&persp, vec3(0.0, 0.0, -near)), vec3(0.0, 0.0, -1.0));
+        assert_vec3_near(project(&persp, vec3(0.0, 0.0, -far)), vec3(0.0, 0.0, 1.0));
+        // A 90 degree fov reaches y = -z, and x twice as far for an aspect of 2
+        assert_vec3_near(
+            project(&persp, vec3(2.0, 1.0, -1.0)).xy().extend(0.0),
+            vec3(1.0, 1.0, 0.0),
+        );
+
+        let ortho = mat4::orthographic(-4.0, 2.0, -1.0, 3.0, near, far);
+        assert_vec3_near(
+            project(&ortho, vec3(-4.0, -1.0, -near)),
+            vec3(-1.0, -1.0, -1.0),
+        );
+        assert_vec3_near(project(&ortho, vec3(2.0, 3.0, -far)), vec3(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn look_at_puts_target_on_negative_z() {
+        let eye = vec3(3.0, 4.0, -2.0);
+        let target = vec3(-1.0, 2.0, 5.0);
+        let view = mat4::look_at(&eye, &target, &vec3(0.0, 1.0, 0.0));
+
+        assert_vec3_near(view.transform_point(&eye), vec3(0.0, 0.0, 0.0));
+        let d = length(&(target - eye));
+        assert_vec3_near(view.transform_point(&target), vec3(0.0, 0.0, -d));
+
+        // Up stays up and the view is a rigid transform
+        let above = view.transform_point(&(eye + vec3(0.0, 1.0, 0.0)));
+        assert!(above.y > 0.0 && above.x.abs() < 1e-5);
+        assert!((view.determinant() - 1.0).abs() < 1e-5);
+    }
+
+    fn assert_quat_near(a: quat, b: quat) {
+        assert!((a - b).length() < 1e-4, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn quat_rotates_like_mat3() {
+        let axis = normalize(&vec3(1.0, -2.0, 0.5));
+        let q = quat::from_axis_angle(&axis, 1.3);
+        let m = mat3::rotation(&axis, 1.3);
+        assert_mat3_near(&q.to_mat3(), &m);
+        assert_mat4_near(&q.to_mat4(), &mat4::rotation(&axis, 1.3));
+
+        for v in [
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.5, 2.0, -3.0),
+            vec3(-1.0, 1.0, 1.0),
+        ] {
+            assert_vec3_near(q * v, m * v);
+            assert_vec3_near(q.inverse() * (q * v), v);
+        }
+
+        // q and -q are the same rotation, from_mat3 may return either
+        let mut rand = crate::game_rand::GameRand::new(12345);
+        for _ in 0..100 {
+            let axis = rand.point_on_sphere();
+            let q = quat::from_axis_angle(&axis, rand.rand_range_f32(-3.1, 3.1));
+            let back = quat::from_mat3(&q.to_mat3());
+            assert!((back.dot(&q).abs() - 1.0).abs() < 1e-5, "{q:?} {back:?}");
+        }
+
+        let qa = quat::from_axis_angle(&vec3(0.0, 1.0, 0.0), 0.4);
+        let qb = quat::from_axis_angle(&vec3(1.0, 0.0, 0.0), -0.9);
+        assert_mat3_near(&(qa * qb).to_mat3(), &(qa.to_mat3() * qb.to_mat3()));
+    }
+
+    #[test]
+    fn quat_euler_round_trip() {
+        let yaw = quat::from_euler(&vec3(0.0, 0.5, 0.0));
+        assert_quat_near(yaw, quat::from_axis_angle(&vec3(0.0, 1.0, 0.0), 0.5));
+
+        // Roll is applied first, then pitch, then yaw
+        let angles = vec3(0.3, -1.2, 0.8);
+        let q = quat::from_euler(&angles);
+        let v = vec3(0.5, 2.0, -3.0);
+        let expected = mat3::rotation(&vec3(0.0, 1.0, 0.0), angles.y)
+            * (mat3::rotation(&vec3(1.0, 0.0, 0.0), angles.x)
+                * (mat3::rotation(&vec3(0.0, 0.0, 1.0), angles.z) * v));
+        assert_vec3_near(q * v, expected);
+
+        for angles in [
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.3, -1.2, 0.8),
+            vec3(-1.5, 3.0, -2.9),
+            vec3(1.2, -0.1, 0.1),
+        ] {
+            assert_vec3_near(quat::from_euler(&angles).to_euler(), angles);
+        }
+
+        // At +-90 degrees pitch yaw and roll turn around the same axis, so the roll moves into yaw
+        let half_pi = std::f32::consts::FRAC_PI_2;
+        for pitch in [half_pi, -half_pi] {
+            let q = quat::from_euler(&vec3(pitch, 0.4, 0.25));
+            let e = q.to_euler();
+            assert!((e.x - pitch).abs() < 1e-3);
+            assert_eq!(e.z, 0.0);
+            assert!((quat::from_euler(&e).dot(&q).abs() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn quat_slerp() {
+        let a = quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), 0.2);
+        let b = quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), 1.8);
+        assert_quat_near(slerp(&a, &b, 0.0), a);
+        assert_quat_near(slerp(&a, &b, 1.0), b);
+        assert_quat_near(
+            slerp(&a, &b, 0.5),
+            quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), 1.0),
+        );
+        assert_quat_near(
+            slerp(&a, &b, 0.25),
+            quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), 0.6),
+        );
+        assert!((slerp(&a, &b, 0.3).length() - 1.0).abs() < 1e-6);
+
+        // -b is the same rotation as b, slerp takes the short way round to it
+        assert_quat_near(slerp(&a, &-b, 0.5), slerp(&a, &b, 0.5));
+        assert_quat_near(slerp(&a, &-b, 1.0), b);
+        assert_quat_near(nlerp(&a, &-b, 0.5), slerp(&a, &b, 0.5));
+
+        // Almost equal rotations fall back to nlerp
+        let c = quat::from_axis_angle(&vec3(0.0, 0.0, 1.0), 0.2001);
+        assert_quat_near(slerp(&a, &c, 0.5), nlerp(&a, &c, 0.5));
+    }
+
+    #[test]
+    fn quat_axis_angle() {
+        let axis = normalize(&vec3(1.0, 2.0, -2.0));
+        let (a, angle) = quat::from_axis_angle(&axis, 0.75).to_axis_angle();
+        assert_vec3_near(a, axis);
+        assert!((angle - 0.75).abs() < 1e-5);
+
+        // Unnormalized quaternions give the same axis and angle
+        let (a, angle) = (quat::from_axis_angle(&axis, 0.75) * 3.0).to_axis_angle();
+        assert_vec3_near(a, axis);
+        assert!((angle - 0.75).abs() < 1e-5);
+
+        assert_eq!(quat::IDENTITY.to_axis_angle(), (vec3(1.0, 0.0, 0.0), 0.0));
+        let (a, angle) = quat::from_axis_angle(&axis, 1e-7).to_axis_angle();
+        assert_eq!(a, vec3(1.0, 0.0, 0.0));
+        assert!(angle.abs() < 1e-5);
+    }
+}